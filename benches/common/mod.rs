@@ -0,0 +1,81 @@
+// Channels driven through the Maker and Taker traits directly, without the daemon around them
+// Each bench uses only part of it
+#![allow(dead_code)]
+
+use rand::{SeedableRng, rngs::StdRng};
+use serde::{Serialize, de::DeserializeOwned};
+
+use rainboltd::{
+    clock::system_clock,
+    maker::{Maker, MakerState},
+    taker::{Taker, TakerState},
+    MarketData,
+    MarketPrice
+};
+
+// Margins large enough that no number of benchmark rounds can drain either side
+pub const MAKER_MARGIN: i64 = 2_000_000_000;
+pub const TAKER_MARGIN: i64 = 1_000_000_000;
+pub const ORDER_SIZE: i64 = 1000;
+
+pub fn market_data(bitcoin: i64) -> MarketData {
+    MarketData {
+        bitcoin: MarketPrice { usd: bitcoin },
+        cosmos: MarketPrice { usd: 4 }
+    }
+}
+
+// MakerState is not Clone, but survives a round trip through JSON
+pub fn copy<T: Serialize + DeserializeOwned>(state: &T) -> T {
+    serde_json::from_value(serde_json::to_value(state).expect("state serializes")).expect("state deserializes")
+}
+
+pub struct Channel {
+    pub rng: StdRng,
+    pub maker: MakerState,
+    pub taker: TakerState,
+}
+
+impl Channel {
+    // Established channel with one unsettled epoch
+    pub fn open(seed: u64) -> Self {
+        let mut rng = StdRng::seed_from_u64(seed);
+        let mut maker = MakerState::init(&mut rng, system_clock(), MAKER_MARGIN);
+        maker.collateral = Some(TAKER_MARGIN);
+        let mut taker = TakerState::init(
+            &mut rng,
+            system_clock(),
+            TAKER_MARGIN,
+            maker.quote_collateral(ORDER_SIZE),
+            ORDER_SIZE,
+            maker.channel_state.clone(),
+            maker.channel_token.clone()
+        );
+        let res = maker.recv_open_channel_req(&mut rng, taker.send_open_channel_req()).expect("order is within the policy");
+        taker.recv_open_channel_res(res).expect("maker tokens verify");
+        maker.recv_channel_established(taker.send_channel_established_req()).expect("channel is pending");
+
+        let mut channel = Channel { rng, maker, taker };
+        channel.next_epoch();
+        channel.next_epoch();
+        channel
+    }
+
+    // Price moves 10% up and back down, so every epoch needs a payment
+    pub fn next_epoch(&mut self) {
+        let epoch = self.taker.epoch + 1;
+        let bitcoin = if epoch % 2 == 0 { 8800 } else { 8000 };
+        self.maker.record_market_data(epoch, market_data(bitcoin));
+        self.taker.record_market_data(epoch, market_data(bitcoin));
+    }
+
+    // Full payment round, as the driver runs it over the transport
+    pub fn settle(&mut self) {
+        let Channel { ref mut rng, ref mut maker, ref mut taker } = *self;
+        let req = taker.send_payment_req(rng).expect("an epoch is unsettled");
+        let res = maker.recv_payment_req(rng, req).expect("payment is for the unsettled epochs");
+        taker.recv_payment_res(res).expect("close token verifies");
+        let res = maker.recv_generate_payment_token_req(taker.send_generate_payment_token_req()).expect("revoke token is for the pending payment");
+        taker.recv_generate_payment_token_res(res).expect("pay token verifies");
+    }
+}
@@ -0,0 +1,61 @@
+// Cost of each bolt step rainboltd runs per channel. Save a baseline with
+//   cargo bench --bench protocol -- --save-baseline main
+// and compare a change against it with --baseline main.
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion};
+use rand::{SeedableRng, rngs::StdRng};
+
+use rainboltd::{
+    clock::system_clock,
+    maker::{Maker, MakerState},
+    message::{GeneratePaymentTokenRequest, PaymentRequest},
+    taker::{Taker, TakerState}
+};
+
+mod common;
+use common::{copy, Channel, ORDER_SIZE, TAKER_MARGIN};
+
+// Customer keys, wallet commitment and the establish proof
+fn taker_init(c: &mut Criterion) {
+    let mut rng = StdRng::seed_from_u64(0);
+    let maker = MakerState::init(&mut rng, system_clock(), TAKER_MARGIN);
+    c.bench_function("taker_init", |b| b.iter_batched(
+        || (maker.channel_state.clone(), maker.channel_token.clone()),
+        |(channel_state, channel_token)| TakerState::init(&mut rng, system_clock(), TAKER_MARGIN, ORDER_SIZE, ORDER_SIZE, channel_state, channel_token),
+        BatchSize::SmallInput
+    ));
+}
+
+fn send_payment_req(c: &mut Criterion) {
+    let Channel { mut rng, taker, .. } = Channel::open(1);
+    c.bench_function("send_payment_req", |b| b.iter_batched(
+        || taker.clone(),
+        |mut taker| taker.send_payment_req(&mut rng),
+        BatchSize::SmallInput
+    ));
+}
+
+// Proofs and tokens are kept serialized, so each iteration gets its own copy of the request
+fn recv_payment_req(c: &mut Criterion) {
+    let Channel { mut rng, maker, mut taker } = Channel::open(2);
+    let req = serde_json::to_vec(&taker.send_payment_req(&mut rng).unwrap()).unwrap();
+    c.bench_function("recv_payment_req", |b| b.iter_batched(
+        || (copy(&maker), serde_json::from_slice::<PaymentRequest>(&req).unwrap()),
+        |(mut maker, req)| maker.recv_payment_req(&mut rng, req),
+        BatchSize::SmallInput
+    ));
+}
+
+fn recv_generate_payment_token_req(c: &mut Criterion) {
+    let Channel { mut rng, mut maker, mut taker } = Channel::open(3);
+    let req = taker.send_payment_req(&mut rng).unwrap();
+    taker.recv_payment_res(maker.recv_payment_req(&mut rng, req).unwrap()).unwrap();
+    let req = serde_json::to_vec(&taker.send_generate_payment_token_req()).unwrap();
+    c.bench_function("recv_generate_payment_token_req", |b| b.iter_batched(
+        || (copy(&maker), serde_json::from_slice::<GeneratePaymentTokenRequest>(&req).unwrap()),
+        |(mut maker, req)| maker.recv_generate_payment_token_req(req),
+        BatchSize::SmallInput
+    ));
+}
+
+criterion_group!(benches, taker_init, send_payment_req, recv_payment_req, recv_generate_payment_token_req);
+criterion_main!(benches);
@@ -0,0 +1,35 @@
+// Settlements per second when one process settles N channels at once, each on its own thread as
+// channels settle independently. Throughput is reported per settlement, so it reads as settlements/s.
+//   cargo bench --bench settlement_load -- --save-baseline main
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use std::thread;
+
+mod common;
+use common::Channel;
+
+const CHANNELS: &[usize] = &[1, 4, 16, 64];
+
+// One payment round on every channel, then a new epoch so each has something to settle next time
+fn settle_all(channels: &mut Vec<Channel>) {
+    let handles: Vec<_> = channels.drain(..).map(|mut channel| thread::spawn(move || {
+        channel.settle();
+        channel.next_epoch();
+        channel
+    })).collect();
+    channels.extend(handles.into_iter().map(|handle| handle.join().expect("settlement round panicked")));
+}
+
+fn settlement_load(c: &mut Criterion) {
+    let mut group = c.benchmark_group("settlement_load");
+    // A sample settles every channel several times, the default of 100 samples takes too long
+    group.sample_size(10);
+    for &count in CHANNELS {
+        let mut channels: Vec<Channel> = (0..count as u64).map(Channel::open).collect();
+        group.throughput(Throughput::Elements(count as u64));
+        group.bench_with_input(BenchmarkId::from_parameter(count), &count, |b, _| b.iter(|| settle_all(&mut channels)));
+    }
+    group.finish();
+}
+
+criterion_group!(benches, settlement_load);
+criterion_main!(benches);
@@ -0,0 +1,236 @@
+use serde::{Serialize, Deserialize};
+use std::fmt;
+
+// Internal
+use crate::message::OpenChannelRequest;
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct AdmissionPolicy {
+    pub min_order_size: i64,
+    pub max_order_size: i64,
+    pub max_leverage: i64, // notional / margin
+    pub margin_requirement_bps: i64, // minimum margin as a fraction of notional, in basis points
+    pub max_exposure_per_identity: i64, // total notional per customer public key
+}
+
+impl Default for AdmissionPolicy {
+    fn default() -> Self {
+        AdmissionPolicy {
+            min_order_size: 1,
+            max_order_size: i64::max_value(),
+            max_leverage: 100,
+            margin_requirement_bps: 100,
+            max_exposure_per_identity: i64::max_value(),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(tag = "reason")]
+pub enum OrderRejection {
+    InvalidMargin { margin: i64 },
+    InvalidOrderSize { order_size: i64 },
+    InvalidMakerMargin { maker_margin: i64 },
+    OrderTooSmall { order_size: i64, min_order_size: i64 },
+    OrderTooLarge { order_size: i64, max_order_size: i64 },
+    LeverageTooHigh { order_size: i64, margin: i64, max_leverage: i64 },
+    InsufficientMargin { margin: i64, required: i64 },
+    InsufficientLiquidity { maker_margin: i64, available_margin: i64 },
+    ExposureLimit { exposure: i64, order_size: i64, max_exposure: i64 },
+    CollateralMismatch { maker_margin: i64, quoted: i64 },
+    InvalidCommitment { error: String },
+    ChannelPending,
+    ChannelOpen,
+    UnknownChannel,
+}
+
+impl fmt::Display for OrderRejection {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            OrderRejection::InvalidMargin { margin } =>
+                write!(f, "Margin must be positive, got {}", margin),
+            OrderRejection::InvalidOrderSize { order_size } =>
+                write!(f, "Order size must be positive, got {}", order_size),
+            OrderRejection::InvalidMakerMargin { maker_margin } =>
+                write!(f, "Maker collateral must be positive, got {}", maker_margin),
+            OrderRejection::OrderTooSmall { order_size, min_order_size } =>
+                write!(f, "Order size {} is below the minimum of {}", order_size, min_order_size),
+            OrderRejection::OrderTooLarge { order_size, max_order_size } =>
+                write!(f, "Order size {} is above the maximum of {}", order_size, max_order_size),
+            OrderRejection::LeverageTooHigh { order_size, margin, max_leverage } =>
+                write!(f, "Leverage of {} / {} exceeds the maximum of {}x", order_size, margin, max_leverage),
+            OrderRejection::InsufficientMargin { margin, required } =>
+                write!(f, "Margin {} is below the required {}", margin, required),
+            OrderRejection::InsufficientLiquidity { maker_margin, available_margin } =>
+                write!(f, "Maker collateral requested {} but only {} available", maker_margin, available_margin),
+            OrderRejection::ExposureLimit { exposure, order_size, max_exposure } =>
+                write!(f, "Exposure of {} plus order of {} exceeds the limit of {}", exposure, order_size, max_exposure),
+            OrderRejection::CollateralMismatch { maker_margin, quoted } =>
+                write!(f, "Maker collateral of {} does not match the quoted {}", maker_margin, quoted),
+            OrderRejection::InvalidCommitment { error } =>
+                write!(f, "Commitment to the opening balances does not verify: {}", error),
+            OrderRejection::ChannelPending =>
+                write!(f, "Maker already has a pending channel"),
+            OrderRejection::ChannelOpen =>
+                write!(f, "Maker already has an open channel"),
+            OrderRejection::UnknownChannel =>
+                write!(f, "Maker has no pending channel for this customer"),
+        }
+    }
+}
+
+impl OrderRejection {
+    // Stable name of the rejection, as in its serialized "reason"
+    pub fn name(&self) -> &'static str {
+        match self {
+            OrderRejection::InvalidMargin { .. } => "InvalidMargin",
+            OrderRejection::InvalidOrderSize { .. } => "InvalidOrderSize",
+            OrderRejection::InvalidMakerMargin { .. } => "InvalidMakerMargin",
+            OrderRejection::OrderTooSmall { .. } => "OrderTooSmall",
+            OrderRejection::OrderTooLarge { .. } => "OrderTooLarge",
+            OrderRejection::LeverageTooHigh { .. } => "LeverageTooHigh",
+            OrderRejection::InsufficientMargin { .. } => "InsufficientMargin",
+            OrderRejection::InsufficientLiquidity { .. } => "InsufficientLiquidity",
+            OrderRejection::ExposureLimit { .. } => "ExposureLimit",
+            OrderRejection::CollateralMismatch { .. } => "CollateralMismatch",
+            OrderRejection::InvalidCommitment { .. } => "InvalidCommitment",
+            OrderRejection::ChannelPending => "ChannelPending",
+            OrderRejection::ChannelOpen => "ChannelOpen",
+            OrderRejection::UnknownChannel => "UnknownChannel",
+        }
+    }
+}
+
+impl AdmissionPolicy {
+    pub fn check(&self, req: &OpenChannelRequest, available_margin: i64, exposure: i64) -> Result<(), OrderRejection> {
+        self.check_order(req.margin, req.maker_margin, req.order_size, available_margin, exposure)
+    }
+
+    // Amounts of an order, apart from the curve points of the request
+    pub fn check_order(&self, margin: i64, maker_margin: i64, order_size: i64, available_margin: i64, exposure: i64) -> Result<(), OrderRejection> {
+        // Positive amounts first, whatever the configured limits allow
+        if margin <= 0 {
+            return Err(OrderRejection::InvalidMargin { margin })
+        }
+        if order_size <= 0 {
+            return Err(OrderRejection::InvalidOrderSize { order_size })
+        }
+        if maker_margin <= 0 {
+            return Err(OrderRejection::InvalidMakerMargin { maker_margin })
+        }
+        if order_size < self.min_order_size {
+            return Err(OrderRejection::OrderTooSmall { order_size, min_order_size: self.min_order_size })
+        }
+        if order_size > self.max_order_size {
+            return Err(OrderRejection::OrderTooLarge { order_size, max_order_size: self.max_order_size })
+        }
+        // i128 so that notional * bps cannot overflow
+        if order_size as i128 > self.max_leverage as i128 * margin as i128 {
+            return Err(OrderRejection::LeverageTooHigh { order_size, margin, max_leverage: self.max_leverage })
+        }
+        let required = (order_size as i128 * self.margin_requirement_bps as i128 / 10000) as i64;
+        if margin < required {
+            return Err(OrderRejection::InsufficientMargin { margin, required })
+        }
+        if maker_margin > available_margin {
+            return Err(OrderRejection::InsufficientLiquidity { maker_margin, available_margin })
+        }
+        if exposure.saturating_add(order_size) > self.max_exposure_per_identity {
+            return Err(OrderRejection::ExposureLimit { exposure, order_size, max_exposure: self.max_exposure_per_identity })
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy() -> AdmissionPolicy {
+        AdmissionPolicy {
+            min_order_size: 10,
+            max_order_size: 10_000,
+            max_leverage: 10,
+            margin_requirement_bps: 2000,
+            max_exposure_per_identity: 20_000,
+        }
+    }
+
+    // margin, maker margin, order size, available margin, exposure
+    fn check(margin: i64, maker_margin: i64, order_size: i64, available_margin: i64, exposure: i64) -> Result<(), &'static str> {
+        policy()
+            .check_order(margin, maker_margin, order_size, available_margin, exposure)
+            .map_err(|rejection| rejection.name())
+    }
+
+    #[test]
+    fn admits_an_order_within_the_policy() {
+        assert_eq!(check(1000, 2000, 5000, 5000, 0), Ok(()));
+    }
+
+    #[test]
+    fn rejects_non_positive_amounts() {
+        assert_eq!(check(0, 2000, 5000, 5000, 0), Err("InvalidMargin"));
+        assert_eq!(check(-1, 2000, 5000, 5000, 0), Err("InvalidMargin"));
+        assert_eq!(check(1000, 2000, 0, 5000, 0), Err("InvalidOrderSize"));
+        assert_eq!(check(1000, 2000, -5000, 5000, 0), Err("InvalidOrderSize"));
+        assert_eq!(check(1000, 0, 5000, 5000, 0), Err("InvalidMakerMargin"));
+        assert_eq!(check(1000, -2000, 5000, 5000, 0), Err("InvalidMakerMargin"));
+    }
+
+    #[test]
+    fn rejects_orders_outside_the_size_limits() {
+        assert_eq!(check(1000, 2000, 9, 5000, 0), Err("OrderTooSmall"));
+        assert_eq!(check(1000, 2000, 10_001, 5000, 0), Err("OrderTooLarge"));
+    }
+
+    #[test]
+    fn rejects_too_much_leverage() {
+        assert_eq!(check(499, 2000, 5000, 5000, 0), Err("LeverageTooHigh"));
+        assert_eq!(check(500, 2000, 5000, 5000, 0), Err("InsufficientMargin"));
+    }
+
+    #[test]
+    fn rejects_margin_below_the_requirement() {
+        let mut policy = policy();
+        policy.max_leverage = 100;
+        let rejection = policy.check_order(999, 2000, 5000, 5000, 0).unwrap_err();
+        assert_eq!(rejection.name(), "InsufficientMargin");
+        assert_eq!(policy.check_order(1000, 2000, 5000, 5000, 0).map_err(|rejection| rejection.name()), Ok(()));
+    }
+
+    #[test]
+    fn rejects_collateral_beyond_the_free_margin() {
+        assert_eq!(check(1000, 5001, 5000, 5000, 0), Err("InsufficientLiquidity"));
+    }
+
+    #[test]
+    fn rejects_exposure_beyond_the_limit() {
+        assert_eq!(check(1000, 2000, 5000, 5000, 15_000), Ok(()));
+        assert_eq!(check(1000, 2000, 5000, 5000, 15_001), Err("ExposureLimit"));
+    }
+
+    #[test]
+    fn rejections_serialize_with_their_name() {
+        let rejections = vec![
+            OrderRejection::InvalidMargin { margin: 0 },
+            OrderRejection::InvalidOrderSize { order_size: 0 },
+            OrderRejection::InvalidMakerMargin { maker_margin: 0 },
+            OrderRejection::OrderTooSmall { order_size: 1, min_order_size: 10 },
+            OrderRejection::OrderTooLarge { order_size: 20, max_order_size: 10 },
+            OrderRejection::LeverageTooHigh { order_size: 100, margin: 1, max_leverage: 10 },
+            OrderRejection::InsufficientMargin { margin: 1, required: 2 },
+            OrderRejection::InsufficientLiquidity { maker_margin: 2, available_margin: 1 },
+            OrderRejection::ExposureLimit { exposure: 1, order_size: 1, max_exposure: 1 },
+            OrderRejection::CollateralMismatch { maker_margin: 1, quoted: 2 },
+            OrderRejection::ChannelPending,
+        ];
+        for rejection in rejections {
+            let value = serde_json::to_value(&rejection).unwrap();
+            assert_eq!(value["reason"], rejection.name());
+            let decoded: OrderRejection = serde_json::from_value(value).unwrap();
+            assert_eq!(decoded.name(), rejection.name());
+            assert!(!rejection.to_string().is_empty());
+        }
+    }
+}
@@ -0,0 +1,461 @@
+use bytes::Bytes;
+use futures03::{future, StreamExt};
+use std::convert::Infallible;
+use warp::{
+    self,
+    path,
+    reply,
+    http::StatusCode,
+    Filter,
+    Reply,
+    reject::Rejection
+};
+use tracing::{debug, info, warn};
+
+// Internal
+use crate::{
+    taker::{
+        TakerState,
+        Taker
+    },
+    maker::{
+        Maker,
+        MakerState
+    },
+    message::{
+        OrderRequest,
+        OpenChannelRequest,
+        ChannelEstablishedRequest,
+        SettlementRequest,
+        PaymentRequest,
+        GeneratePaymentTokenRequest,
+        CloseChannelRequest,
+    },
+    admission::AdmissionPolicy,
+    audit::AuditJournal,
+    clock::SharedClock,
+    settlement::Backoff,
+    history::CatchUpMode,
+    auth::{
+        authenticated,
+        authenticated_json,
+        AuthError,
+        Role
+    },
+    driver::{self, MakerSlot, TakerSlot},
+    events::{EventBus, EventFilter},
+    peer::PeerMessage,
+    scheduler::{answer_settlement_req, request_settlement_with_backoff},
+    state::{with_state, DaemonState},
+    transport::TransportError,
+    view::{channel_views, ChannelView, MakerView, TakerView},
+    MarketData
+};
+
+fn init_maker_state(initial_margin: i64, maker_slot: &MakerSlot, events: &EventBus, journal: &AuditJournal, clock: &SharedClock) -> impl Reply {
+    let mut maker = maker_slot.lock().expect("Maker is not poisoned");
+    if maker.is_none() {
+        info!("Creating a new Maker!")
+    };
+    let maker = maker.get_or_insert(
+        MakerState::init(&mut rand::thread_rng(), clock.clone(), initial_margin)
+    );
+    maker.events = events.clone();
+    maker.journal = journal.clone();
+    reply::json(&MakerView::from(&*maker))
+}
+
+// Failures of a route that are not the caller's credentials, answered by handle_rejection
+#[derive(Debug)]
+pub enum ApiError {
+    NoMaker,
+    NoTaker,
+    Conflict(&'static str),
+    Transport(TransportError),
+}
+
+impl warp::reject::Reject for ApiError {}
+
+fn api_error(err: ApiError) -> Rejection {
+    warp::reject::custom(err)
+}
+
+fn transport_error(err: TransportError) -> Rejection {
+    warp::reject::custom(ApiError::Transport(err))
+}
+
+fn set_maker_collateral(collateral: i64, maker_slot: &MakerSlot) -> Result<impl Reply, Rejection> {
+    let mut maybe_maker = maker_slot.lock().expect("Maker is not poisoned");
+    let maker = maybe_maker.as_mut().ok_or_else(|| api_error(ApiError::NoMaker))?;
+    maker.collateral = Some(collateral);
+    info!("Maker collateral per channel set to {}", collateral);
+    Ok(reply::json(&maker.quote_collateral(maker.order_size.unwrap_or(collateral))))
+}
+
+fn set_maker_policy(policy: AdmissionPolicy, maker_slot: &MakerSlot) -> Result<impl Reply, Rejection> {
+    let mut maybe_maker = maker_slot.lock().expect("Maker is not poisoned");
+    let maker = maybe_maker.as_mut().ok_or_else(|| api_error(ApiError::NoMaker))?;
+    info!("Maker admission policy set to {:?}", policy);
+    maker.policy = policy;
+    Ok(reply::json(&maker.policy))
+}
+
+fn release_maker_reservation(maker_slot: &MakerSlot) -> Result<impl Reply, Rejection> {
+    let mut maybe_maker = maker_slot.lock().expect("Maker is not poisoned");
+    let maker = maybe_maker.as_mut().ok_or_else(|| api_error(ApiError::NoMaker))?;
+    maker.release_reservation();
+    Ok(reply::json(&maker.available_margin))
+}
+
+// Paying needs the taker's price for every unsettled epoch, unless it is resuming a round
+fn require_settlement(taker_slot: &TakerSlot) -> Result<(), Rejection> {
+    match taker_slot.lock().expect("Taker is not poisoned").as_ref() {
+        Some(taker) if taker.can_settle() => Ok(()),
+        Some(_) => Err(api_error(ApiError::Conflict("Nothing to settle, the taker needs a price for every unsettled epoch"))),
+        None => Err(api_error(ApiError::NoTaker))
+    }
+}
+
+// Closing waits for the payment in flight, the maker only closes on a settled state
+fn require_closable(taker_slot: &TakerSlot) -> Result<(), Rejection> {
+    match taker_slot.lock().expect("Taker is not poisoned").as_ref() {
+        Some(taker) if taker.revoke_token.is_some() => Err(api_error(ApiError::Conflict("A payment is in flight, pay to finish it before closing"))),
+        Some(_) => Ok(()),
+        None => Err(api_error(ApiError::NoTaker))
+    }
+}
+
+fn order(req: OrderRequest, taker_slot: &TakerSlot, maker_slot: &MakerSlot, events: &EventBus, journal: &AuditJournal, clock: &SharedClock) -> Result<TakerState, ApiError> {
+    let mut taker = taker_slot.lock().expect("Taker is not poisoned");
+    if taker.is_none() {
+        info!("Creating a new Taker!");
+    } else {
+        warn!("Taker already has an order");
+        return Err(ApiError::Conflict("Taker already has an order"))
+    };
+
+    let OrderRequest {
+        initial_margin,
+        order_size,
+        maker_order_id: _
+    } = req;
+
+    let maybe_maker = maker_slot
+        .lock()
+        .expect("Order failed. Maker is poisoned");
+    let maker = maybe_maker.as_ref().ok_or(ApiError::NoMaker)?;
+    let channel_state = maker.channel_state.clone();
+    let channel_token = maker.channel_token.clone();
+    let maker_margin = maker.quote_collateral(order_size);
+    let latest_market_data = maker.market_data.clone().map(|market_data| (maker.epoch, market_data));
+    drop(maybe_maker);
+
+    let taker_state = taker.get_or_insert(
+        TakerState::init(
+            &mut rand::thread_rng(),
+            clock.clone(),
+            initial_margin,
+            maker_margin,
+            order_size,
+            channel_state,
+            channel_token
+        )
+    );
+    taker_state.events = events.clone();
+    taker_state.journal = journal.clone();
+    // Start from the same reference price as the maker
+    if let Some((epoch, market_data)) = latest_market_data {
+        taker_state.record_market_data(epoch, market_data);
+    }
+    Ok(taker_state.clone())
+}
+
+// HTTP response for the answer to a protocol message, from either side
+fn peer_reply(res: PeerMessage) -> warp::reply::Response {
+    match res {
+        PeerMessage::OpenChannelResponse(res) => reply::json(&res).into_response(),
+        PeerMessage::PaymentResponse(res) => reply::json(&res).into_response(),
+        PeerMessage::GeneratePaymentTokenResponse(res) => reply::json(&res).into_response(),
+        PeerMessage::CloseChannelResponse(res) => reply::json(&res).into_response(),
+        PeerMessage::OrderRejection(rejection) => reply::with_status(reply::json(&rejection), StatusCode::BAD_REQUEST).into_response(),
+        PeerMessage::PaymentRejection(rejection) => reply::with_status(reply::json(&rejection), StatusCode::BAD_REQUEST).into_response(),
+        PeerMessage::Ack => "Success".to_string().into_response(),
+        PeerMessage::Error(err) => reply::with_status(err, StatusCode::INTERNAL_SERVER_ERROR).into_response(),
+        _ => reply::with_status("Unexpected maker response".to_string(), StatusCode::INTERNAL_SERVER_ERROR).into_response()
+    }
+}
+
+pub async fn handle_rejection(err: Rejection) -> Result<warp::reply::Response, Rejection> {
+    if let Some(auth_error) = err.find::<AuthError>() {
+        let status = match auth_error {
+            AuthError::Forbidden => StatusCode::FORBIDDEN,
+            AuthError::InvalidBody => StatusCode::BAD_REQUEST,
+            AuthError::BodyTooLarge => StatusCode::PAYLOAD_TOO_LARGE,
+            _ => StatusCode::UNAUTHORIZED
+        };
+        return Ok(reply::with_status(format!("{:?}", auth_error), status).into_response())
+    }
+    match err.find::<ApiError>() {
+        Some(ApiError::NoMaker) => Ok(reply::with_status("No maker".to_string(), StatusCode::NOT_FOUND).into_response()),
+        Some(ApiError::NoTaker) => Ok(reply::with_status("No taker".to_string(), StatusCode::NOT_FOUND).into_response()),
+        Some(ApiError::Conflict(reason)) => Ok(reply::with_status(reason.to_string(), StatusCode::CONFLICT).into_response()),
+        // Refusals are the counterparty's verdict on our request, anything else is the counterparty failing
+        Some(ApiError::Transport(TransportError::Rejected(rejection))) =>
+            Ok(reply::with_status(reply::json(rejection), StatusCode::BAD_REQUEST).into_response()),
+        Some(ApiError::Transport(TransportError::Refused(rejection))) =>
+            Ok(reply::with_status(reply::json(rejection), StatusCode::BAD_REQUEST).into_response()),
+        Some(ApiError::Transport(err)) => Ok(reply::with_status(err.to_string(), StatusCode::BAD_GATEWAY).into_response()),
+        None => Err(err)
+    }
+}
+
+// POST /maker/...
+pub fn maker_routes(state: DaemonState) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
+    let credentials = state.credentials.clone();
+
+    let init_maker = path!("init" / i64)
+        .and(authenticated(credentials.clone(), Role::Admin))
+        .and(with_state(state.clone()))
+        .map(|initial_margin, _body, state: DaemonState| {
+            init_maker_state(initial_margin, &state.maker, &state.events, &state.journal, &state.clock)
+        });
+
+    let set_collateral = path!("collateral" / i64)
+        .and(authenticated(credentials.clone(), Role::Admin))
+        .and(with_state(state.clone()))
+        .and_then(|collateral, _body, state: DaemonState| async move {
+            set_maker_collateral(collateral, &state.maker)
+        });
+
+    let set_policy = path!("policy")
+        .and(authenticated_json(credentials.clone(), Role::Admin))
+        .and(with_state(state.clone()))
+        .and_then(|policy: AdmissionPolicy, state: DaemonState| async move {
+            set_maker_policy(policy, &state.maker)
+        });
+
+    let open_channel = path!("openChannel")
+        .and(authenticated_json(credentials.clone(), Role::Trader))
+        .and(with_state(state.clone()))
+        .map(|req: OpenChannelRequest, state: DaemonState| {
+            peer_reply(driver::handle_maker_slot_message(&state.maker, PeerMessage::OpenChannelRequest(req)))
+        });
+
+    let channel_established = path!("established")
+        .and(authenticated_json(credentials.clone(), Role::Trader))
+        .and(with_state(state.clone()))
+        .map(|req: ChannelEstablishedRequest, state: DaemonState| {
+            peer_reply(driver::handle_maker_slot_message(&state.maker, PeerMessage::ChannelEstablishedRequest(req)))
+        });
+
+    let release = path!("release")
+        .and(authenticated(credentials.clone(), Role::Admin))
+        .and(with_state(state.clone()))
+        .and_then(|_body, state: DaemonState| async move {
+            release_maker_reservation(&state.maker)
+        });
+
+    let maker_settle = path!("settle")
+        .and(authenticated(credentials.clone(), Role::Admin))
+        .and(with_state(state.clone()))
+        .and_then(|_body: Bytes, state: DaemonState| async move {
+            let settlement_req = {
+                let maybe_maker = state.maker.lock().expect("Maker is not poisoned");
+                maybe_maker.as_ref().map(|maker| maker.send_settlement_req()).ok_or_else(|| api_error(ApiError::NoMaker))?
+            };
+            let requested = match settlement_req {
+                Some(req) => request_settlement_with_backoff(&state, req, &Backoff::default()).await,
+                None => false
+            };
+            Ok::<_, Rejection>(reply::json(&requested))
+        });
+
+    let recv_pay = path!("recvPay")
+        .and(authenticated_json(credentials.clone(), Role::Trader))
+        .and(with_state(state.clone()))
+        .map(|req: PaymentRequest, state: DaemonState| {
+            peer_reply(driver::handle_maker_slot_message(&state.maker, PeerMessage::PaymentRequest(req)))
+        });
+
+    let get_payment_token = path!("paymentToken")
+        .and(authenticated_json(credentials.clone(), Role::Trader))
+        .and(with_state(state.clone()))
+        .map(|req: GeneratePaymentTokenRequest, state: DaemonState| {
+            peer_reply(driver::handle_maker_slot_message(&state.maker, PeerMessage::GeneratePaymentTokenRequest(req)))
+        });
+
+    let close_channel = path!("close")
+        .and(authenticated_json(credentials.clone(), Role::Trader))
+        .and(with_state(state.clone()))
+        .map(|req: CloseChannelRequest, state: DaemonState| {
+            peer_reply(driver::handle_maker_slot_message(&state.maker, PeerMessage::CloseChannelRequest(req)))
+        });
+
+    path!("maker")
+        .and(
+            init_maker
+            .or(set_collateral)
+            .or(set_policy)
+            .or(open_channel)
+            .or(channel_established)
+            .or(release)
+            .or(maker_settle)
+            .or(recv_pay)
+            .or(get_payment_token)
+            .or(close_channel)
+        )
+}
+
+// POST /taker/...
+pub fn taker_routes(state: DaemonState) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
+    let credentials = state.credentials.clone();
+
+    let take_order = path!("order")
+        .and(authenticated_json(credentials.clone(), Role::Admin))
+        .and(with_state(state.clone()))
+        .and_then(|order_request: OrderRequest, state: DaemonState| async move {
+            // One order at a time, re-opening the live channel would reset it
+            let created = order(order_request, &state.taker, &state.maker, &state.events, &state.journal, &state.clock)
+                .map_err(api_error)?
+                .channel_id;
+            match driver::open_channel(&state.taker, &*state.transport).await {
+                Ok(taker_updated_state) => Ok::<warp::reply::Response, Rejection>(
+                    reply::json(&TakerView::from(&taker_updated_state)).into_response()
+                ),
+                Err(err) => {
+                    warn!("Order failed: {}", err);
+                    // Drop the taker this order created so a new order can be placed
+                    let mut taker = state.taker.lock().expect("Taker is not poisoned");
+                    if taker.as_ref().map_or(false, |taker| taker.channel_id == created) {
+                        taker.take();
+                    }
+                    Err(transport_error(err))
+                }
+            }
+        });
+
+    let send_payment = path!("pay")
+        .and(authenticated(credentials.clone(), Role::Admin))
+        .and(with_state(state.clone()))
+        .and_then(|_body: Bytes, state: DaemonState| async move {
+            // One payment round at a time, and a scheduled one may have settled everything meanwhile
+            let _settling = state.settlement_lock.lock().await;
+            require_settlement(&state.taker)?;
+            let taker_updated_state = driver::settle(&state.taker, &*state.transport)
+                .await
+                .map_err(transport_error)?;
+            Ok::<_, Rejection>(reply::json(&TakerView::from(&taker_updated_state)))
+        });
+
+    let settle = path!("settle")
+        .and(authenticated_json(credentials.clone(), Role::Trader))
+        .and(with_state(state.clone()))
+        .and_then(|req: SettlementRequest, state: DaemonState| async move {
+            match answer_settlement_req(&state, req).await {
+                PeerMessage::Ack => (),
+                res => return Ok::<warp::reply::Response, Rejection>(peer_reply(res))
+            }
+            let taker = state.taker.lock().expect("Taker is not poisoned").clone().ok_or_else(|| api_error(ApiError::NoTaker))?;
+            Ok::<warp::reply::Response, Rejection>(reply::json(&TakerView::from(&taker)).into_response())
+        });
+
+    let catch_up = path!("catchUp")
+        .and(authenticated_json(credentials.clone(), Role::Admin))
+        .and(with_state(state.clone()))
+        .and_then(|mode: CatchUpMode, state: DaemonState| async move {
+            let mut maybe_taker = state.taker.lock().expect("Taker is not poisoned");
+            let taker = maybe_taker.as_mut().ok_or_else(|| api_error(ApiError::NoTaker))?;
+            info!("Taker catch up mode set to {:?}", mode);
+            taker.catch_up = mode;
+            Ok::<_, Rejection>(reply::json(&taker.catch_up))
+        });
+
+    let close = path!("close")
+        .and(authenticated(credentials.clone(), Role::Admin))
+        .and(with_state(state.clone()))
+        .and_then(|_body: Bytes, state: DaemonState| async move {
+            // Wait for any settlement round to finish before closing
+            let _settling = state.settlement_lock.lock().await;
+            require_closable(&state.taker)?;
+            let taker_updated_state = driver::close_channel(&state.taker, &*state.transport)
+                .await
+                .map_err(transport_error)?;
+            Ok::<_, Rejection>(reply::json(&TakerView::from(&taker_updated_state)))
+        });
+
+    path!("taker")
+        .and(
+            take_order
+            .or(send_payment)
+            .or(settle)
+            .or(catch_up)
+            .or(close)
+        )
+}
+
+// GET /channels/...
+pub fn query_routes(state: DaemonState) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
+    let credentials = state.credentials.clone();
+
+    let list_channels = path!("channels")
+        .and(warp::path::end())
+        .and(authenticated(credentials.clone(), Role::Trader))
+        .and(with_state(state.clone()))
+        .map(|_body, state: DaemonState| {
+            reply::json(&channel_views(&state.taker, &state.maker))
+        });
+
+    // Both sides of a channel share its id when one daemon is maker and taker
+    let get_channel = path!("channels" / String)
+        .and(authenticated(credentials.clone(), Role::Trader))
+        .and(with_state(state.clone()))
+        .map(|channel: String, _body, state: DaemonState| {
+            let views: Vec<ChannelView> = channel_views(&state.taker, &state.maker)
+                .into_iter()
+                .filter(|view| view.customer_public_key.as_ref() == Some(&channel))
+                .collect();
+            if views.is_empty() {
+                reply::with_status(format!("No channel {}", channel), StatusCode::NOT_FOUND).into_response()
+            } else {
+                reply::json(&views).into_response()
+            }
+        });
+
+    list_channels.or(get_channel)
+}
+
+// GET /events?channel=<customer public key>
+pub fn event_routes(state: DaemonState) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
+    path!("events")
+        .and(warp::path::end())
+        .and(warp::query::<EventFilter>())
+        .and(authenticated(state.credentials.clone(), Role::Trader))
+        .and(with_state(state))
+        .map(|filter: EventFilter, _body, state: DaemonState| {
+            let EventFilter { channel } = filter;
+            let events = state
+                .events
+                .subscribe()
+                .filter(move |event| future::ready(event.matches(channel.as_ref().map(String::as_str))))
+                .map(|event| Ok::<_, Infallible>((warp::sse::event(event.name()), warp::sse::json(event))));
+            warp::sse::reply(events)
+        })
+}
+
+// POST /marketData
+pub fn market_routes(state: DaemonState) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
+    path!("marketData")
+        .and(authenticated_json(state.credentials.clone(), Role::Admin))
+        .and(with_state(state))
+        .map(|req: MarketData, state: DaemonState| {
+            debug!("Got new market data! {:?}", req);
+            // Payments are relative to the previous price, which must be positive
+            if req.bitcoin.usd <= 0 || req.cosmos.usd <= 0 {
+                warn!("Rejected market data with a non-positive price: {:?}", req);
+                return reply::with_status("Prices must be positive".to_string(), StatusCode::BAD_REQUEST)
+            }
+            // Closing the epoch wakes the settlement schedulers
+            state.record_market_data(req);
+            reply::with_status("Success".to_string(), StatusCode::OK)
+        })
+}
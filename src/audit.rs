@@ -0,0 +1,246 @@
+// Append-only journal of every protocol message sent or received and every closed price epoch, one JSON line each.
+// Each entry commits to the one before it: hash = sha256(prev_hash || json(record)).
+use serde::{Serialize, Deserialize};
+use sha2::{Sha256, Digest};
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use tracing::warn;
+
+// Internal
+use crate::admission::AdmissionPolicy;
+use crate::clock::SharedClock;
+use crate::config::create_private_dir;
+use crate::events::{maker_channel, taker_channel};
+use crate::maker::MakerState;
+use crate::peer::PeerMessage;
+use crate::settlement::Side;
+use crate::taker::TakerState;
+use crate::MarketData;
+
+pub const GENESIS_HASH: &str = "0000000000000000000000000000000000000000000000000000000000000000";
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub enum Direction {
+    Inbound,
+    Outbound
+}
+
+// What the maker quoted collateral and admitted an order against, beyond the order itself
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct MakerTerms {
+    pub available_margin: i64,
+    pub collateral: Option<i64>,
+    pub exposure: i64, // Notional the customer already had open with the maker
+    pub policy: AdmissionPolicy,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(tag = "kind")]
+pub enum Recorded {
+    Message { side: Side, direction: Direction, message: serde_json::Value }, // The PeerMessage as sent on the wire
+    Price { market_data: MarketData },
+    MakerTerms(MakerTerms), // Journaled by the maker just before each order it receives
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct AuditRecord {
+    pub timestamp: u64,
+    pub channel: String, // Customer public key, as for events. Prices belong to every channel and have none.
+    pub epoch: u64,
+    pub recorded: Recorded,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct AuditEntry {
+    pub seq: u64,
+    pub prev_hash: String,
+    pub hash: String,
+    pub record: AuditRecord,
+}
+
+pub fn entry_hash(prev_hash: &str, record: &AuditRecord) -> String {
+    let mut hasher = Sha256::new();
+    hasher.input(prev_hash.as_bytes());
+    hasher.input(&serde_json::to_vec(record).expect("Audit record serializes to JSON"));
+    hex::encode(hasher.result())
+}
+
+#[derive(Debug)]
+pub enum AuditError {
+    Io(io::Error),
+    Parse { line: usize, error: String },
+    Sequence { line: usize, expected: u64, found: u64 },
+    BrokenChain { seq: u64 },
+    BadHash { seq: u64 },
+}
+
+impl fmt::Display for AuditError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            AuditError::Io(err) => write!(f, "Failed to read the journal: {}", err),
+            AuditError::Parse { line, error } => write!(f, "Line {} is not an audit entry: {}", line, error),
+            AuditError::Sequence { line, expected, found } => write!(f, "Line {} has seq {}, expected {}", line, found, expected),
+            AuditError::BrokenChain { seq } => write!(f, "Entry {} does not follow the previous entry's hash", seq),
+            AuditError::BadHash { seq } => write!(f, "Entry {} does not match its hash", seq),
+        }
+    }
+}
+
+impl From<io::Error> for AuditError {
+    fn from(err: io::Error) -> Self {
+        AuditError::Io(err)
+    }
+}
+
+// Reads every entry, checking the sequence numbers and the hash chain from the genesis hash
+pub fn read_verified(path: &Path) -> Result<Vec<AuditEntry>, AuditError> {
+    let mut entries: Vec<AuditEntry> = Vec::new();
+    for (index, line) in BufReader::new(File::open(path)?).lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue
+        }
+        let entry: AuditEntry = serde_json::from_str(&line)
+            .map_err(|err| AuditError::Parse { line: index + 1, error: err.to_string() })?;
+        let expected = entries.len() as u64;
+        if entry.seq != expected {
+            return Err(AuditError::Sequence { line: index + 1, expected, found: entry.seq })
+        }
+        let prev_hash = entries.last().map_or(GENESIS_HASH, |prev| prev.hash.as_str());
+        if entry.prev_hash != prev_hash {
+            return Err(AuditError::BrokenChain { seq: entry.seq })
+        }
+        if entry.hash != entry_hash(&entry.prev_hash, &entry.record) {
+            return Err(AuditError::BadHash { seq: entry.seq })
+        }
+        entries.push(entry);
+    }
+    Ok(entries)
+}
+
+// Number of entries in an intact journal
+pub fn verify(path: &Path) -> Result<usize, AuditError> {
+    read_verified(path).map(|entries| entries.len())
+}
+
+// Every message of one channel and every price, in order, once the whole chain checks out
+pub fn transcript(path: &Path, channel: &str) -> Result<Vec<AuditEntry>, AuditError> {
+    Ok(read_verified(path)?
+        .into_iter()
+        .filter(|entry| entry.record.channel == channel || entry.record.channel.is_empty())
+        .collect())
+}
+
+struct JournalWriter {
+    path: PathBuf,
+    clock: SharedClock,
+    next_seq: u64,
+    last_hash: String,
+}
+
+impl JournalWriter {
+    fn append(&mut self, record: AuditRecord) -> io::Result<()> {
+        let hash = entry_hash(&self.last_hash, &record);
+        let entry = AuditEntry { seq: self.next_seq, prev_hash: self.last_hash.clone(), hash: hash.clone(), record };
+        let line = serde_json::to_string(&entry).expect("Audit entry serializes to JSON");
+        let mut file = OpenOptions::new().create(true).append(true).open(&self.path)?;
+        writeln!(file, "{}", line)?;
+        file.sync_data()?;
+        self.next_seq += 1;
+        self.last_hash = hash;
+        Ok(())
+    }
+}
+
+// Handle to the journal, disabled by default. Clones append to the same file,
+// so the maker, taker and daemon can all hold the same journal.
+#[derive(Clone, Default)]
+pub struct AuditJournal {
+    writer: Option<Arc<Mutex<JournalWriter>>>,
+}
+
+impl AuditJournal {
+    // Continues the chain of an existing journal, refusing one that has been tampered with
+    pub fn open(path: PathBuf, clock: SharedClock) -> Result<Self, AuditError> {
+        // Entries name every channel and order, so the directory is private like the files
+        if let Some(dir) = path.parent() {
+            create_private_dir(dir)?;
+        }
+        let (next_seq, last_hash) = if path.exists() {
+            let entries = read_verified(&path)?;
+            (entries.len() as u64, entries.last().map_or(GENESIS_HASH.to_string(), |entry| entry.hash.clone()))
+        } else {
+            (0, GENESIS_HASH.to_string())
+        };
+        Ok(AuditJournal { writer: Some(Arc::new(Mutex::new(JournalWriter { path, clock, next_seq, last_hash }))) })
+    }
+
+    pub fn record(&self, side: Side, direction: Direction, channel: String, epoch: u64, msg: &PeerMessage) {
+        if self.writer.is_some() {
+            let message = serde_json::to_value(msg).expect("Peer message serializes to JSON");
+            self.append(channel, epoch, Recorded::Message { side, direction, message });
+        }
+    }
+
+    pub fn record_price(&self, epoch: u64, market_data: &MarketData) {
+        self.append(String::new(), epoch, Recorded::Price { market_data: market_data.clone() });
+    }
+
+    pub fn record_maker_terms(&self, channel: String, epoch: u64, terms: MakerTerms) {
+        self.append(channel, epoch, Recorded::MakerTerms(terms));
+    }
+
+    fn append(&self, channel: String, epoch: u64, recorded: Recorded) {
+        let writer = match self.writer {
+            Some(ref writer) => writer,
+            None => return
+        };
+        let mut writer = writer.lock().expect("Audit journal is not poisoned");
+        let record = AuditRecord { timestamp: writer.clock.now_secs(), channel, epoch, recorded };
+        if let Err(err) = writer.append(record) {
+            warn!("Failed to append to the audit journal {}: {}", writer.path.display(), err);
+        }
+    }
+}
+
+// Epoch the message settles up to, if it is a settlement message
+fn message_epoch(msg: &PeerMessage) -> Option<u64> {
+    match msg {
+        PeerMessage::SettlementRequest(req) => Some(req.to_epoch),
+        PeerMessage::PaymentRequest(req) => Some(req.to_epoch),
+        _ => None
+    }
+}
+
+// The maker only learns the channel from the taker's first messages
+fn message_channel(msg: &PeerMessage) -> Option<String> {
+    match msg {
+        PeerMessage::OpenChannelRequest(req) => Some(req.customer_public_key.to_string()),
+        PeerMessage::ChannelEstablishedRequest(req) => Some(req.customer_public_key.to_string()),
+        _ => None
+    }
+}
+
+pub fn record_taker(taker: &TakerState, direction: Direction, msg: &PeerMessage) {
+    let channel = message_channel(msg).unwrap_or_else(|| taker_channel(taker));
+    let epoch = message_epoch(msg).unwrap_or(taker.epoch);
+    taker.journal.record(Side::Taker, direction, channel, epoch, msg);
+}
+
+pub fn record_maker(maker: &MakerState, direction: Direction, msg: &PeerMessage) {
+    let channel = message_channel(msg).unwrap_or_else(|| maker_channel(maker));
+    let epoch = message_epoch(msg).unwrap_or(maker.epoch);
+    // Replaying an order needs the margin and policy it was quoted and admitted against
+    if let (Direction::Inbound, PeerMessage::OpenChannelRequest(req)) = (direction, msg) {
+        maker.journal.record_maker_terms(channel.clone(), epoch, MakerTerms {
+            available_margin: maker.available_margin,
+            collateral: maker.collateral,
+            exposure: maker.exposure.get(&req.customer_public_key.to_string()).cloned().unwrap_or(0),
+            policy: maker.policy.clone(),
+        });
+    }
+    maker.journal.record(Side::Maker, direction, channel, epoch, msg);
+}
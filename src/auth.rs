@@ -0,0 +1,208 @@
+use bytes::{Buf, Bytes, BytesMut};
+use futures03::StreamExt;
+use secp256k1::{Secp256k1, Message, PublicKey, SecretKey, Signature};
+use serde::{Serialize, Deserialize, de::DeserializeOwned};
+use sha2::{Sha256, Digest};
+use std::collections::HashMap;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use warp::{
+    self,
+    http::{HeaderMap, Method},
+    path::FullPath,
+    Filter,
+    Rejection,
+};
+
+// Internal
+use crate::clock::{Clock, SharedClock, SystemClock};
+
+pub const PUBKEY_HEADER: &str = "x-rainbolt-pubkey";
+pub const TIMESTAMP_HEADER: &str = "x-rainbolt-timestamp";
+pub const SIGNATURE_HEADER: &str = "x-rainbolt-signature";
+pub const NONCE_HEADER: &str = "x-rainbolt-nonce";
+// Largest request body read, protocol messages are a few kilobytes
+pub const MAX_BODY_LEN: usize = 1 << 20;
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub enum Role {
+    Admin, // Operates the daemon: init, orders, market data
+    Trader, // Counterparty protocol messages
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct AuthConfig {
+    pub admins: Vec<String>, // hex secp256k1 public keys
+    pub traders: Vec<String>,
+    pub max_clock_skew_secs: u64,
+}
+
+#[derive(Debug)]
+pub enum AuthError {
+    MissingHeader(&'static str),
+    InvalidHeader(&'static str),
+    StaleTimestamp,
+    BadSignature,
+    Forbidden,
+    InvalidBody,
+    BodyTooLarge,
+    Replayed,
+}
+
+impl warp::reject::Reject for AuthError {}
+
+// Credentials allowed for each role, admins may also act as traders
+#[derive(Clone, Debug)]
+pub struct Credentials {
+    pub admins: Vec<PublicKey>,
+    pub traders: Vec<PublicKey>,
+    pub max_clock_skew_secs: u64,
+    pub clock: SharedClock,
+    seen: Arc<Mutex<HashMap<String, u64>>>, // Signatures accepted within the skew window, by timestamp
+}
+
+impl Credentials {
+    pub fn from_config(config: &AuthConfig, node_public_key: PublicKey, clock: SharedClock) -> Self {
+        let parse = |keys: &Vec<String>| -> Vec<PublicKey> {
+            keys.iter()
+                .map(|key| PublicKey::from_str(key).unwrap_or_else(|err| panic!("Invalid public key {} in auth config: {}", key, err)))
+                .collect()
+        };
+        let mut traders = parse(&config.traders);
+        // The daemon signs its own calls between the maker and taker routes
+        traders.push(node_public_key);
+        Credentials {
+            admins: parse(&config.admins),
+            traders,
+            max_clock_skew_secs: config.max_clock_skew_secs,
+            clock,
+            seen: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    pub fn allows(&self, public_key: &PublicKey, role: Role) -> bool {
+        match role {
+            Role::Admin => self.admins.contains(public_key),
+            Role::Trader => self.admins.contains(public_key) || self.traders.contains(public_key),
+        }
+    }
+
+    // Accepts each signature once, forgetting those whose timestamp is already outside the window
+    fn first_use(&self, signature: &Signature, timestamp: u64) -> bool {
+        let now = self.clock.now_secs();
+        let mut seen = self.seen.lock().expect("Seen signatures are not poisoned");
+        let max_clock_skew_secs = self.max_clock_skew_secs;
+        seen.retain(|_, seen_at| seen_at.saturating_add(max_clock_skew_secs) >= now);
+        seen.insert(hex::encode(&signature.serialize_compact()[..]), timestamp).is_none()
+    }
+}
+
+pub fn now_secs() -> u64 {
+    SystemClock.now_secs()
+}
+
+// sha256(method \n path \n hex(sha256(body)) \n timestamp \n nonce)
+pub fn signing_digest(method: &str, path: &str, body: &[u8], timestamp: u64, nonce: &str) -> [u8; 32] {
+    let body_hash = hex::encode(Sha256::digest(body));
+    let preimage = format!("{}\n{}\n{}\n{}\n{}", method, path, body_hash, timestamp, nonce);
+    let mut digest = [0u8; 32];
+    digest.copy_from_slice(&Sha256::digest(preimage.as_bytes()));
+    digest
+}
+
+// Header name and value pairs to attach to a signed request
+pub fn sign_request(secret_key: &SecretKey, method: &str, path: &str, body: &[u8]) -> Vec<(&'static str, String)> {
+    let secp = Secp256k1::signing_only();
+    let timestamp = now_secs();
+    // Identical requests within a second still sign differently
+    let nonce = hex::encode(rand::random::<[u8; 16]>());
+    let message = Message::from_slice(&signing_digest(method, path, body, timestamp, &nonce)).expect("Digest is 32 bytes");
+    let signature = secp.sign(&message, secret_key);
+    vec![
+        (PUBKEY_HEADER, PublicKey::from_secret_key(&secp, secret_key).to_string()),
+        (TIMESTAMP_HEADER, timestamp.to_string()),
+        (NONCE_HEADER, nonce),
+        (SIGNATURE_HEADER, hex::encode(&signature.serialize_compact()[..])),
+    ]
+}
+
+fn header<'a>(headers: &'a HeaderMap, name: &'static str) -> Result<&'a str, AuthError> {
+    headers
+        .get(name)
+        .ok_or(AuthError::MissingHeader(name))?
+        .to_str()
+        .map_err(|_| AuthError::InvalidHeader(name))
+}
+
+pub fn verify_request(credentials: &Credentials, role: Role, method: &Method, path: &str, headers: &HeaderMap, body: &[u8]) -> Result<PublicKey, AuthError> {
+    let public_key = PublicKey::from_str(header(headers, PUBKEY_HEADER)?)
+        .map_err(|_| AuthError::InvalidHeader(PUBKEY_HEADER))?;
+    let timestamp = u64::from_str(header(headers, TIMESTAMP_HEADER)?)
+        .map_err(|_| AuthError::InvalidHeader(TIMESTAMP_HEADER))?;
+    let signature = hex::decode(header(headers, SIGNATURE_HEADER)?)
+        .ok()
+        .and_then(|bytes| Signature::from_compact(&bytes).ok())
+        .ok_or(AuthError::InvalidHeader(SIGNATURE_HEADER))?;
+    let nonce = header(headers, NONCE_HEADER)?;
+
+    // Bounds replay of a captured request to the allowed clock skew
+    let now = credentials.clock.now_secs();
+    let skew = if now > timestamp { now - timestamp } else { timestamp - now };
+    if skew > credentials.max_clock_skew_secs {
+        return Err(AuthError::StaleTimestamp)
+    }
+
+    let message = Message::from_slice(&signing_digest(method.as_str(), path, body, timestamp, nonce)).expect("Digest is 32 bytes");
+    Secp256k1::verification_only()
+        .verify(&message, &signature, &public_key)
+        .map_err(|_| AuthError::BadSignature)?;
+
+    if !credentials.allows(&public_key, role) {
+        return Err(AuthError::Forbidden)
+    }
+    // and within the window to a single use
+    if !credentials.first_use(&signature, timestamp) {
+        return Err(AuthError::Replayed)
+    }
+    Ok(public_key)
+}
+
+// Reads the body up to MAX_BODY_LEN, whether or not the client sent a content length
+fn limited_body() -> impl Filter<Extract = (Bytes,), Error = Rejection> + Clone {
+    warp::body::stream().and_then(|mut body| async move {
+        let mut bytes = BytesMut::new();
+        while let Some(chunk) = body.next().await {
+            let chunk = chunk.map_err(|_| warp::reject::custom(AuthError::InvalidBody))?;
+            if bytes.len() + chunk.remaining() > MAX_BODY_LEN {
+                return Err(warp::reject::custom(AuthError::BodyTooLarge))
+            }
+            bytes.extend_from_slice(chunk.bytes());
+        }
+        Ok::<Bytes, Rejection>(bytes.freeze())
+    })
+}
+
+// Verifies the request signature for the role and extracts the raw body.
+// Only a daemon built without credentials, which the server refuses unless auth is disabled in the config, skips it.
+pub fn authenticated(credentials: Arc<Option<Credentials>>, role: Role) -> impl Filter<Extract = (Bytes,), Error = Rejection> + Clone {
+    warp::method()
+        .and(warp::path::full())
+        .and(warp::header::headers_cloned())
+        .and(limited_body())
+        .and_then(move |method: Method, path: FullPath, headers: HeaderMap, body: Bytes| {
+            let credentials = credentials.clone();
+            async move {
+                if let Some(credentials) = credentials.as_ref() {
+                    verify_request(credentials, role, &method, path.as_str(), &headers, &body)
+                        .map_err(warp::reject::custom)?;
+                }
+                Ok::<Bytes, Rejection>(body)
+            }
+        })
+}
+
+pub fn authenticated_json<T: DeserializeOwned + Send>(credentials: Arc<Option<Credentials>>, role: Role) -> impl Filter<Extract = (T,), Error = Rejection> + Clone {
+    authenticated(credentials, role).and_then(|body: Bytes| async move {
+        serde_json::from_slice::<T>(&body).map_err(|_| warp::reject::custom(AuthError::InvalidBody))
+    })
+}
@@ -0,0 +1,97 @@
+// Offline checks of a rainboltd audit journal, for dispute review
+use clap::{App, AppSettings, Arg, SubCommand};
+use std::fs::File;
+use std::io::{self, Write};
+use std::path::Path;
+use std::process;
+
+// Internal
+use rainboltd::{
+    audit::{transcript, verify, AuditEntry, AuditError},
+    replay::replay,
+    settlement::Side
+};
+
+fn fail(err: AuditError) -> ! {
+    eprintln!("{}", err);
+    process::exit(1)
+}
+
+fn write_transcript<W: Write>(mut out: W, entries: &[AuditEntry]) -> io::Result<()> {
+    for entry in entries {
+        writeln!(out, "{}", serde_json::to_string(entry).expect("Audit entry serializes to JSON"))?;
+    }
+    Ok(())
+}
+
+fn main() {
+    let matches = App::new("rainbolt-audit")
+        .about("Verifies and exports rainboltd audit journals")
+        .setting(AppSettings::SubcommandRequiredElseHelp)
+        .subcommand(SubCommand::with_name("verify")
+            .about("Checks the hash chain of a journal")
+            .arg(Arg::with_name("journal").required(true)))
+        .subcommand(SubCommand::with_name("export")
+            .about("Writes every message of one channel as JSON lines")
+            .arg(Arg::with_name("journal").required(true))
+            .arg(Arg::with_name("channel").required(true).help("Customer public key of the channel"))
+            .arg(Arg::with_name("out")
+                .long("out")
+                .takes_value(true)
+                .help("File to write the transcript to, stdout if unset")))
+        .subcommand(SubCommand::with_name("replay")
+            .about("Replays one channel offline and reports the first step that differs from the journal")
+            .arg(Arg::with_name("journal").required(true))
+            .arg(Arg::with_name("channel").required(true).help("Customer public key of the channel"))
+            .arg(Arg::with_name("side")
+                .long("side")
+                .possible_values(&["taker", "maker"])
+                .default_value("taker")
+                .help("Whose messages to replay"))
+            .arg(Arg::with_name("seed")
+                .long("seed")
+                .default_value("0")
+                .help("Seed of the RNG the replayed maker and taker draw their keys from")))
+        .get_matches();
+
+    match matches.subcommand() {
+        ("verify", Some(args)) => {
+            let path = args.value_of("journal").expect("required argument");
+            let count = verify(Path::new(path)).unwrap_or_else(|err| fail(err));
+            println!("{} entries, chain intact", count);
+        },
+        ("export", Some(args)) => {
+            let path = args.value_of("journal").expect("required argument");
+            let channel = args.value_of("channel").expect("required argument");
+            let entries = transcript(Path::new(path), channel).unwrap_or_else(|err| fail(err));
+            let written = match args.value_of("out") {
+                Some(out) => File::create(out).and_then(|file| write_transcript(file, &entries)),
+                None => write_transcript(io::stdout(), &entries)
+            };
+            if let Err(err) = written {
+                fail(AuditError::Io(err))
+            }
+            eprintln!("Exported {} entries for channel {}", entries.len(), channel);
+        },
+        ("replay", Some(args)) => {
+            let path = args.value_of("journal").expect("required argument");
+            let channel = args.value_of("channel").expect("required argument");
+            let side = match args.value_of("side") {
+                Some("maker") => Side::Maker,
+                _ => Side::Taker
+            };
+            let seed = args.value_of("seed").expect("has a default").parse().unwrap_or_else(|_| {
+                eprintln!("Invalid seed");
+                process::exit(2)
+            });
+            let entries = transcript(Path::new(path), channel).unwrap_or_else(|err| fail(err));
+            let report = replay(&entries, side, seed);
+            println!("{}", serde_json::to_string_pretty(&report).expect("Replay report serializes to JSON"));
+            if let Some(divergence) = report.divergence {
+                eprintln!("Diverged at {}", divergence);
+                process::exit(1)
+            }
+        },
+        _ => unreachable!("a subcommand is required")
+    }
+}
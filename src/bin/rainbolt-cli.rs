@@ -0,0 +1,230 @@
+// Operator client for a running rainboltd
+use clap::{App, AppSettings, Arg, ArgMatches, SubCommand};
+use secp256k1::SecretKey;
+use serde::Serialize;
+use std::process;
+use std::str::FromStr;
+
+// Internal
+use rainboltd::{
+    client::{ClientError, RainboltClient},
+    history::CatchUpMode,
+    message::OrderRequest,
+    view::{ChannelView, MakerView, TakerView},
+    settlement::Side,
+    MarketData,
+    MarketPrice
+};
+
+fn print_table(rows: &[(&str, String)]) {
+    let width = rows.iter().map(|(name, _)| name.len()).max().unwrap_or(0);
+    for (name, value) in rows {
+        println!("{:<width$}  {}", name, value, width = width);
+    }
+}
+
+fn optional<T: ToString>(value: Option<T>) -> String {
+    value.map_or("-".to_string(), |value| value.to_string())
+}
+
+fn maker_rows(maker: &MakerView) -> Vec<(&'static str, String)> {
+    vec![
+        ("initial margin", maker.initial_margin.to_string()),
+        ("available margin", maker.available_margin.to_string()),
+        ("reserved margin", maker.reserved_margin.to_string()),
+        ("collateral", optional(maker.collateral)),
+        ("order size", optional(maker.order_size)),
+        ("maker margin", optional(maker.maker_margin)),
+        ("taker margin", optional(maker.taker_margin)),
+        ("net payments", maker.net_payments.to_string()),
+        ("epoch", maker.epoch.to_string()),
+        ("last settled epoch", maker.last_settled_epoch.to_string()),
+    ]
+}
+
+fn taker_rows(taker: &TakerView) -> Vec<(&'static str, String)> {
+    vec![
+        ("established", taker.established.to_string()),
+        ("closed", taker.closed.to_string()),
+        ("order size", taker.order_size.to_string()),
+        ("taker balance", taker.taker_balance.to_string()),
+        ("maker balance", taker.maker_balance.to_string()),
+        ("pnl", (taker.taker_balance - taker.initial_margin).to_string()),
+        ("epoch", taker.epoch.to_string()),
+        ("last settled epoch", taker.last_settled_epoch.to_string()),
+        ("settlement", format!("{:?}", taker.settlement)),
+    ]
+}
+
+// One column per channel
+fn print_channels(channels: &[ChannelView]) {
+    let header = ["side", "phase", "taker balance", "maker balance", "pnl", "last settled", "available margin"];
+    let rows: Vec<Vec<String>> = channels.iter().map(|channel| {
+        // PnL of the side that owns the view
+        let pnl = match channel.side {
+            Side::Taker => channel.taker_balance - channel.margin.taker_margin.unwrap_or(0),
+            Side::Maker => channel.maker_balance - channel.margin.maker_margin.unwrap_or(0)
+        };
+        vec![
+            format!("{:?}", channel.side),
+            format!("{:?}", channel.phase),
+            channel.taker_balance.to_string(),
+            channel.maker_balance.to_string(),
+            pnl.to_string(),
+            channel.last_settled_epoch.to_string(),
+            channel.margin.available_margin.to_string(),
+        ]
+    }).collect();
+    let widths: Vec<usize> = header.iter().enumerate().map(|(i, name)| {
+        rows.iter().map(|row| row[i].len()).chain(Some(name.len())).max().unwrap_or(0)
+    }).collect();
+    let print_row = |row: Vec<String>| {
+        let cells: Vec<String> = row.iter().zip(&widths).map(|(cell, width)| format!("{:<width$}", cell, width = width)).collect();
+        println!("{}", cells.join("  ").trim_end());
+    };
+    print_row(header.iter().map(|name| name.to_string()).collect());
+    rows.into_iter().for_each(print_row);
+}
+
+fn output<T: Serialize>(json: bool, value: &T, rows: Vec<(&str, String)>) {
+    if json {
+        println!("{}", serde_json::to_string_pretty(value).expect("Response serializes to JSON"));
+    } else {
+        print_table(&rows);
+    }
+}
+
+fn parse<T: FromStr>(matches: &ArgMatches, name: &str) -> T {
+    let value = matches.value_of(name).expect("required argument");
+    value.parse().unwrap_or_else(|_| {
+        eprintln!("Invalid {}: {}", name, value);
+        process::exit(2)
+    })
+}
+
+async fn run(client: RainboltClient, matches: ArgMatches<'_>) -> Result<(), ClientError> {
+    let json = matches.is_present("json");
+    match matches.subcommand() {
+        ("init-maker", Some(args)) => {
+            let maker = client.init_maker(parse(args, "margin")).await?;
+            output(json, &maker, maker_rows(&maker));
+        },
+        ("post-order", Some(args)) => {
+            let collateral = client.set_collateral(parse(args, "collateral")).await?;
+            output(json, &collateral, vec![("collateral", collateral.to_string())]);
+        },
+        ("cancel-order", Some(_)) => {
+            let available_margin = client.release().await?;
+            output(json, &available_margin, vec![("available margin", available_margin.to_string())]);
+        },
+        ("take-order", Some(args)) => {
+            let req = OrderRequest {
+                initial_margin: parse(args, "margin"),
+                order_size: parse(args, "size"),
+                maker_order_id: args.value_of("maker-order-id").unwrap_or_default().to_string()
+            };
+            let taker = client.order(&req).await?;
+            output(json, &taker, taker_rows(&taker));
+        },
+        ("settle", Some(_)) => {
+            let taker = client.pay().await?;
+            output(json, &taker, taker_rows(&taker));
+        },
+        ("request-settlement", Some(_)) => {
+            let requested = client.maker_settle().await?;
+            output(json, &requested, vec![("requested", requested.to_string())]);
+        },
+        ("catch-up", Some(args)) => {
+            let mode = match args.value_of("mode") {
+                Some("netted") => CatchUpMode::Netted,
+                _ => CatchUpMode::EveryEpoch
+            };
+            let mode = client.catch_up(&mode).await?;
+            output(json, &mode, vec![("catch up", format!("{:?}", mode))]);
+        },
+        ("close", Some(_)) => {
+            let taker = client.close().await?;
+            output(json, &taker, taker_rows(&taker));
+        },
+        ("balances", Some(_)) => {
+            let channels = client.channels().await?;
+            if json {
+                println!("{}", serde_json::to_string_pretty(&channels).expect("Response serializes to JSON"));
+            } else {
+                print_channels(&channels);
+            }
+        },
+        ("price", Some(args)) => {
+            let market_data = MarketData {
+                bitcoin: MarketPrice { usd: parse(args, "bitcoin") },
+                cosmos: MarketPrice { usd: parse(args, "cosmos") }
+            };
+            client.push_market_data(&market_data).await?;
+            output(json, &market_data, vec![
+                ("bitcoin", market_data.bitcoin.usd.to_string()),
+                ("cosmos", market_data.cosmos.usd.to_string()),
+            ]);
+        },
+        _ => unreachable!("a subcommand is required")
+    }
+    Ok(())
+}
+
+#[tokio::main]
+async fn main() {
+    let matches = App::new("rainbolt-cli")
+        .about("Drives a rainboltd maker or taker")
+        .setting(AppSettings::SubcommandRequiredElseHelp)
+        .arg(Arg::with_name("url")
+            .long("url")
+            .env("RAINBOLT_URL")
+            .default_value("http://localhost:3030")
+            .help("Daemon to talk to"))
+        .arg(Arg::with_name("key")
+            .long("key")
+            .env("RAINBOLT_KEY")
+            .takes_value(true)
+            .help("Hex secp256k1 secret key used to sign requests"))
+        .arg(Arg::with_name("json")
+            .long("json")
+            .global(true)
+            .help("Prints the raw JSON response"))
+        .subcommand(SubCommand::with_name("init-maker")
+            .about("Creates the maker with its initial margin")
+            .arg(Arg::with_name("margin").required(true)))
+        .subcommand(SubCommand::with_name("post-order")
+            .about("Sets the collateral the maker posts per channel")
+            .arg(Arg::with_name("collateral").required(true)))
+        .subcommand(SubCommand::with_name("cancel-order")
+            .about("Releases the maker's pending order reservation"))
+        .subcommand(SubCommand::with_name("take-order")
+            .about("Takes an order and opens a channel with the maker")
+            .arg(Arg::with_name("margin").long("margin").takes_value(true).required(true))
+            .arg(Arg::with_name("size").long("size").takes_value(true).required(true))
+            .arg(Arg::with_name("maker-order-id").long("maker-order-id").takes_value(true)))
+        .subcommand(SubCommand::with_name("settle")
+            .about("Runs a payment round for the taker's channel"))
+        .subcommand(SubCommand::with_name("request-settlement")
+            .about("Asks the taker to settle what the maker owes"))
+        .subcommand(SubCommand::with_name("catch-up")
+            .about("Sets how the taker settles missed epochs")
+            .arg(Arg::with_name("mode").possible_values(&["every-epoch", "netted"]).required(true)))
+        .subcommand(SubCommand::with_name("close")
+            .about("Closes the taker's channel on its latest state"))
+        .subcommand(SubCommand::with_name("balances")
+            .about("Shows channel balances and PnL"))
+        .subcommand(SubCommand::with_name("price")
+            .about("Pushes a manual price tick")
+            .arg(Arg::with_name("bitcoin").required(true))
+            .arg(Arg::with_name("cosmos").required(true)))
+        .get_matches();
+
+    let mut client = RainboltClient::new(matches.value_of("url").expect("url has a default"));
+    if let Some(key) = matches.value_of("key") {
+        client = client.signing_key(SecretKey::from_str(key).expect("key is a valid hex secp256k1 key"));
+    }
+    if let Err(err) = run(client, matches).await {
+        eprintln!("{}", err);
+        process::exit(1);
+    }
+}
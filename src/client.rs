@@ -0,0 +1,215 @@
+// Typed async client for the rainboltd HTTP API, used by tools and by the daemon's own calls to its counterparty
+use reqwest::{Client, Method, StatusCode, header::CONTENT_TYPE};
+use secp256k1::SecretKey;
+use serde::{Serialize, de::DeserializeOwned};
+use tracing::warn;
+
+// Internal
+use crate::admission::{AdmissionPolicy, OrderRejection};
+use crate::auth::sign_request;
+use crate::clock::{system_clock, SharedClock};
+use crate::history::CatchUpMode;
+use crate::message::{
+    OrderRequest,
+    OpenChannelRequest,
+    OpenChannelResponse,
+    ChannelEstablishedRequest,
+    SettlementRequest,
+    PaymentRequest,
+    PaymentResponse,
+    GeneratePaymentTokenRequest,
+    GeneratePaymentTokenResponse,
+    CloseChannelRequest,
+    CloseChannelResponse,
+};
+use crate::settlement::{Backoff, PaymentRejection};
+use crate::transport::TransportError;
+use crate::view::{ChannelView, MakerView, TakerView};
+use crate::MarketData;
+
+pub type ClientError = TransportError;
+
+#[derive(Clone)]
+pub struct RainboltClient {
+    pub client: Client,
+    pub base_url: String,
+    pub signing_key: Option<SecretKey>,
+    pub backoff: Backoff,
+    pub clock: SharedClock, // Paces the retries
+}
+
+impl RainboltClient {
+    pub fn new<S: Into<String>>(base_url: S) -> Self {
+        RainboltClient {
+            client: Client::new(),
+            base_url: base_url.into(),
+            signing_key: None,
+            backoff: Backoff::default(),
+            clock: system_clock(),
+        }
+    }
+
+    pub fn client(mut self, client: Client) -> Self {
+        self.client = client;
+        self
+    }
+
+    // Signs every request for daemons with auth configured
+    pub fn signing_key(mut self, signing_key: SecretKey) -> Self {
+        self.signing_key = Some(signing_key);
+        self
+    }
+
+    pub fn backoff(mut self, backoff: Backoff) -> Self {
+        backoff.validate();
+        self.backoff = backoff;
+        self
+    }
+
+    pub fn clock(mut self, clock: SharedClock) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    async fn send_once(&self, method: &Method, path: &str, body: &[u8]) -> Result<reqwest::Response, reqwest::Error> {
+        let headers = match self.signing_key {
+            Some(ref signing_key) => sign_request(signing_key, method.as_str(), path, body),
+            None => Vec::new()
+        };
+        headers
+            .into_iter()
+            .fold(
+                self.client.request(method.clone(), &format!("{}{}", self.base_url, path)).header(CONTENT_TYPE, "application/json"),
+                |req, (name, value)| req.header(name, value)
+            )
+            .body(body.to_vec())
+            .send()
+            .await
+    }
+
+    // Retries only when the daemon could not be reached, so a request is never applied twice
+    async fn send(&self, method: Method, path: &str, body: &[u8]) -> Result<reqwest::Response, ClientError> {
+        let mut attempt = 1;
+        let res = loop {
+            match self.send_once(&method, path, body).await {
+                Ok(res) => break res,
+                Err(err) if err.is_connect() && attempt < self.backoff.max_attempts => {
+                    warn!("{} unreachable (attempt {}): {}", path, attempt, err);
+                    self.clock.sleep(self.backoff.delay(attempt)).await;
+                    attempt += 1;
+                },
+                Err(err) => return Err(err.into())
+            }
+        };
+        match res.status() {
+            status if status.is_success() => Ok(res),
+            StatusCode::BAD_REQUEST => {
+                let text = res.text().await?;
+                if let Ok(rejection) = serde_json::from_str::<OrderRejection>(&text) {
+                    return Err(TransportError::Rejected(rejection))
+                }
+                match serde_json::from_str::<PaymentRejection>(&text) {
+                    Ok(rejection) => Err(TransportError::Refused(rejection)),
+                    Err(_) => Err(TransportError::Remote(format!("{} returned 400: {}", path, text)))
+                }
+            },
+            status => Err(TransportError::Remote(format!("{} returned {}", path, status)))
+        }
+    }
+
+    async fn post<T: Serialize, R: DeserializeOwned>(&self, path: &str, body: &T) -> Result<R, ClientError> {
+        let body = serde_json::to_vec(body).map_err(|err| TransportError::Io(err.to_string()))?;
+        Ok(self.send(Method::POST, path, &body).await?.json().await?)
+    }
+
+    async fn post_empty<R: DeserializeOwned>(&self, path: &str) -> Result<R, ClientError> {
+        Ok(self.send(Method::POST, path, &[]).await?.json().await?)
+    }
+
+    async fn get<R: DeserializeOwned>(&self, path: &str) -> Result<R, ClientError> {
+        Ok(self.send(Method::GET, path, &[]).await?.json().await?)
+    }
+
+    // Routes that answer with plain text
+    async fn post_unit<T: Serialize>(&self, path: &str, body: &T) -> Result<(), ClientError> {
+        let body = serde_json::to_vec(body).map_err(|err| TransportError::Io(err.to_string()))?;
+        self.send(Method::POST, path, &body).await.map(|_| ())
+    }
+
+    // POST /maker/...
+    pub async fn init_maker(&self, initial_margin: i64) -> Result<MakerView, ClientError> {
+        self.post_empty(&format!("/maker/init/{}", initial_margin)).await
+    }
+
+    pub async fn set_collateral(&self, collateral: i64) -> Result<i64, ClientError> {
+        self.post_empty(&format!("/maker/collateral/{}", collateral)).await
+    }
+
+    pub async fn set_policy(&self, policy: &AdmissionPolicy) -> Result<AdmissionPolicy, ClientError> {
+        self.post("/maker/policy", policy).await
+    }
+
+    pub async fn release(&self) -> Result<i64, ClientError> {
+        self.post_empty("/maker/release").await
+    }
+
+    pub async fn maker_settle(&self) -> Result<bool, ClientError> {
+        self.post_empty("/maker/settle").await
+    }
+
+    pub async fn open_channel(&self, req: &OpenChannelRequest) -> Result<OpenChannelResponse, ClientError> {
+        self.post("/maker/openChannel", req).await
+    }
+
+    pub async fn channel_established(&self, req: &ChannelEstablishedRequest) -> Result<(), ClientError> {
+        self.post_unit("/maker/established", req).await
+    }
+
+    pub async fn recv_pay(&self, req: &PaymentRequest) -> Result<PaymentResponse, ClientError> {
+        self.post("/maker/recvPay", req).await
+    }
+
+    pub async fn payment_token(&self, req: &GeneratePaymentTokenRequest) -> Result<GeneratePaymentTokenResponse, ClientError> {
+        self.post("/maker/paymentToken", req).await
+    }
+
+    pub async fn close_channel(&self, req: &CloseChannelRequest) -> Result<CloseChannelResponse, ClientError> {
+        self.post("/maker/close", req).await
+    }
+
+    // POST /taker/...
+    pub async fn order(&self, req: &OrderRequest) -> Result<TakerView, ClientError> {
+        self.post("/taker/order", req).await
+    }
+
+    pub async fn pay(&self) -> Result<TakerView, ClientError> {
+        self.post_empty("/taker/pay").await
+    }
+
+    pub async fn settle(&self, req: &SettlementRequest) -> Result<TakerView, ClientError> {
+        self.post("/taker/settle", req).await
+    }
+
+    pub async fn catch_up(&self, mode: &CatchUpMode) -> Result<CatchUpMode, ClientError> {
+        self.post("/taker/catchUp", mode).await
+    }
+
+    pub async fn close(&self) -> Result<TakerView, ClientError> {
+        self.post_empty("/taker/close").await
+    }
+
+    // GET /channels/...
+    pub async fn channels(&self) -> Result<Vec<ChannelView>, ClientError> {
+        self.get("/channels").await
+    }
+
+    // Every side of the channel this daemon holds, by customer public key
+    pub async fn channel(&self, channel: &str) -> Result<Vec<ChannelView>, ClientError> {
+        self.get(&format!("/channels/{}", channel)).await
+    }
+
+    // POST /marketData
+    pub async fn push_market_data(&self, market_data: &MarketData) -> Result<(), ClientError> {
+        self.post_unit("/marketData", market_data).await
+    }
+}
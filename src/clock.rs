@@ -0,0 +1,109 @@
+// Time source for the maker, taker and daemon, so time dependent logic can run on a manual clock in tests
+use std::fmt;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::task::{Context, Poll, Waker};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::timer::delay_for;
+
+pub type Sleep = Pin<Box<dyn Future<Output = ()> + Send>>;
+
+pub trait Clock: fmt::Debug + Send + Sync {
+    // Milliseconds since the unix epoch
+    fn now_millis(&self) -> u64;
+
+    // Resolves once the clock has moved on by the duration, for retry and backoff delays
+    fn sleep(&self, duration: Duration) -> Sleep;
+
+    fn now_secs(&self) -> u64 {
+        self.now_millis() / 1000
+    }
+}
+
+pub type SharedClock = Arc<dyn Clock>;
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now_millis(&self) -> u64 {
+        SystemTime::now().duration_since(UNIX_EPOCH).expect("Clock is after the epoch").as_millis() as u64
+    }
+
+    fn sleep(&self, duration: Duration) -> Sleep {
+        Box::pin(delay_for(duration))
+    }
+}
+
+pub fn system_clock() -> SharedClock {
+    Arc::new(SystemClock)
+}
+
+// Only moves when told to, waking the sleeps it has passed. Clones share the same time.
+#[derive(Clone, Debug, Default)]
+pub struct ManualClock {
+    millis: Arc<AtomicU64>,
+    sleepers: Arc<Mutex<Vec<Waker>>>,
+}
+
+impl ManualClock {
+    pub fn new(millis: u64) -> Self {
+        ManualClock { millis: Arc::new(AtomicU64::new(millis)), sleepers: Arc::default() }
+    }
+
+    pub fn set(&self, millis: u64) {
+        self.millis.store(millis, Ordering::SeqCst);
+        self.wake();
+    }
+
+    pub fn advance(&self, duration: Duration) {
+        self.millis.fetch_add(duration.as_millis() as u64, Ordering::SeqCst);
+        self.wake();
+    }
+
+    // Sleeps that have not seen the clock reach their deadline yet
+    pub fn sleeping(&self) -> usize {
+        self.sleepers.lock().expect("Sleepers are not poisoned").len()
+    }
+
+    fn wake(&self) {
+        for waker in self.sleepers.lock().expect("Sleepers are not poisoned").drain(..) {
+            waker.wake();
+        }
+    }
+}
+
+impl Clock for ManualClock {
+    fn now_millis(&self) -> u64 {
+        self.millis.load(Ordering::SeqCst)
+    }
+
+    fn sleep(&self, duration: Duration) -> Sleep {
+        let deadline = self.now_millis().saturating_add(duration.as_millis() as u64);
+        Box::pin(ManualSleep { clock: self.clone(), deadline })
+    }
+}
+
+struct ManualSleep {
+    clock: ManualClock,
+    deadline: u64,
+}
+
+impl Future for ManualSleep {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+        if self.clock.now_millis() >= self.deadline {
+            return Poll::Ready(())
+        }
+        // Checked again once registered, so an advance in between still wakes us
+        self.clock.sleepers.lock().expect("Sleepers are not poisoned").push(cx.waker().clone());
+        if self.clock.now_millis() >= self.deadline {
+            Poll::Ready(())
+        } else {
+            Poll::Pending
+        }
+    }
+}
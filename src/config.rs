@@ -0,0 +1,83 @@
+use serde::{Serialize, Deserialize};
+use std::env;
+use std::fs::{self, DirBuilder};
+use std::io;
+use std::os::unix::fs::DirBuilderExt;
+use std::path::{Path, PathBuf};
+use tracing::info;
+
+// Internal
+use crate::auth::AuthConfig;
+use crate::logging::LogConfig;
+use crate::tls::TlsConfig;
+use crate::webhook::WebhookConfig;
+
+pub const CONFIG_ENV: &str = "RAINBOLTD_CONFIG";
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct Config {
+    pub node_secret_key: Option<String>, // hex secp256k1 key used to sign the daemon's own requests
+    pub auth: Option<AuthConfig>,
+    #[serde(default)]
+    pub disable_auth: bool, // Serve every route without signatures, for local testing only
+    pub tls: Option<TlsConfig>,
+    pub peer_url: Option<String>, // Base url of the counterparty, defaults to this daemon
+    pub peer_listen_addr: Option<String>, // Address for the encrypted peer transport, disabled if unset
+    pub peer_addr: Option<String>, // Counterparty's encrypted peer transport, used instead of peer_url if set
+    pub peer_public_key: Option<String>, // hex secp256k1 key the counterparty must prove on peer_addr
+    pub webhooks: Option<WebhookConfig>,
+    pub metrics_listen_addr: Option<String>, // Address for the unsigned /metrics scrape, disabled if unset
+    pub audit_journal: Option<String>, // Path of the hash chained message journal, disabled if unset
+    pub log: Option<LogConfig>,
+    pub data_dir: Option<String>, // Generated keys and logs, defaults to ~/.rainboltd
+    #[serde(skip)]
+    pub path: Option<String>, // File the config was read from, None for the defaults
+}
+
+impl Config {
+    pub fn peer_url(&self) -> String {
+        match (&self.peer_url, &self.tls) {
+            (Some(url), _) => url.clone(),
+            (None, Some(_)) => "https://localhost:3030".to_string(),
+            (None, None) => "http://localhost:3030".to_string()
+        }
+    }
+
+    pub fn data_dir(&self) -> PathBuf {
+        match self.data_dir {
+            Some(ref dir) => PathBuf::from(dir),
+            None => env::var("HOME").map_or(PathBuf::from(".rainboltd"), |home| Path::new(&home).join(".rainboltd"))
+        }
+    }
+
+    // Reads the JSON config named by RAINBOLTD_CONFIG, falling back to the defaults
+    pub fn load() -> Self {
+        match env::var(CONFIG_ENV) {
+            Ok(path) => {
+                let contents = fs::read_to_string(&path)
+                    .unwrap_or_else(|err| panic!("Failed to read config {}: {}", path, err));
+                let mut config: Config = serde_json::from_str(&contents)
+                    .unwrap_or_else(|err| panic!("Failed to parse config {}: {}", path, err));
+                if let Some(backoff) = config.webhooks.as_ref().and_then(|webhooks| webhooks.backoff.as_ref()) {
+                    backoff.validate();
+                }
+                config.path = Some(path);
+                config
+            },
+            Err(_) => Config::default()
+        }
+    }
+
+    // Logged once logging is set up from the config itself
+    pub fn log_source(&self) {
+        match self.path {
+            Some(ref path) => info!("Using the config in {}", path),
+            None => info!("No {} set, using the default config", CONFIG_ENV)
+        }
+    }
+}
+
+// Directory only the daemon's user can list or write, for keys and logs it generates
+pub fn create_private_dir(dir: &Path) -> io::Result<()> {
+    DirBuilder::new().recursive(true).mode(0o700).create(dir)
+}
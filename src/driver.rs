@@ -0,0 +1,163 @@
+// Runs the protocol rounds between a Taker and a Maker over any Transport.
+// The taker lives in a shared slot that is only locked between messages, never across a send.
+use std::sync::{Arc, Mutex};
+use tracing::{debug, info};
+
+// Internal
+use crate::audit::{record_maker, record_taker, Direction};
+use crate::maker::{Maker, MakerState};
+use crate::message::SettlementRequest;
+use crate::peer::PeerMessage;
+use crate::settlement::PaymentRejection;
+use crate::taker::{Taker, TakerState};
+use crate::transport::{Transport, TransportError};
+
+pub type TakerSlot = Arc<Mutex<Option<TakerState>>>;
+pub type MakerSlot = Arc<Mutex<Option<MakerState>>>;
+
+fn with_maker<F: FnOnce(&MakerState)>(slot: &MakerSlot, f: F) {
+    if let Some(maker) = slot.lock().expect("Maker is not poisoned").as_ref() {
+        f(maker);
+    }
+}
+
+fn with_taker<R, F: FnOnce(&mut TakerState) -> R>(slot: &TakerSlot, f: F) -> R {
+    let mut maybe_taker = slot.lock().expect("Taker is not poisoned");
+    maybe_taker.as_mut().map(f).expect("taker exists")
+}
+
+// Sends a taker message, journaling it and whatever comes back
+async fn taker_request(slot: &TakerSlot, transport: &dyn Transport, msg: PeerMessage) -> Result<PeerMessage, TransportError> {
+    with_taker(slot, |taker| record_taker(taker, Direction::Outbound, &msg));
+    let res = transport.request(msg).await?;
+    with_taker(slot, |taker| record_taker(taker, Direction::Inbound, &res));
+    Ok(res)
+}
+
+fn unexpected(res: PeerMessage, expected: &'static str) -> TransportError {
+    match res {
+        PeerMessage::OrderRejection(rejection) => TransportError::Rejected(rejection),
+        PeerMessage::PaymentRejection(rejection) => TransportError::Refused(rejection),
+        PeerMessage::Error(err) => TransportError::Remote(err),
+        _ => TransportError::Unexpected(expected)
+    }
+}
+
+// Open channel request, close and pay tokens, then lets the maker lock its collateral
+pub async fn open_channel(slot: &TakerSlot, transport: &dyn Transport) -> Result<TakerState, TransportError> {
+    let req = with_taker(slot, |taker| taker.send_open_channel_req());
+    let res = match taker_request(slot, transport, PeerMessage::OpenChannelRequest(req)).await? {
+        PeerMessage::OpenChannelResponse(res) => res,
+        res => return Err(unexpected(res, "OpenChannelResponse"))
+    };
+
+    let channel_established_req = with_taker(slot, |taker| {
+        taker.recv_open_channel_res(res)?;
+        Ok::<_, TransportError>(taker.send_channel_established_req())
+    })?;
+    match taker_request(slot, transport, PeerMessage::ChannelEstablishedRequest(channel_established_req)).await? {
+        PeerMessage::Ack => Ok(with_taker(slot, |taker| taker.clone())),
+        res => Err(unexpected(res, "Ack"))
+    }
+}
+
+// Runs one payment round for the taker's channel: payment proof, revoke token, new pay token
+pub async fn settle(slot: &TakerSlot, transport: &dyn Transport) -> Result<TakerState, TransportError> {
+    // A revoke token that was never acknowledged means the maker already issued the new close token
+    let revoke_outstanding = with_taker(slot, |taker| taker.revoke_token.is_some());
+
+    let generate_payment_token_req = if revoke_outstanding {
+        info!("Resuming settlement from the revoke step");
+        with_taker(slot, |taker| taker.send_generate_payment_token_req())
+    } else {
+        let send_payment_req = with_taker(slot, |taker| {
+            taker.send_payment_req(&mut rand::thread_rng())
+                .ok_or_else(|| TransportError::Refused(PaymentRejection::MissingMarketData { from_epoch: taker.last_settled_epoch, to_epoch: taker.epoch }))
+        })?;
+        debug!("Sending payment request: {}", send_payment_req.payment_proof.amount);
+        let send_payment_res = match taker_request(slot, transport, PeerMessage::PaymentRequest(send_payment_req)).await? {
+            PeerMessage::PaymentResponse(res) => res,
+            res => return Err(unexpected(res, "PaymentResponse"))
+        };
+        with_taker(slot, |taker| {
+            taker.recv_payment_res(send_payment_res)?;
+            Ok::<_, TransportError>(taker.send_generate_payment_token_req())
+        })?
+    };
+
+    let generate_payment_token_res = match taker_request(slot, transport, PeerMessage::GeneratePaymentTokenRequest(generate_payment_token_req)).await? {
+        PeerMessage::GeneratePaymentTokenResponse(res) => res,
+        res => return Err(unexpected(res, "GeneratePaymentTokenResponse"))
+    };
+    with_taker(slot, |taker| {
+        taker.recv_generate_payment_token_res(generate_payment_token_res)?;
+        Ok(taker.clone())
+    })
+}
+
+// Closes the taker's channel on its latest state, once no payment is in flight
+pub async fn close_channel(slot: &TakerSlot, transport: &dyn Transport) -> Result<TakerState, TransportError> {
+    let req = with_taker(slot, |taker| taker.send_close_channel_req())?;
+    let res = match taker_request(slot, transport, PeerMessage::CloseChannelRequest(req)).await? {
+        PeerMessage::CloseChannelResponse(res) => res,
+        res => return Err(unexpected(res, "CloseChannelResponse"))
+    };
+    with_taker(slot, |taker| {
+        taker.recv_close_channel_res(res)?;
+        Ok(taker.clone())
+    })
+}
+
+// Maker asks the taker to settle, since only the taker can generate payment proofs
+pub async fn request_settlement(slot: &MakerSlot, req: SettlementRequest, transport: &dyn Transport) -> Result<(), TransportError> {
+    let msg = PeerMessage::SettlementRequest(req);
+    with_maker(slot, |maker| record_maker(maker, Direction::Outbound, &msg));
+    let res = transport.request(msg).await?;
+    with_maker(slot, |maker| record_maker(maker, Direction::Inbound, &res));
+    match res {
+        PeerMessage::Ack => Ok(()),
+        res => Err(unexpected(res, "Ack"))
+    }
+}
+
+// Answers the taker's protocol messages on the maker side
+pub fn handle_maker_message(maker: &mut MakerState, msg: PeerMessage) -> PeerMessage {
+    record_maker(maker, Direction::Inbound, &msg);
+    let res = answer_maker_message(maker, msg);
+    record_maker(maker, Direction::Outbound, &res);
+    res
+}
+
+fn answer_maker_message(maker: &mut MakerState, msg: PeerMessage) -> PeerMessage {
+    match msg {
+        PeerMessage::OpenChannelRequest(req) => match maker.recv_open_channel_req(&mut rand::thread_rng(), req) {
+            Ok(res) => PeerMessage::OpenChannelResponse(res),
+            Err(rejection) => PeerMessage::OrderRejection(rejection)
+        },
+        PeerMessage::ChannelEstablishedRequest(req) => match maker.recv_channel_established(req) {
+            Ok(()) => PeerMessage::Ack,
+            Err(rejection) => PeerMessage::OrderRejection(rejection)
+        },
+        PeerMessage::PaymentRequest(req) => match maker.recv_payment_req(&mut rand::thread_rng(), req) {
+            Ok(res) => PeerMessage::PaymentResponse(res),
+            Err(rejection) => PeerMessage::PaymentRejection(rejection)
+        },
+        PeerMessage::GeneratePaymentTokenRequest(req) => match maker.recv_generate_payment_token_req(req) {
+            Ok(res) => PeerMessage::GeneratePaymentTokenResponse(res),
+            Err(rejection) => PeerMessage::PaymentRejection(rejection)
+        },
+        PeerMessage::CloseChannelRequest(req) => match maker.recv_close_channel_req(req) {
+            Ok(res) => PeerMessage::CloseChannelResponse(res),
+            Err(rejection) => PeerMessage::PaymentRejection(rejection)
+        },
+        _ => PeerMessage::Error("Unexpected message for the maker".to_string())
+    }
+}
+
+pub fn handle_maker_slot_message(slot: &MakerSlot, msg: PeerMessage) -> PeerMessage {
+    let mut maybe_maker = slot.lock().expect("Maker is not poisoned");
+    match maybe_maker.as_mut() {
+        Some(maker) => handle_maker_message(maker, msg),
+        None => PeerMessage::Error("Maker does not exist".to_string())
+    }
+}
@@ -0,0 +1,169 @@
+// Domain events emitted by the maker and taker as the protocol progresses, fanned out to subscribers
+use serde::{Serialize, Deserialize};
+use std::sync::{Arc, Mutex};
+use tokio::sync::mpsc;
+use tracing::warn;
+
+// Internal
+use crate::maker::MakerState;
+use crate::settlement::Side;
+use crate::taker::TakerState;
+use crate::MarketData;
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(tag = "type")]
+pub enum Event {
+    PriceEpochClosed { epoch: u64, market_data: MarketData },
+    ChannelEstablished { side: Side, channel: String },
+    PaymentProofVerified { side: Side, channel: String, to_epoch: u64, amount: i64 },
+    RevokeTokenAccepted { side: Side, channel: String, epoch: u64 },
+    PayTokenIssued { side: Side, channel: String, epoch: u64 },
+    // The outstanding payment is more than the paying side has left in the channel
+    MarginCall { side: Side, channel: String, epoch: u64, balance: i64, payment: i64 },
+    ChannelClosed { side: Side, channel: String, taker_balance: i64, maker_balance: i64 },
+    OrderRejected { side: Side, channel: String, reason: String },
+    // The payment proof does not match the payment computed from our own price history
+    PaymentDisputed { side: Side, channel: String, to_epoch: u64, expected: i64, received: i64 },
+    // Retries ran out, the side that owes has not paid
+    SettlementFailed { side: Side, channel: String, epoch: u64, attempts: u32, error: String },
+}
+
+impl Event {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Event::PriceEpochClosed { .. } => "price_epoch_closed",
+            Event::ChannelEstablished { .. } => "channel_established",
+            Event::PaymentProofVerified { .. } => "payment_proof_verified",
+            Event::RevokeTokenAccepted { .. } => "revoke_token_accepted",
+            Event::PayTokenIssued { .. } => "pay_token_issued",
+            Event::MarginCall { .. } => "margin_call",
+            Event::ChannelClosed { .. } => "channel_closed",
+            Event::OrderRejected { .. } => "order_rejected",
+            Event::PaymentDisputed { .. } => "payment_disputed",
+            Event::SettlementFailed { .. } => "settlement_failed",
+        }
+    }
+
+    // Channel the event belongs to, closed epochs belong to every channel
+    pub fn channel(&self) -> Option<&str> {
+        match self {
+            Event::PriceEpochClosed { .. } => None,
+            Event::ChannelEstablished { channel, .. }
+            | Event::PaymentProofVerified { channel, .. }
+            | Event::RevokeTokenAccepted { channel, .. }
+            | Event::PayTokenIssued { channel, .. }
+            | Event::MarginCall { channel, .. }
+            | Event::ChannelClosed { channel, .. }
+            | Event::OrderRejected { channel, .. }
+            | Event::PaymentDisputed { channel, .. }
+            | Event::SettlementFailed { channel, .. } => Some(channel)
+        }
+    }
+
+    pub fn matches(&self, channel: Option<&str>) -> bool {
+        match (channel, self.channel()) {
+            (Some(wanted), Some(channel)) => wanted == channel,
+            _ => true
+        }
+    }
+}
+
+// Query of the streaming endpoint, without a channel every event is delivered
+#[derive(Deserialize, Debug)]
+pub struct EventFilter {
+    pub channel: Option<String>,
+}
+
+// Channels are named by the customer public key, which both sides know
+pub fn taker_channel(taker: &TakerState) -> String {
+    taker.channel_token.pk_c.map_or(String::new(), |pk| pk.to_string())
+}
+
+pub fn maker_channel(maker: &MakerState) -> String {
+    maker.channel_token.pk_c.map_or(String::new(), |pk| pk.to_string())
+}
+
+pub fn taker_margin_call(taker: &TakerState) -> Option<Event> {
+    if !taker.established || taker.closed {
+        return None
+    }
+    let payment = taker.price_history.compute_payment(taker.last_settled_epoch, taker.epoch, taker.order_size)?;
+    let balance = taker.customer_state.cust_balance;
+    if payment <= balance {
+        return None
+    }
+    Some(Event::MarginCall { side: Side::Taker, channel: taker_channel(taker), epoch: taker.epoch, balance, payment })
+}
+
+pub fn maker_margin_call(maker: &MakerState) -> Option<Event> {
+    let order_size = maker.order_size?;
+    if maker.reserved_margin > 0 {
+        return None
+    }
+    let payment = maker.price_history.compute_payment(maker.last_settled_epoch, maker.epoch, order_size)?.checked_neg()?;
+    let balance = maker.maker_margin? + maker.net_payments;
+    if payment <= balance {
+        return None
+    }
+    Some(Event::MarginCall { side: Side::Maker, channel: maker_channel(maker), epoch: maker.epoch, balance, payment })
+}
+
+// A subscriber's queue. Live streams are bounded and miss events when they fall behind.
+// Unbounded subscribers must take every event right away, as the webhook dispatcher does
+// by handing each to a bounded queue per endpoint.
+enum Listener {
+    Bounded(mpsc::Sender<Event>),
+    Unbounded(mpsc::UnboundedSender<Event>),
+}
+
+impl Listener {
+    // False once the subscriber hung up
+    fn send(&mut self, event: &Event) -> bool {
+        match self {
+            Listener::Bounded(sender) => match sender.try_send(event.clone()) {
+                Ok(()) => true,
+                Err(ref err) if err.is_closed() => false,
+                Err(err) => {
+                    warn!("Event listener is busy, {} not queued: {}", event.name(), err);
+                    true
+                }
+            },
+            Listener::Unbounded(sender) => sender.try_send(event.clone()).is_ok()
+        }
+    }
+}
+
+// Fans events out to every subscriber.
+// Clones share their subscribers, so the maker, taker and daemon can all hold the same bus.
+#[derive(Clone, Default)]
+pub struct EventBus {
+    listeners: Arc<Mutex<Vec<Listener>>>,
+}
+
+impl EventBus {
+    // Drops events while the subscriber is 64 behind
+    pub fn subscribe(&self) -> mpsc::Receiver<Event> {
+        let (sender, receiver) = mpsc::channel(64);
+        self.listeners.lock().expect("Event listeners are not poisoned").push(Listener::Bounded(sender));
+        receiver
+    }
+
+    // Queues every event until the subscriber takes it
+    pub fn subscribe_unbounded(&self) -> mpsc::UnboundedReceiver<Event> {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        self.listeners.lock().expect("Event listeners are not poisoned").push(Listener::Unbounded(sender));
+        receiver
+    }
+
+    pub fn publish(&self, event: Event) {
+        let mut listeners = self.listeners.lock().expect("Event listeners are not poisoned");
+        // Subscribers that hung up are dropped
+        let mut open = Vec::with_capacity(listeners.len());
+        for mut listener in listeners.drain(..) {
+            if listener.send(&event) {
+                open.push(listener);
+            }
+        }
+        *listeners = open;
+    }
+}
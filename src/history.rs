@@ -0,0 +1,54 @@
+use serde::{Serialize, Deserialize};
+use std::collections::BTreeMap;
+
+// Internal
+use crate::math;
+use crate::MarketData;
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub enum CatchUpMode {
+    EveryEpoch, // One payment per missed epoch
+    Netted, // A single payment covering every missed epoch
+}
+
+impl Default for CatchUpMode {
+    fn default() -> Self {
+        CatchUpMode::EveryEpoch
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct PriceHistory {
+    pub prices: BTreeMap<u64, MarketData>
+}
+
+impl PriceHistory {
+    pub fn record(&mut self, epoch: u64, market_data: MarketData) {
+        self.prices.insert(epoch, market_data);
+    }
+
+    pub fn get(&self, epoch: u64) -> Option<&MarketData> {
+        self.prices.get(&epoch)
+    }
+
+    pub fn latest_epoch(&self) -> Option<u64> {
+        self.prices.keys().next_back().cloned()
+    }
+
+    // Drops prices before the given epoch, which is kept as the reference price
+    pub fn prune_before(&mut self, epoch: u64) {
+        self.prices = self.prices.split_off(&epoch);
+    }
+
+    // Sum of the per epoch payments for (from_epoch, to_epoch], None if a price is missing or out of range
+    pub fn compute_payment(&self, from_epoch: u64, to_epoch: u64, position_size: i64) -> Option<i64> {
+        let mut payment = 0;
+        for epoch in (from_epoch + 1)..=to_epoch {
+            let prev_market_data = self.get(epoch - 1)?.clone();
+            let market_data = self.get(epoch)?.clone();
+            payment = math::compute_payment(market_data, prev_market_data, position_size)
+                .and_then(|epoch_payment| epoch_payment.checked_add(payment))?;
+        }
+        Some(payment)
+    }
+}
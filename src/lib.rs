@@ -2,10 +2,31 @@ pub mod message;
 pub mod taker;
 pub mod maker;
 pub mod math;
+pub mod metrics;
+pub mod admission;
+pub mod settlement;
+pub mod history;
+pub mod auth;
+pub mod config;
+pub mod tls;
+pub mod peer;
+pub mod transport;
+pub mod client;
+pub mod driver;
+pub mod events;
+pub mod state;
+pub mod scheduler;
+pub mod api;
+pub mod server;
+pub mod view;
+pub mod webhook;
+pub mod logging;
+pub mod audit;
+pub mod replay;
+pub mod clock;
 // pub mod price_feed;
 
 use serde::{Serialize, Deserialize};
-use std::fmt::Debug;
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct MarketPrice {
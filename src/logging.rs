@@ -0,0 +1,26 @@
+// Structured tracing output, as human readable lines or JSON lines
+use rand::Rng;
+use serde::{Serialize, Deserialize};
+use tracing_subscriber::{fmt, EnvFilter};
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct LogConfig {
+    pub json: bool,
+    pub filter: Option<String>, // Directives such as "rainboltd=debug", defaults to info
+}
+
+pub fn init(config: &LogConfig) {
+    let filter = EnvFilter::new(config.filter.as_ref().map_or("info", String::as_str));
+    let builder = fmt::Subscriber::builder().with_env_filter(filter);
+    let installed = if config.json {
+        tracing::subscriber::set_global_default(builder.json().finish())
+    } else {
+        tracing::subscriber::set_global_default(builder.finish())
+    };
+    installed.expect("Only one tracing subscriber is installed");
+}
+
+// Ties together the messages of one protocol round on both sides
+pub fn new_correlation_id() -> String {
+    hex::encode(rand::thread_rng().gen::<[u8; 8]>())
+}
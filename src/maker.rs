@@ -5,6 +5,7 @@ use bolt::{
         init_merchant,
         establish_merchant_issue_close_token,
         establish_merchant_issue_pay_token,
+        merchant_close,
         verify_payment_proof,
         verify_revoke_token
     },
@@ -14,32 +15,44 @@ use bolt::{
         ChannelToken
     }
 };
-use ff;
-use rand;
+use rand::{CryptoRng, RngCore};
 use pairing::bls12_381::Bls12;
 use serde::{Serialize, Deserialize};
-use std::time::Instant;
+use sha2::{Sha256, Digest};
+use std::collections::{HashMap, HashSet};
+use std::panic::{self, AssertUnwindSafe};
+use tracing::{debug, info, info_span, warn};
 
 // Internal
 use crate::message::{
     OpenChannelRequest,
     OpenChannelResponse,
+    ChannelEstablishedRequest,
+    SettlementRequest,
     PaymentRequest,
     PaymentResponse,
     GeneratePaymentTokenRequest,
     GeneratePaymentTokenResponse,
-    OpenMarketState
+    CloseChannelRequest,
+    CloseChannelResponse
 };
-use crate::math;
+use crate::admission::{AdmissionPolicy, OrderRejection};
+use crate::audit::AuditJournal;
+use crate::clock::{system_clock, SharedClock};
+use crate::events::{maker_channel, maker_margin_call, Event, EventBus};
+use crate::settlement::{PaymentRejection, Side};
+use crate::history::PriceHistory;
+use crate::logging::new_correlation_id;
+use crate::metrics;
 use crate::MarketData;
 
 macro_rules! measure_one_arg {
-    ($x: expr) => {
+    ($clock: expr, $x: expr) => {
         {
-            let s = Instant::now();
+            let s = $clock.now_millis();
             let res = $x;
-            let e = s.elapsed();
-            (res, e.as_millis())
+            let e = $clock.now_millis().saturating_sub(s);
+            (res, e as u128)
         };
     }
 }
@@ -55,21 +68,71 @@ pub struct MakerState {
     pub initial_margin: i64,
     pub order_size: Option<i64>,
     pub available_margin: i64,
+    pub collateral: Option<i64>, // Target collateral per channel, defaults to the order size
+    pub maker_margin: Option<i64>, // Collateral locked in the open channel
+    pub taker_margin: Option<i64>,
+    pub net_payments: i64, // Paid by the taker to the maker over the life of the channel
+    pub pending_payment: Option<i64>,
+    pub revoked: HashSet<String>, // Hashes of the revoke tokens already accepted
+    pub reserved_margin: i64, // Collateral held for a channel that is not yet established
+    pub policy: AdmissionPolicy,
+    pub exposure: HashMap<String, i64>, // Notional per customer public key
     pub market_data: Option<MarketData>,
-    pub prev_market_data: Option<MarketData>
+    pub prev_market_data: Option<MarketData>,
+    pub epoch: u64,
+    pub price_history: PriceHistory,
+    pub last_settled_epoch: u64,
+    pub pending_epoch: Option<u64>,
+    #[serde(skip)]
+    pub events: EventBus,
+    #[serde(skip)]
+    pub journal: AuditJournal,
+    #[serde(skip, default = "system_clock")]
+    pub clock: SharedClock
 }
 
 pub trait Maker {
-    fn init(initial_margin: i64) -> Self;
+    fn init<R: RngCore + CryptoRng>(rng: &mut R, clock: SharedClock, initial_margin: i64) -> Self;
     fn place_order(&mut self);
-    fn recv_open_channel_req(&mut self, req: OpenChannelRequest) -> OpenChannelResponse;
-    fn recv_payment_req(&mut self, req: PaymentRequest) -> PaymentResponse;
-    fn recv_generate_payment_token_req(&mut self, req: GeneratePaymentTokenRequest) -> GeneratePaymentTokenResponse;
+    fn quote_collateral(&self, order_size: i64) -> i64;
+    fn recv_open_channel_req<R: RngCore + CryptoRng>(&mut self, rng: &mut R, req: OpenChannelRequest) -> Result<OpenChannelResponse, OrderRejection>;
+    fn recv_channel_established(&mut self, req: ChannelEstablishedRequest) -> Result<(), OrderRejection>;
+    fn release_reservation(&mut self);
+    fn send_settlement_req(&self) -> Option<SettlementRequest>;
+    fn recv_payment_req<R: RngCore + CryptoRng>(&mut self, rng: &mut R, req: PaymentRequest) -> Result<PaymentResponse, PaymentRejection>;
+    fn recv_generate_payment_token_req(&mut self, req: GeneratePaymentTokenRequest) -> Result<GeneratePaymentTokenResponse, PaymentRejection>;
+    fn recv_close_channel_req(&mut self, req: CloseChannelRequest) -> Result<CloseChannelResponse, PaymentRejection>;
+}
+
+impl MakerState {
+    pub fn record_market_data(&mut self, epoch: u64, market_data: MarketData) {
+        self.prev_market_data = self.market_data.replace(market_data.clone());
+        self.price_history.record(epoch, market_data);
+        self.epoch = epoch;
+        // Without a price at the settled epoch the first recorded price becomes the reference
+        if self.price_history.get(self.last_settled_epoch).is_none() {
+            self.last_settled_epoch = epoch;
+        }
+        if let Some(margin_call) = maker_margin_call(self) {
+            self.events.publish(margin_call);
+        }
+    }
+
+    fn reject(&self, channel: String, rejection: OrderRejection) -> Result<OpenChannelResponse, OrderRejection> {
+        warn!(reason = rejection.name(), "Open Channel Request rejected: {}", rejection);
+        self.events.publish(Event::OrderRejected { side: Side::Maker, channel, reason: rejection.name().to_string() });
+        Err(rejection)
+    }
+
+    // Refuses a payment round without touching the channel, so the taker can retry it
+    fn refuse<T>(&self, rejection: PaymentRejection) -> Result<T, PaymentRejection> {
+        warn!(reason = rejection.name(), "Payment refused: {}", rejection);
+        Err(rejection)
+    }
 }
 
 impl Maker for MakerState {
-    fn init(initial_margin: i64) -> Self {
-        let rng = &mut rand::thread_rng();
+    fn init<R: RngCore + CryptoRng>(rng: &mut R, clock: SharedClock, initial_margin: i64) -> Self {
         let mut channel_state = ChannelState::<Bls12>::new(String::from("Channel A -> B"), false);
         let (channel_token, merchant_state, channel_state) = init_merchant(rng, &mut channel_state, "Merchant Bob");
         
@@ -83,8 +146,24 @@ impl Maker for MakerState {
             initial_margin,
             order_size: None,
             available_margin: initial_margin,
+            collateral: None,
+            maker_margin: None,
+            taker_margin: None,
+            net_payments: 0,
+            pending_payment: None,
+            revoked: HashSet::new(),
+            reserved_margin: 0,
+            policy: AdmissionPolicy::default(),
+            exposure: HashMap::new(),
             market_data: None,
             prev_market_data: None,
+            epoch: 0,
+            price_history: PriceHistory::default(),
+            last_settled_epoch: 0,
+            pending_epoch: None,
+            events: EventBus::default(),
+            journal: AuditJournal::default(),
+            clock,
         }
     }
 
@@ -92,39 +171,83 @@ impl Maker for MakerState {
         // TODO send channel_token, keys, etc. to Cosmos
     }
 
-    fn recv_open_channel_req(&mut self, req: OpenChannelRequest) -> OpenChannelResponse {
-        println!("Open Channel Request received!");
-        let rng = &mut rand::thread_rng();
+    fn quote_collateral(&self, order_size: i64) -> i64 {
+        // Collateral is the maker's choice, but can never exceed its free margin
+        std::cmp::min(self.collateral.unwrap_or(order_size), self.available_margin)
+    }
+
+    fn recv_open_channel_req<R: RngCore + CryptoRng>(&mut self, rng: &mut R, req: OpenChannelRequest) -> Result<OpenChannelResponse, OrderRejection> {
+        let identity = req.customer_public_key.to_string();
+        let span = info_span!("open_channel", side = "maker", channel = %identity, correlation_id = %req.correlation_id);
+        let _enter = span.enter();
+        info!("Open Channel Request received!");
+
+        // Only one channel can be pending at a time, and none while one is open
+        if self.reserved_margin > 0 {
+            return self.reject(identity, OrderRejection::ChannelPending)
+        }
+        if self.maker_margin.is_some() {
+            return self.reject(identity, OrderRejection::ChannelOpen)
+        }
+
+        // Admission checks against the maker's policy and free liquidity
+        let exposure = self.exposure.get(&identity).cloned().unwrap_or(0);
+        if let Err(rejection) = self.policy.check(&req, self.available_margin, exposure) {
+            return self.reject(identity, rejection)
+        }
+
+        // The taker must fund the channel with exactly the collateral we quoted, never less or negative
+        let quoted = self.quote_collateral(req.order_size);
+        if req.maker_margin <= 0 || req.maker_margin != quoted {
+            return self.reject(identity, OrderRejection::CollateralMismatch { maker_margin: req.maker_margin, quoted })
+        }
+
         let OpenChannelRequest {
             root_commitment,
             root_commitment_proof,
             customer_public_key,
             margin,
-            order_size
+            maker_margin,
+            order_size,
+            correlation_id: _
         } = req;
 
-        // Record order size
-        self.order_size = Some(order_size);
-
-        // Save customer public key and Generate channel id
-        self.channel_token.set_customer_pk(&customer_public_key);
-        self.channel_id = Some(self.channel_token.compute_channel_id());
-
-        // receive closing token   
-        let close_token = match establish_merchant_issue_close_token(
+        // The commitment to the opening balances must verify before anything is reserved.
+        // libbolt unwraps some malformed proofs instead of returning an error.
+        let mut channel_token = self.channel_token.clone();
+        channel_token.set_customer_pk(&customer_public_key);
+        let channel_id = channel_token.compute_channel_id();
+        let issued = panic::catch_unwind(AssertUnwindSafe(|| establish_merchant_issue_close_token(
             rng, 
             &self.channel_state, 
             &root_commitment, 
             &root_commitment_proof, 
-            &self.channel_id.expect("id was set earlier"), 
+            &channel_id, 
             margin, 
-            order_size, 
+            maker_margin, 
             &self.merchant_state
-        ) {
-            Ok(token) => token.expect("valid close_token is empty"),
-            Err(err) => panic!("Failed - bidirectional::establish_merchant_issue_close_token(): {}", err)
+        )));
+        let close_token = match issued {
+            Ok(Ok(Some(token))) => token,
+            Ok(Ok(None)) => return self.reject(identity, OrderRejection::InvalidCommitment { error: "no close token issued".to_string() }),
+            Ok(Err(err)) => return self.reject(identity, OrderRejection::InvalidCommitment { error: err.to_string() }),
+            Err(_) => return self.reject(identity, OrderRejection::InvalidCommitment { error: "malformed commitment proof".to_string() })
         };
 
+        // Record order size and reserve the collateral until the channel is established
+        self.order_size = Some(order_size);
+        self.last_settled_epoch = self.epoch;
+        self.maker_margin = Some(maker_margin);
+        self.taker_margin = Some(margin);
+        self.net_payments = 0;
+        self.available_margin -= maker_margin;
+        self.reserved_margin += maker_margin;
+        *self.exposure.entry(identity).or_insert(0) += order_size;
+
+        // Save customer public key and channel id
+        self.channel_token = channel_token;
+        self.channel_id = Some(channel_id);
+
         // receive payment token for pay protocol
         let pay_token = establish_merchant_issue_pay_token(
             rng, 
@@ -134,58 +257,234 @@ impl Maker for MakerState {
         );
 
         // TODO send pay_token and close_token to client
-        OpenChannelResponse {
+        Ok(OpenChannelResponse {
             close_token,
-            pay_token
+            pay_token,
+            maker_margin
+        })
+    }
+
+    fn recv_channel_established(&mut self, req: ChannelEstablishedRequest) -> Result<(), OrderRejection> {
+        let ChannelEstablishedRequest {
+            customer_public_key,
+            correlation_id
+        } = req;
+        let span = info_span!("open_channel", side = "maker", channel = %customer_public_key, correlation_id = %correlation_id);
+        let _enter = span.enter();
+
+        // Only the customer of the pending channel establishes it, and only once
+        let maker_margin = match self.maker_margin {
+            Some(maker_margin) if self.reserved_margin > 0 && self.channel_token.pk_c == Some(customer_public_key) => maker_margin,
+            _ => {
+                let rejection = OrderRejection::UnknownChannel;
+                warn!(reason = rejection.name(), "Channel Established Request rejected: {}", rejection);
+                return Err(rejection)
+            }
+        };
+
+        // Reserved collateral is now locked in the channel
+        self.reserved_margin -= maker_margin;
+        info!(maker_margin, "Channel established! Maker collateral locked");
+        self.events.publish(Event::ChannelEstablished { side: Side::Maker, channel: maker_channel(self) });
+        Ok(())
+    }
+
+    fn release_reservation(&mut self) {
+        if self.reserved_margin == 0 {
+            return
+        }
+        // The pending order was never established, free its liquidity and exposure
+        self.available_margin += self.reserved_margin;
+        self.reserved_margin = 0;
+        self.maker_margin = None;
+        self.taker_margin = None;
+        if let (Some(pk), Some(order_size)) = (self.channel_token.pk_c, self.order_size.take()) {
+            self.exposure.entry(pk.to_string()).and_modify(|exposure| *exposure -= order_size);
         }
+        info!("Released pending order reservation");
     }
 
-    fn recv_payment_req(&mut self, req: PaymentRequest) -> PaymentResponse {
-        let rng = &mut rand::thread_rng();
+    fn send_settlement_req(&self) -> Option<SettlementRequest> {
+        // Nothing to settle until a channel is open and a new epoch has closed
+        let position_size = self.order_size?;
+        if self.reserved_margin > 0 || self.last_settled_epoch >= self.epoch {
+            return None
+        }
+        let from_epoch = self.last_settled_epoch;
+        let to_epoch = self.epoch;
+        let amount = self.price_history.compute_payment(from_epoch, to_epoch, position_size)?;
+        let correlation_id = new_correlation_id();
+        info!(channel = %maker_channel(self), from_epoch, to_epoch, amount, correlation_id = %correlation_id, "Settlement Request sent!");
+        Some(SettlementRequest {
+            from_epoch,
+            to_epoch,
+            amount,
+            correlation_id
+        })
+    }
+
+    fn recv_payment_req<R: RngCore + CryptoRng>(&mut self, rng: &mut R, req: PaymentRequest) -> Result<PaymentResponse, PaymentRejection> {
         let PaymentRequest {
-            payment_proof
+            payment_proof,
+            from_epoch,
+            to_epoch,
+            correlation_id
         } = req;
-        
+        let span = info_span!("settlement", side = "maker", channel = %maker_channel(self), from_epoch, to_epoch, correlation_id = %correlation_id);
+        let _enter = span.enter();
+
+        // Payment must cover the epochs following the last settlement, against our own price history
+        let position_size = match self.order_size {
+            Some(position_size) if self.reserved_margin == 0 => position_size,
+            _ => return self.refuse(PaymentRejection::NoChannel)
+        };
+        if from_epoch != self.last_settled_epoch || to_epoch <= from_epoch || to_epoch > self.epoch {
+            return self.refuse(PaymentRejection::UnexpectedEpochs { from_epoch, to_epoch, last_settled_epoch: self.last_settled_epoch, epoch: self.epoch })
+        }
+
         // compute payment
-        let market_data = self.market_data.clone().expect("must have market data");
-        let prev_market_data = self.prev_market_data.clone().expect("must have market data");
-        let position_size = self.order_size.clone().expect("Order exists if payment received");
-        let payment = math::compute_payment(market_data, prev_market_data, position_size);
+        let payment = match self.price_history.compute_payment(from_epoch, to_epoch, position_size) {
+            Some(payment) => payment,
+            None => return self.refuse(PaymentRejection::MissingMarketData { from_epoch, to_epoch })
+        };
         // Verify amount
         if payment != payment_proof.amount { // TODO add some tolerance specified in the contract, e.g. a few cents of difference, can average values or dispute
-            panic!("Payment expected {} received {}", payment, payment_proof.amount)
+            self.events.publish(Event::PaymentDisputed {
+                side: Side::Maker,
+                channel: maker_channel(self),
+                to_epoch,
+                expected: payment,
+                received: payment_proof.amount
+            });
+            return self.refuse(PaymentRejection::WrongAmount { expected: payment, received: payment_proof.amount })
         }
 
-        let (close_token, verify_time) = measure_one_arg!(
-            verify_payment_proof(
+        // libbolt unwraps the result of a proof that does not verify, before it touches the merchant state
+        let (verified, verify_time) = measure_one_arg!(
+            self.clock,
+            panic::catch_unwind(AssertUnwindSafe(|| verify_payment_proof(
                 rng, 
                 &self.channel_state, 
                 &payment_proof, 
                 &mut self.merchant_state
-            )
+            )))
         );
-        println!(">> Time to verify payment proof: {} ms", verify_time);
+        debug!(step = "verify_payment_proof", ms = verify_time as u64, "Verified payment proof");
+        metrics::observe_step("verify_payment_proof", verify_time);
+        let close_token = match verified {
+            Ok(close_token) => close_token,
+            Err(_) => return self.refuse(PaymentRejection::InvalidProof)
+        };
+        self.pending_epoch = Some(to_epoch);
+        self.pending_payment = Some(payment);
+        info!(amount = payment, "Payment proof verified!");
+        self.events.publish(Event::PaymentProofVerified { side: Side::Maker, channel: maker_channel(self), to_epoch, amount: payment });
         // -------- Send new_close_token to customer -------
-        PaymentResponse {
+        Ok(PaymentResponse {
             close_token
-        }
+        })
     }
 
-    fn recv_generate_payment_token_req(&mut self, req: GeneratePaymentTokenRequest) -> GeneratePaymentTokenResponse {
+    fn recv_generate_payment_token_req(&mut self, req: GeneratePaymentTokenRequest) -> Result<GeneratePaymentTokenResponse, PaymentRejection> {
         // Recv new revoke token 
         let GeneratePaymentTokenRequest {
-            revoke_token
+            revoke_token,
+            correlation_id
         } = req;
+        let span = info_span!("settlement", side = "maker", channel = %maker_channel(self), to_epoch = ?self.pending_epoch, correlation_id = %correlation_id);
+        let _enter = span.enter();
+
+        // An accepted revoke token never settles another payment, nor re-issues its pay token
+        let revoke_token_hash = hex::encode(Sha256::digest(&serde_json::to_vec(&revoke_token).expect("Revoke token serializes to JSON")));
+        if self.revoked.contains(&revoke_token_hash) {
+            return self.refuse(PaymentRejection::RevokeTokenReused)
+        }
 
         // Create new pay token and update state
-        let new_pay_token_result = verify_revoke_token(
-            &revoke_token, 
-            &mut self.merchant_state
+        let (new_pay_token_result, revoke_time) = measure_one_arg!(
+            self.clock,
+            panic::catch_unwind(AssertUnwindSafe(|| verify_revoke_token(
+                &revoke_token, 
+                &mut self.merchant_state
+            )))
         );
-        let payment_token = handle_bolt_result!(new_pay_token_result).expect("Payment token is Some()");
+        debug!(step = "verify_revoke_token", ms = revoke_time as u64, "Verified revoke token");
+        metrics::observe_step("verify_revoke_token", revoke_time);
+        // As with payment proofs, a malformed token can panic in libbolt rather than fail to verify
+        let payment_token = match new_pay_token_result.map(|result| handle_bolt_result!(result)) {
+            Ok(Some(payment_token)) => payment_token,
+            _ => return self.refuse(PaymentRejection::InvalidRevokeToken)
+        };
+        self.revoked.insert(revoke_token_hash);
+        if let Some(epoch) = self.pending_epoch.take() {
+            self.last_settled_epoch = epoch;
+            self.price_history.prune_before(epoch);
+        }
+        self.net_payments += self.pending_payment.take().unwrap_or(0);
+        let channel = maker_channel(self);
+        self.events.publish(Event::RevokeTokenAccepted { side: Side::Maker, channel: channel.clone(), epoch: self.last_settled_epoch });
+        info!(last_settled_epoch = self.last_settled_epoch, net_payments = self.net_payments, "Revoke token accepted, pay token issued!");
+        self.events.publish(Event::PayTokenIssued { side: Side::Maker, channel, epoch: self.last_settled_epoch });
         // --------- Send new pay token to customer --------
-        GeneratePaymentTokenResponse {
+        Ok(GeneratePaymentTokenResponse {
             payment_token
+        })
+    }
+
+    fn recv_close_channel_req(&mut self, req: CloseChannelRequest) -> Result<CloseChannelResponse, PaymentRejection> {
+        let CloseChannelRequest {
+            customer_close,
+            correlation_id
+        } = req;
+        let span = info_span!("close_channel", side = "maker", channel = %maker_channel(self), correlation_id = %correlation_id);
+        let _enter = span.enter();
+        info!("Close Channel Request received!");
+
+        // Only an established channel closes, and only once
+        let (maker_margin, taker_margin) = match (self.maker_margin, self.taker_margin) {
+            (Some(maker_margin), Some(taker_margin)) if self.reserved_margin == 0 => (maker_margin, taker_margin),
+            _ => return self.refuse(PaymentRejection::NoChannel)
+        };
+        if self.pending_epoch.is_some() {
+            return self.refuse(PaymentRejection::PaymentInFlight)
+        }
+        let maker_balance = maker_margin + self.net_payments;
+        let taker_balance = taker_margin - self.net_payments;
+
+        // Signed by the customer on a wallet we issued a close token for, and not one it revoked since.
+        // libbolt answers a revoked wallet with our dispute message and the latest one with an error.
+        // Our copy of the channel state never ran the customer's establish step.
+        let mut channel_state = self.channel_state.clone();
+        channel_state.channel_established = true;
+        match merchant_close(&channel_state, &self.channel_token, &customer_close, &self.merchant_state) {
+            Ok(Some(_)) => return self.refuse(PaymentRejection::RevokedClose),
+            Err(ref err) if err.contains("Valid close") => (),
+            Err(error) => return self.refuse(PaymentRejection::InvalidClose { error }),
+            Ok(None) => return self.refuse(PaymentRejection::InvalidClose { error: "no close message".to_string() })
+        }
+        if customer_close.message.bc != taker_balance || customer_close.message.bm != maker_balance {
+            return self.refuse(PaymentRejection::WrongCloseBalances {
+                taker_balance: customer_close.message.bc,
+                maker_balance: customer_close.message.bm,
+                expected_taker_balance: taker_balance,
+                expected_maker_balance: maker_balance
+            })
+        }
+        // TODO broadcast the customer close message to Cosmos
+
+        // Collateral and PnL return to the free margin
+        self.maker_margin = None;
+        self.taker_margin = None;
+        self.available_margin += maker_balance;
+        if let (Some(pk), Some(order_size)) = (self.channel_token.pk_c, self.order_size.take()) {
+            self.exposure.entry(pk.to_string()).and_modify(|exposure| *exposure -= order_size);
         }
+        self.net_payments = 0;
+        info!(maker_balance, taker_balance, "Channel closed!");
+        self.events.publish(Event::ChannelClosed { side: Side::Maker, channel: maker_channel(self), taker_balance, maker_balance });
+        Ok(CloseChannelResponse {
+            maker_balance,
+            taker_balance
+        })
     }
 }
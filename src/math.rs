@@ -1,12 +1,21 @@
-use crate::message::OpenMarketState;
 use crate::MarketData;
+use tracing::debug;
 
 
-pub fn compute_payment(market_data: MarketData, prev_market_data: MarketData, position_size: i64) -> i64 {
-    let decimal_precision = 100000000i64;
-    let change_in_price = market_data.bitcoin.usd - prev_market_data.bitcoin.usd; // change in USD
-    let percent_change_in_price = change_in_price * decimal_precision / prev_market_data.bitcoin.usd ;
-    let profit_or_loss = position_size * percent_change_in_price / decimal_precision;
-    println!("PAyment is {}", profit_or_loss);
-    profit_or_loss
-}
\ No newline at end of file
+// None if the previous price is not positive or the payment does not fit in an i64
+pub fn compute_payment(market_data: MarketData, prev_market_data: MarketData, position_size: i64) -> Option<i64> {
+    let decimal_precision = 100000000i128;
+    if prev_market_data.bitcoin.usd <= 0 {
+        return None
+    }
+    // i128 so that the change in price cannot overflow, only the position size times it can
+    let change_in_price = market_data.bitcoin.usd as i128 - prev_market_data.bitcoin.usd as i128; // change in USD
+    let percent_change_in_price = change_in_price * decimal_precision / prev_market_data.bitcoin.usd as i128;
+    let profit_or_loss = (position_size as i128).checked_mul(percent_change_in_price)? / decimal_precision;
+    // Symmetric bound, so the other side's payment can always be negated
+    if profit_or_loss.abs() > i64::max_value() as i128 {
+        return None
+    }
+    debug!("Payment is {}", profit_or_loss);
+    Some(profit_or_loss as i64)
+}
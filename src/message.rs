@@ -1,13 +1,13 @@
 use bolt::{
     ped92::{Commitment, CommitmentProof},
     cl::Signature,
+    channels::ChannelcloseC,
     bidirectional::{
         Payment,
         RevokeToken
     },
 };
 use serde::{Serialize, Deserialize};
-use secp256k1;
 use pairing::bls12_381::Bls12;
 
 #[derive(Serialize, Deserialize)]
@@ -16,18 +16,43 @@ pub struct OpenChannelRequest {
     pub root_commitment: Commitment<Bls12>,
     pub root_commitment_proof: CommitmentProof<Bls12>,
     pub margin: i64,
+    pub maker_margin: i64,
     pub order_size: i64,
+    #[serde(default)]
+    pub correlation_id: String
 }
 
 #[derive(Serialize, Deserialize)]
 pub struct OpenChannelResponse {
     pub close_token: Signature<Bls12>,
-    pub pay_token: Signature<Bls12>
+    pub pay_token: Signature<Bls12>,
+    pub maker_margin: i64
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ChannelEstablishedRequest {
+    pub customer_public_key: secp256k1::PublicKey,
+    #[serde(default)]
+    pub correlation_id: String
+}
+
+// Asks the taker to settle up to an epoch, since only the customer can generate payment proofs
+#[derive(Serialize, Deserialize, Clone)]
+pub struct SettlementRequest {
+    pub from_epoch: u64,
+    pub to_epoch: u64,
+    pub amount: i64,
+    #[serde(default)]
+    pub correlation_id: String
 }
 
 #[derive(Serialize, Deserialize)]
 pub struct PaymentRequest {
-    pub payment_proof: Payment<Bls12>
+    pub payment_proof: Payment<Bls12>,
+    pub from_epoch: u64,
+    pub to_epoch: u64,
+    #[serde(default)]
+    pub correlation_id: String
 }
 
 #[derive(Serialize, Deserialize)]
@@ -37,7 +62,9 @@ pub struct PaymentResponse {
 
 #[derive(Serialize, Deserialize)]
 pub struct GeneratePaymentTokenRequest {
-    pub revoke_token: RevokeToken
+    pub revoke_token: RevokeToken,
+    #[serde(default)]
+    pub correlation_id: String
 }
 
 #[derive(Serialize, Deserialize)]
@@ -45,6 +72,19 @@ pub struct GeneratePaymentTokenResponse {
     pub payment_token: Signature<Bls12>
 } 
 
+#[derive(Serialize, Deserialize)]
+pub struct CloseChannelRequest {
+    pub customer_close: ChannelcloseC<Bls12>,
+    #[serde(default)]
+    pub correlation_id: String
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct CloseChannelResponse {
+    pub maker_balance: i64,
+    pub taker_balance: i64
+}
+
 #[derive(Serialize, Deserialize)]
 pub struct OpenMarketState {
     pub last_index_price: f64,
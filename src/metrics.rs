@@ -0,0 +1,146 @@
+// Prometheus metrics: protocol step timings, counters fed by the event bus and gauges read from the channels at scrape time
+use lazy_static::lazy_static;
+use prometheus::{
+    register_histogram_vec,
+    register_int_counter,
+    register_int_counter_vec,
+    register_int_gauge,
+    register_int_gauge_vec,
+    Encoder,
+    HistogramVec,
+    IntCounter,
+    IntCounterVec,
+    IntGauge,
+    IntGaugeVec,
+    TextEncoder,
+    TEXT_FORMAT,
+};
+use tokio::sync::mpsc;
+use warp::{self, path, Filter, Reply, reject::Rejection};
+
+// Internal
+use crate::events::Event;
+use crate::settlement::Side;
+use crate::state::{with_state, DaemonState};
+use crate::view::{channel_views, ChannelPhase};
+
+lazy_static! {
+    static ref PROTOCOL_STEP_MS: HistogramVec = register_histogram_vec!(
+        "rainbolt_protocol_step_duration_ms",
+        "Time spent generating and verifying proofs and tokens",
+        &["step"],
+        vec![1.0, 5.0, 10.0, 25.0, 50.0, 100.0, 250.0, 500.0, 1000.0, 2500.0, 5000.0]
+    ).expect("Protocol step histogram registers");
+    static ref SETTLEMENTS: IntCounterVec = register_int_counter_vec!(
+        "rainbolt_settlements_total",
+        "Payment rounds completed",
+        &["side"]
+    ).expect("Settlement counter registers");
+    static ref SETTLEMENT_FAILURES: IntCounterVec = register_int_counter_vec!(
+        "rainbolt_settlement_failures_total",
+        "Settlements that ran out of retries",
+        &["side"]
+    ).expect("Settlement failure counter registers");
+    static ref REJECTIONS: IntCounterVec = register_int_counter_vec!(
+        "rainbolt_order_rejections_total",
+        "Open channel requests rejected by the maker",
+        &["reason"]
+    ).expect("Rejection counter registers");
+    static ref DISPUTES: IntCounter = register_int_counter!(
+        "rainbolt_payment_disputes_total",
+        "Payment proofs that did not match the maker's own price history"
+    ).expect("Dispute counter registers");
+    static ref MARGIN_CALLS: IntCounterVec = register_int_counter_vec!(
+        "rainbolt_margin_calls_total",
+        "Epochs that left the paying side short of margin",
+        &["side"]
+    ).expect("Margin call counter registers");
+    static ref EPOCHS_DROPPED: IntCounter = register_int_counter!(
+        "rainbolt_epochs_dropped_total",
+        "Closed epochs a busy settlement scheduler was not told about"
+    ).expect("Dropped epoch counter registers");
+    static ref OPEN_CHANNELS: IntGauge = register_int_gauge!(
+        "rainbolt_open_channels",
+        "Channels opening, open or settling"
+    ).expect("Open channel gauge registers");
+    static ref TOTAL_MARGIN: IntGaugeVec = register_int_gauge_vec!(
+        "rainbolt_total_margin",
+        "Balance held in open channels",
+        &["side"]
+    ).expect("Margin gauge registers");
+    static ref NET_EXPOSURE: IntGaugeVec = register_int_gauge_vec!(
+        "rainbolt_net_exposure",
+        "Notional of the open positions, long as taker and short as maker",
+        &["instrument"]
+    ).expect("Exposure gauge registers");
+}
+
+fn side_label(side: &Side) -> &'static str {
+    match side {
+        Side::Taker => "taker",
+        Side::Maker => "maker"
+    }
+}
+
+pub fn observe_step(step: &str, millis: u128) {
+    PROTOCOL_STEP_MS.with_label_values(&[step]).observe(millis as f64);
+}
+
+pub fn epoch_dropped() {
+    EPOCHS_DROPPED.inc();
+}
+
+// Counts what the maker and taker report on the event bus, every event since it never falls behind
+pub async fn run_metrics(mut events: mpsc::UnboundedReceiver<Event>) {
+    while let Some(event) = events.recv().await {
+        match event {
+            Event::PayTokenIssued { side, .. } => SETTLEMENTS.with_label_values(&[side_label(&side)]).inc(),
+            Event::SettlementFailed { side, .. } => SETTLEMENT_FAILURES.with_label_values(&[side_label(&side)]).inc(),
+            Event::OrderRejected { reason, .. } => REJECTIONS.with_label_values(&[&reason]).inc(),
+            Event::PaymentDisputed { .. } => DISPUTES.inc(),
+            Event::MarginCall { side, .. } => MARGIN_CALLS.with_label_values(&[side_label(&side)]).inc(),
+            _ => ()
+        }
+    }
+}
+
+fn update_gauges(state: &DaemonState) {
+    let channels: Vec<_> = channel_views(&state.taker, &state.maker)
+        .into_iter()
+        .filter(|channel| channel.phase != ChannelPhase::Closed)
+        .collect();
+    OPEN_CHANNELS.set(channels.len() as i64);
+
+    let (mut taker_margin, mut maker_margin, mut exposure) = (0, 0, 0);
+    for channel in channels.iter() {
+        let order_size = channel.margin.order_size.unwrap_or(0);
+        match channel.side {
+            Side::Taker => {
+                taker_margin += channel.taker_balance;
+                exposure += order_size;
+            },
+            Side::Maker => {
+                maker_margin += channel.maker_balance;
+                exposure -= order_size;
+            }
+        }
+    }
+    TOTAL_MARGIN.with_label_values(&["taker"]).set(taker_margin);
+    TOTAL_MARGIN.with_label_values(&["maker"]).set(maker_margin);
+    // Payments follow the bitcoin price
+    NET_EXPOSURE.with_label_values(&["bitcoin"]).set(exposure);
+}
+
+// GET /metrics, unsigned so scrapers can read it. It shows the maker's margin and exposure,
+// so it is only served on its own metrics_listen_addr, which should stay on a private interface.
+pub fn metrics_routes(state: DaemonState) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
+    path!("metrics")
+        .and(warp::path::end())
+        .and(with_state(state))
+        .map(|state: DaemonState| {
+            update_gauges(&state);
+            let mut body = Vec::new();
+            TextEncoder::new().encode(&prometheus::gather(), &mut body).expect("Metrics encode as text");
+            warp::reply::with_header(body, "content-type", TEXT_FORMAT)
+        })
+}
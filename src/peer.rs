@@ -0,0 +1,208 @@
+// Noise style encrypted transport between maker and taker, keyed by secp256k1 identities.
+// The initiator knows the responder's static key (as in Noise XK):
+//   -> e              ck = H(ck || ECDH(e, rs))
+//   <- e              ck = H(ck || ECDH(e, re))
+//   -> enc(s)         ck = H(ck || ECDH(s, re))
+// after which each direction uses its own ChaCha20-Poly1305 key derived from ck.
+use chacha20poly1305::{
+    ChaCha20Poly1305,
+    aead::{Aead, NewAead, generic_array::GenericArray},
+};
+use secp256k1::{Secp256k1, PublicKey, SecretKey, ecdh::SharedSecret};
+use serde::{Serialize, Deserialize};
+use sha2::{Sha256, Digest};
+use std::io;
+use std::net::SocketAddr;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+// Internal
+use crate::admission::OrderRejection;
+use crate::settlement::PaymentRejection;
+use crate::message::{
+    OpenChannelRequest,
+    OpenChannelResponse,
+    ChannelEstablishedRequest,
+    SettlementRequest,
+    PaymentRequest,
+    PaymentResponse,
+    GeneratePaymentTokenRequest,
+    GeneratePaymentTokenResponse,
+    CloseChannelRequest,
+    CloseChannelResponse,
+};
+
+const PROTOCOL_NAME: &[u8] = b"rainbolt-noise-secp256k1-chachapoly-sha256";
+const PUBLIC_KEY_LEN: usize = 33;
+const TAG_LEN: usize = 16;
+pub const MAX_FRAME_LEN: usize = 1 << 24;
+
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "type", content = "body")]
+pub enum PeerMessage {
+    OpenChannelRequest(OpenChannelRequest),
+    OpenChannelResponse(OpenChannelResponse),
+    OrderRejection(OrderRejection),
+    ChannelEstablishedRequest(ChannelEstablishedRequest),
+    SettlementRequest(SettlementRequest),
+    PaymentRequest(PaymentRequest),
+    PaymentResponse(PaymentResponse),
+    PaymentRejection(PaymentRejection),
+    GeneratePaymentTokenRequest(GeneratePaymentTokenRequest),
+    GeneratePaymentTokenResponse(GeneratePaymentTokenResponse),
+    CloseChannelRequest(CloseChannelRequest),
+    CloseChannelResponse(CloseChannelResponse),
+    Ack,
+    Error(String),
+}
+
+fn mix_key(ck: &[u8; 32], input: &[u8]) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    hasher.input(&ck[..]);
+    hasher.input(input);
+    let mut out = [0u8; 32];
+    out.copy_from_slice(&hasher.result());
+    out
+}
+
+fn ecdh(public_key: &PublicKey, secret_key: &SecretKey) -> SharedSecret {
+    SharedSecret::new(public_key, secret_key)
+}
+
+fn nonce(counter: u64) -> [u8; 12] {
+    let mut nonce = [0u8; 12];
+    nonce[4..].copy_from_slice(&counter.to_le_bytes());
+    nonce
+}
+
+fn invalid_data<E: ToString>(err: E) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, err.to_string())
+}
+
+struct CipherState {
+    cipher: ChaCha20Poly1305,
+    counter: u64,
+}
+
+impl CipherState {
+    fn new(key: &[u8; 32]) -> Self {
+        CipherState {
+            cipher: ChaCha20Poly1305::new(GenericArray::clone_from_slice(&key[..])),
+            counter: 0,
+        }
+    }
+
+    fn encrypt(&mut self, plaintext: &[u8]) -> io::Result<Vec<u8>> {
+        let nonce = nonce(self.counter);
+        self.counter += 1;
+        self.cipher.encrypt(GenericArray::from_slice(&nonce), plaintext).map_err(|_| invalid_data("encryption failed"))
+    }
+
+    fn decrypt(&mut self, ciphertext: &[u8]) -> io::Result<Vec<u8>> {
+        let nonce = nonce(self.counter);
+        self.counter += 1;
+        self.cipher.decrypt(GenericArray::from_slice(&nonce), ciphertext).map_err(|_| invalid_data("decryption failed"))
+    }
+}
+
+pub struct PeerSession {
+    stream: TcpStream,
+    send: CipherState,
+    recv: CipherState,
+    pub remote_public_key: PublicKey,
+}
+
+impl PeerSession {
+    // Opens a session to a responder whose static key is already known
+    pub async fn connect(addr: SocketAddr, local_key: &SecretKey, remote_public_key: &PublicKey) -> io::Result<Self> {
+        let secp = Secp256k1::new();
+        let mut stream = TcpStream::connect(&addr).await?;
+        let mut ck = mix_key(&[0u8; 32], PROTOCOL_NAME);
+        ck = mix_key(&ck, &remote_public_key.serialize());
+
+        // -> e, es
+        let ephemeral_key = SecretKey::new(&mut rand::thread_rng());
+        let ephemeral_public_key = PublicKey::from_secret_key(&secp, &ephemeral_key);
+        stream.write_all(&ephemeral_public_key.serialize()).await?;
+        ck = mix_key(&ck, &ecdh(remote_public_key, &ephemeral_key)[..]);
+
+        // <- e, ee
+        let mut remote_ephemeral = [0u8; PUBLIC_KEY_LEN];
+        stream.read_exact(&mut remote_ephemeral).await?;
+        let remote_ephemeral = PublicKey::from_slice(&remote_ephemeral).map_err(invalid_data)?;
+        ck = mix_key(&ck, &ecdh(&remote_ephemeral, &ephemeral_key)[..]);
+
+        // -> s, se
+        let local_public_key = PublicKey::from_secret_key(&secp, local_key);
+        let encrypted_static = CipherState::new(&ck).encrypt(&local_public_key.serialize())?;
+        stream.write_all(&encrypted_static).await?;
+        ck = mix_key(&ck, &ecdh(&remote_ephemeral, local_key)[..]);
+
+        Ok(PeerSession {
+            stream,
+            send: CipherState::new(&mix_key(&ck, b"initiator")),
+            recv: CipherState::new(&mix_key(&ck, b"responder")),
+            remote_public_key: *remote_public_key,
+        })
+    }
+
+    // Completes the handshake on an accepted connection, the caller decides whether to trust the remote key
+    pub async fn accept(mut stream: TcpStream, local_key: &SecretKey) -> io::Result<Self> {
+        let secp = Secp256k1::new();
+        let local_public_key = PublicKey::from_secret_key(&secp, local_key);
+        let mut ck = mix_key(&[0u8; 32], PROTOCOL_NAME);
+        ck = mix_key(&ck, &local_public_key.serialize());
+
+        // -> e, es
+        let mut remote_ephemeral = [0u8; PUBLIC_KEY_LEN];
+        stream.read_exact(&mut remote_ephemeral).await?;
+        let remote_ephemeral = PublicKey::from_slice(&remote_ephemeral).map_err(invalid_data)?;
+        ck = mix_key(&ck, &ecdh(&remote_ephemeral, local_key)[..]);
+
+        // <- e, ee
+        let ephemeral_key = SecretKey::new(&mut rand::thread_rng());
+        let ephemeral_public_key = PublicKey::from_secret_key(&secp, &ephemeral_key);
+        stream.write_all(&ephemeral_public_key.serialize()).await?;
+        ck = mix_key(&ck, &ecdh(&remote_ephemeral, &ephemeral_key)[..]);
+
+        // -> s, se
+        let mut encrypted_static = [0u8; PUBLIC_KEY_LEN + TAG_LEN];
+        stream.read_exact(&mut encrypted_static).await?;
+        let remote_public_key = CipherState::new(&ck).decrypt(&encrypted_static)?;
+        let remote_public_key = PublicKey::from_slice(&remote_public_key).map_err(invalid_data)?;
+        ck = mix_key(&ck, &ecdh(&remote_public_key, &ephemeral_key)[..]);
+
+        Ok(PeerSession {
+            stream,
+            send: CipherState::new(&mix_key(&ck, b"responder")),
+            recv: CipherState::new(&mix_key(&ck, b"initiator")),
+            remote_public_key,
+        })
+    }
+
+    // Frames are a big endian u32 length followed by the encrypted JSON message
+    pub async fn send(&mut self, msg: &PeerMessage) -> io::Result<()> {
+        let plaintext = serde_json::to_vec(msg).map_err(invalid_data)?;
+        let ciphertext = self.send.encrypt(&plaintext)?;
+        self.stream.write_all(&(ciphertext.len() as u32).to_be_bytes()).await?;
+        self.stream.write_all(&ciphertext).await
+    }
+
+    pub async fn recv(&mut self) -> io::Result<PeerMessage> {
+        let mut len = [0u8; 4];
+        self.stream.read_exact(&mut len).await?;
+        let len = u32::from_be_bytes(len) as usize;
+        if len > MAX_FRAME_LEN {
+            return Err(invalid_data(format!("frame of {} bytes is too large", len)))
+        }
+        let mut ciphertext = vec![0u8; len];
+        self.stream.read_exact(&mut ciphertext).await?;
+        let plaintext = self.recv.decrypt(&ciphertext)?;
+        serde_json::from_slice(&plaintext).map_err(invalid_data)
+    }
+
+    pub async fn request(&mut self, msg: &PeerMessage) -> io::Result<PeerMessage> {
+        self.send(msg).await?;
+        self.recv().await
+    }
+}
@@ -0,0 +1,340 @@
+// Rebuilds a channel offline from its audit transcript. A fresh maker and taker, keyed from a seeded RNG,
+// run the recorded protocol rounds against each other over the recorded prices. Proofs and tokens differ
+// from the recorded ones, so what is compared is what they commit to: margins, epochs, amounts and balances.
+use rand::{SeedableRng, rngs::StdRng};
+use serde::{Serialize, Deserialize};
+use std::any::Any;
+use std::fmt;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::Arc;
+
+// Internal
+use crate::admission::AdmissionPolicy;
+use crate::audit::{AuditEntry, MakerTerms, Recorded};
+use crate::clock::{ManualClock, SharedClock};
+use crate::maker::{Maker, MakerState};
+use crate::message::OpenChannelRequest;
+use crate::peer::PeerMessage;
+use crate::settlement::Side;
+use crate::taker::{Taker, TakerState};
+use crate::MarketData;
+
+// First point where the replay and the transcript disagree
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Divergence {
+    pub seq: u64, // Journal entry that could not be reproduced
+    pub message: String,
+    pub field: String,
+    pub recorded: String,
+    pub replayed: String,
+}
+
+impl fmt::Display for Divergence {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "entry {} ({}): {} recorded {} but replayed {}", self.seq, self.message, self.field, self.recorded, self.replayed)
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ReplayReport {
+    pub seed: u64,
+    pub side: Side,
+    pub steps: usize, // Recorded messages reproduced before stopping
+    pub divergence: Option<Divergence>,
+}
+
+fn message_name(msg: &PeerMessage) -> &'static str {
+    match msg {
+        PeerMessage::OpenChannelRequest(_) => "OpenChannelRequest",
+        PeerMessage::OpenChannelResponse(_) => "OpenChannelResponse",
+        PeerMessage::OrderRejection(_) => "OrderRejection",
+        PeerMessage::ChannelEstablishedRequest(_) => "ChannelEstablishedRequest",
+        PeerMessage::SettlementRequest(_) => "SettlementRequest",
+        PeerMessage::PaymentRequest(_) => "PaymentRequest",
+        PeerMessage::PaymentResponse(_) => "PaymentResponse",
+        PeerMessage::PaymentRejection(_) => "PaymentRejection",
+        PeerMessage::GeneratePaymentTokenRequest(_) => "GeneratePaymentTokenRequest",
+        PeerMessage::GeneratePaymentTokenResponse(_) => "GeneratePaymentTokenResponse",
+        PeerMessage::CloseChannelRequest(_) => "CloseChannelRequest",
+        PeerMessage::CloseChannelResponse(_) => "CloseChannelResponse",
+        PeerMessage::Ack => "Ack",
+        PeerMessage::Error(_) => "Error",
+    }
+}
+
+fn panic_message(payload: Box<dyn Any + Send>) -> String {
+    match payload.downcast::<String>() {
+        Ok(message) => *message,
+        Err(payload) => payload.downcast_ref::<&str>().map_or("panic".to_string(), |message| message.to_string())
+    }
+}
+
+// The replayed maker's answer to a request, kept to compare with the recorded response.
+// Responses are consumed by the taker, so only what can be compared is kept.
+#[derive(Debug, PartialEq)]
+enum Answer {
+    Opened { maker_margin: i64 },
+    Rejected(String),
+    Refused(String),
+    Ack,
+    PaymentResponse,
+    PayToken,
+    Closed { taker_balance: i64, maker_balance: i64 },
+}
+
+// field, recorded, replayed
+type Mismatch = (String, String, String);
+
+fn compare<T: PartialEq + fmt::Debug>(field: &str, recorded: T, replayed: T) -> Result<(), Mismatch> {
+    if recorded == replayed {
+        Ok(())
+    } else {
+        Err((field.to_string(), format!("{:?}", recorded), format!("{:?}", replayed)))
+    }
+}
+
+struct Replay {
+    rng: StdRng,
+    clock: SharedClock,
+    prices: Vec<(u64, MarketData)>,
+    fed_epoch: u64,
+    terms: Option<MakerTerms>, // Journaled ahead of the order they apply to
+    maker: Option<MakerState>,
+    taker: Option<TakerState>,
+    answer: Option<Answer>,
+}
+
+impl Replay {
+    // Closes every recorded epoch up to the one the message was recorded in, as the daemon did
+    fn feed_prices(&mut self, up_to: u64) {
+        for (epoch, market_data) in self.prices.iter().filter(|(epoch, _)| *epoch > self.fed_epoch && *epoch <= up_to) {
+            if let Some(maker) = self.maker.as_mut() {
+                maker.record_market_data(*epoch, market_data.clone());
+            }
+            if let Some(taker) = self.taker.as_mut() {
+                taker.record_market_data(*epoch, market_data.clone());
+            }
+            self.fed_epoch = *epoch;
+        }
+    }
+
+    fn sides(&mut self) -> (&mut MakerState, &mut TakerState) {
+        match (self.maker.as_mut(), self.taker.as_mut()) {
+            (Some(maker), Some(taker)) => (maker, taker),
+            _ => panic!("Channel was not opened earlier in the transcript")
+        }
+    }
+
+    fn open_channel(&mut self, recorded: OpenChannelRequest) -> Result<(), Mismatch> {
+        if self.maker.is_none() {
+            // The maker journals the margin and policy it quoted against. The taker only saw the quote,
+            // so its transcript replays against a maker that quotes exactly that.
+            let terms = self.terms.take().unwrap_or_else(|| MakerTerms {
+                available_margin: recorded.maker_margin,
+                collateral: Some(recorded.maker_margin),
+                exposure: 0,
+                policy: AdmissionPolicy::default(),
+            });
+            let mut maker = MakerState::init(&mut self.rng, self.clock.clone(), terms.available_margin);
+            maker.collateral = terms.collateral;
+            maker.policy = terms.policy;
+            if terms.exposure > 0 {
+                maker.exposure.insert(recorded.customer_public_key.to_string(), terms.exposure);
+            }
+            for (epoch, market_data) in self.prices.iter().filter(|(epoch, _)| *epoch <= self.fed_epoch) {
+                maker.record_market_data(*epoch, market_data.clone());
+            }
+            self.maker = Some(maker);
+        }
+        let maker = self.maker.as_mut().expect("maker was created above");
+        let mut taker = TakerState::init(
+            &mut self.rng,
+            self.clock.clone(),
+            recorded.margin,
+            maker.quote_collateral(recorded.order_size),
+            recorded.order_size,
+            maker.channel_state.clone(),
+            maker.channel_token.clone()
+        );
+        // Start from the same reference price as the maker
+        if let Some(ref market_data) = maker.market_data {
+            taker.record_market_data(maker.epoch, market_data.clone());
+        }
+        let req = taker.send_open_channel_req();
+        compare("margin", recorded.margin, req.margin)?;
+        compare("maker_margin", recorded.maker_margin, req.maker_margin)?;
+        compare("order_size", recorded.order_size, req.order_size)?;
+        self.answer = match maker.recv_open_channel_req(&mut self.rng, req) {
+            Ok(res) => {
+                let maker_margin = res.maker_margin;
+                if let Err(err) = taker.recv_open_channel_res(res) {
+                    return Err(("open_channel".to_string(), "established".to_string(), err.to_string()))
+                }
+                self.taker = Some(taker);
+                Some(Answer::Opened { maker_margin })
+            },
+            Err(rejection) => Some(Answer::Rejected(rejection.name().to_string()))
+        };
+        Ok(())
+    }
+
+    fn step(&mut self, recorded: PeerMessage) -> Result<(), Mismatch> {
+        match recorded {
+            PeerMessage::OpenChannelRequest(req) => self.open_channel(req),
+            PeerMessage::ChannelEstablishedRequest(_) => {
+                let (maker, taker) = self.sides();
+                self.answer = match maker.recv_channel_established(taker.send_channel_established_req()) {
+                    Ok(()) => Some(Answer::Ack),
+                    Err(rejection) => Some(Answer::Rejected(rejection.name().to_string()))
+                };
+                Ok(())
+            },
+            PeerMessage::SettlementRequest(recorded) => {
+                let (maker, taker) = self.sides();
+                let req = match maker.send_settlement_req() {
+                    Some(req) => req,
+                    None => return Err(("to_epoch".to_string(), recorded.to_epoch.to_string(), "nothing to settle".to_string()))
+                };
+                compare("from_epoch", recorded.from_epoch, req.from_epoch)?;
+                compare("to_epoch", recorded.to_epoch, req.to_epoch)?;
+                compare("amount", recorded.amount, req.amount)?;
+                if let Err(rejection) = taker.recv_settlement_req(req) {
+                    return Err(("settlement".to_string(), "accepted".to_string(), rejection.to_string()))
+                }
+                // The taker only acknowledges once the payment rounds are done
+                self.answer = None;
+                Ok(())
+            },
+            PeerMessage::PaymentRequest(recorded) => {
+                let Replay { ref mut rng, ref mut maker, ref mut taker, .. } = *self;
+                let (maker, taker) = match (maker.as_mut(), taker.as_mut()) {
+                    (Some(maker), Some(taker)) => (maker, taker),
+                    _ => panic!("Channel was not opened earlier in the transcript")
+                };
+                let req = match taker.send_payment_req(rng) {
+                    Some(req) => req,
+                    None => return Err(("to_epoch".to_string(), recorded.to_epoch.to_string(), "nothing to settle".to_string()))
+                };
+                compare("from_epoch", recorded.from_epoch, req.from_epoch)?;
+                compare("to_epoch", recorded.to_epoch, req.to_epoch)?;
+                compare("amount", recorded.payment_proof.amount, req.payment_proof.amount)?;
+                self.answer = match maker.recv_payment_req(rng, req) {
+                    Ok(res) => {
+                        if let Err(err) = taker.recv_payment_res(res) {
+                            return Err(("payment".to_string(), "revoked".to_string(), err.to_string()))
+                        }
+                        Some(Answer::PaymentResponse)
+                    },
+                    Err(rejection) => Some(Answer::Refused(rejection.name().to_string()))
+                };
+                Ok(())
+            },
+            PeerMessage::GeneratePaymentTokenRequest(_) => {
+                let (maker, taker) = self.sides();
+                self.answer = match maker.recv_generate_payment_token_req(taker.send_generate_payment_token_req()) {
+                    Ok(res) => {
+                        if let Err(err) = taker.recv_generate_payment_token_res(res) {
+                            return Err(("pay_token".to_string(), "verified".to_string(), err.to_string()))
+                        }
+                        Some(Answer::PayToken)
+                    },
+                    Err(rejection) => Some(Answer::Refused(rejection.name().to_string()))
+                };
+                Ok(())
+            },
+            PeerMessage::CloseChannelRequest(_) => {
+                let (maker, taker) = self.sides();
+                let req = match taker.send_close_channel_req() {
+                    Ok(req) => req,
+                    Err(err) => return Err(("close".to_string(), "sent".to_string(), err.to_string()))
+                };
+                self.answer = match maker.recv_close_channel_req(req) {
+                    Ok(res) => {
+                        let answer = Answer::Closed { taker_balance: res.taker_balance, maker_balance: res.maker_balance };
+                        if let Err(err) = taker.recv_close_channel_res(res) {
+                            return Err(("close".to_string(), "closed".to_string(), err.to_string()))
+                        }
+                        Some(answer)
+                    },
+                    Err(rejection) => Some(Answer::Refused(rejection.name().to_string()))
+                };
+                Ok(())
+            },
+            recorded => {
+                let recorded = match recorded {
+                    PeerMessage::OpenChannelResponse(res) => Answer::Opened { maker_margin: res.maker_margin },
+                    PeerMessage::OrderRejection(rejection) => Answer::Rejected(rejection.name().to_string()),
+                    PeerMessage::PaymentResponse(_) => Answer::PaymentResponse,
+                    PeerMessage::PaymentRejection(rejection) => Answer::Refused(rejection.name().to_string()),
+                    PeerMessage::GeneratePaymentTokenResponse(_) => Answer::PayToken,
+                    PeerMessage::CloseChannelResponse(res) => Answer::Closed { taker_balance: res.taker_balance, maker_balance: res.maker_balance },
+                    PeerMessage::Ack => Answer::Ack,
+                    PeerMessage::Error(err) => {
+                        let replayed = self.answer.take().map_or("no error".to_string(), |answer| format!("{:?}", answer));
+                        return Err(("outcome".to_string(), err, replayed))
+                    },
+                    _ => unreachable!("requests are replayed above")
+                };
+                match self.answer.take() {
+                    Some(replayed) => compare("response", recorded, replayed),
+                    // Settlement acknowledgements are not paired with a replayed answer
+                    None => Ok(())
+                }
+            }
+        }
+    }
+}
+
+// Replays the messages one side journaled for a channel, with every price in the transcript
+pub fn replay(entries: &[AuditEntry], side: Side, seed: u64) -> ReplayReport {
+    let prices = entries.iter().filter_map(|entry| match entry.record.recorded {
+        Recorded::Price { ref market_data } => Some((entry.record.epoch, market_data.clone())),
+        _ => None
+    }).collect();
+    let mut state = Replay {
+        rng: StdRng::seed_from_u64(seed),
+        clock: Arc::new(ManualClock::default()),
+        prices,
+        fed_epoch: 0,
+        terms: None,
+        maker: None,
+        taker: None,
+        answer: None,
+    };
+
+    let mut steps = 0;
+    for entry in entries {
+        let message = match entry.record.recorded {
+            Recorded::Message { side: ref recorded_side, ref message, .. } if *recorded_side == side => message,
+            Recorded::MakerTerms(ref terms) if side == Side::Maker => {
+                state.terms = Some(terms.clone());
+                continue
+            },
+            _ => continue
+        };
+        let diverged = |message: &str, (field, recorded, replayed): Mismatch| Divergence {
+            seq: entry.seq,
+            message: message.to_string(),
+            field,
+            recorded,
+            replayed
+        };
+        let msg: PeerMessage = match serde_json::from_value(message.clone()) {
+            Ok(msg) => msg,
+            Err(err) => {
+                let mismatch = ("message".to_string(), message.to_string(), err.to_string());
+                return ReplayReport { seed, side, steps, divergence: Some(diverged("unreadable", mismatch)) }
+            }
+        };
+        let name = message_name(&msg);
+        state.feed_prices(entry.record.epoch);
+        // The maker and taker panic on anything they cannot verify, which is a divergence too
+        let result = panic::catch_unwind(AssertUnwindSafe(|| state.step(msg)))
+            .unwrap_or_else(|payload| Err(("panic".to_string(), name.to_string(), panic_message(payload))));
+        if let Err(mismatch) = result {
+            return ReplayReport { seed, side, steps, divergence: Some(diverged(name, mismatch)) }
+        }
+        steps += 1;
+    }
+    ReplayReport { seed, side, steps, divergence: None }
+}
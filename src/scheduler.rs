@@ -0,0 +1,137 @@
+use tokio::sync::mpsc;
+use tracing::{debug, error, info, warn};
+
+// Internal
+use crate::audit::{record_taker, Direction};
+use crate::driver;
+use crate::events::{maker_channel, taker_channel, Event};
+use crate::maker::Maker;
+use crate::message::SettlementRequest;
+use crate::peer::PeerMessage;
+use crate::settlement::{initiator, Backoff, PaymentRejection, Side, SettlementStatus};
+use crate::state::DaemonState;
+
+// Settles every open channel after each new price epoch, retrying with backoff
+pub async fn run_settlement_scheduler(state: DaemonState, mut epochs: mpsc::Receiver<u64>, backoff: Backoff) {
+    while let Some(epoch) = epochs.recv().await {
+        // Catch up on every epoch missed since the last settlement, as long as the taker owes
+        loop {
+            let taker_owes = {
+                let maybe_taker = state.taker.lock().expect("Taker is not poisoned");
+                maybe_taker
+                    .as_ref()
+                    .and_then(|taker| taker.next_settlement())
+                    .map_or(false, |(_, _, payment)| initiator(payment) == Side::Taker)
+            };
+            if !taker_owes || !settle_with_backoff(&state, epoch, &backoff).await {
+                break
+            }
+        }
+    }
+}
+
+// Asks the taker to settle whenever the maker owes, since only the taker can generate payment proofs
+pub async fn run_maker_settlement_scheduler(state: DaemonState, mut epochs: mpsc::Receiver<u64>, backoff: Backoff) {
+    while let Some(epoch) = epochs.recv().await {
+        let settlement_req = {
+            let maybe_maker = state.maker.lock().expect("Maker is not poisoned");
+            maybe_maker.as_ref().and_then(|maker| maker.send_settlement_req())
+        };
+        match settlement_req {
+            Some(req) if initiator(req.amount) == Side::Maker => {
+                info!("Maker owes {} for epoch {}, requesting settlement", -req.amount, epoch);
+                request_settlement_with_backoff(&state, req, &backoff).await;
+            },
+            _ => ()
+        }
+    }
+}
+
+pub async fn request_settlement_with_backoff(state: &DaemonState, req: SettlementRequest, backoff: &Backoff) -> bool {
+    for attempt in 1..=backoff.max_attempts {
+        let error = match driver::request_settlement(&state.maker, req.clone(), &*state.transport).await {
+            Ok(()) => return true,
+            Err(err) => err
+        };
+        warn!("Settlement request failed (attempt {}): {}", attempt, error);
+        if attempt < backoff.max_attempts {
+            state.clock.sleep(backoff.delay(attempt)).await;
+        } else {
+            let channel = state.maker.lock().expect("Maker is not poisoned").as_ref().map(maker_channel).unwrap_or_default();
+            state.events.publish(Event::SettlementFailed { side: Side::Maker, channel, epoch: req.to_epoch, attempts: attempt, error: error.to_string() });
+        }
+    }
+    false
+}
+
+// Taker's answer to the maker's settlement request, once every payment round it asked for is done
+pub async fn answer_settlement_req(state: &DaemonState, req: SettlementRequest) -> PeerMessage {
+    let requested = {
+        let mut maybe_taker = state.taker.lock().expect("Taker is not poisoned");
+        match maybe_taker.as_mut() {
+            Some(taker) => {
+                record_taker(taker, Direction::Inbound, &PeerMessage::SettlementRequest(req.clone()));
+                taker.recv_settlement_req(req)
+            },
+            None => Err(PaymentRejection::NoChannel)
+        }
+    };
+    let res = match requested {
+        Ok(to_epoch) if settle_taker_until(state, to_epoch, &Backoff::default()).await => PeerMessage::Ack,
+        Ok(to_epoch) => PeerMessage::Error(format!("Settlement up to epoch {} failed", to_epoch)),
+        Err(rejection) => PeerMessage::PaymentRejection(rejection)
+    };
+    if let Some(taker) = state.taker.lock().expect("Taker is not poisoned").as_ref() {
+        record_taker(taker, Direction::Outbound, &res);
+    }
+    res
+}
+
+fn settled_until(state: &DaemonState, to_epoch: u64) -> bool {
+    let maybe_taker = state.taker.lock().expect("Taker is not poisoned");
+    maybe_taker.as_ref().map_or(true, |taker| taker.last_settled_epoch >= to_epoch)
+}
+
+// Runs payment rounds until the given epoch is settled
+pub async fn settle_taker_until(state: &DaemonState, to_epoch: u64, backoff: &Backoff) -> bool {
+    while !settled_until(state, to_epoch) {
+        // Another round may have settled it while this one waited for the lock
+        if !settle_with_backoff(state, to_epoch, backoff).await {
+            return settled_until(state, to_epoch)
+        }
+    }
+    true
+}
+
+// Whether a payment round ran and settled, false when it failed or there was nothing left to settle
+pub async fn settle_with_backoff(state: &DaemonState, epoch: u64, backoff: &Backoff) -> bool {
+    info!("Settling up to epoch {}", epoch);
+    // One payment round at a time, whoever started it, checked again once it is our turn
+    let _settling = state.settlement_lock.lock().await;
+    let can_settle = state.taker.lock().expect("Taker is not poisoned").as_ref().map_or(false, |taker| taker.can_settle());
+    if !can_settle {
+        debug!("Nothing left to settle up to epoch {}", epoch);
+        return false
+    }
+    for attempt in 1..=backoff.max_attempts {
+        state.set_settlement_status(SettlementStatus::Pending { epoch, attempt });
+        match driver::settle(&state.taker, &*state.transport).await {
+            Ok(taker) => {
+                state.set_settlement_status(SettlementStatus::Settled { epoch: taker.last_settled_epoch });
+                info!("Settled epoch {}!", taker.last_settled_epoch);
+                return true
+            },
+            Err(error) if attempt == backoff.max_attempts => {
+                error!("Settlement of epoch {} failed after {} attempts: {}", epoch, attempt, error);
+                state.set_settlement_status(SettlementStatus::Failed { epoch, attempts: attempt, error: error.to_string() });
+                let channel = state.taker.lock().expect("Taker is not poisoned").as_ref().map(taker_channel).unwrap_or_default();
+                state.events.publish(Event::SettlementFailed { side: Side::Taker, channel, epoch, attempts: attempt, error: error.to_string() });
+            },
+            Err(error) => {
+                warn!("Settlement of epoch {} failed (attempt {}): {}", epoch, attempt, error);
+                state.clock.sleep(backoff.delay(attempt)).await;
+            }
+        }
+    }
+    false
+}
@@ -0,0 +1,204 @@
+use reqwest::Client;
+use secp256k1::{Secp256k1, PublicKey, SecretKey};
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::Arc;
+use tokio::net::{TcpListener, TcpStream};
+use warp::{self, Filter};
+use tracing::{info, warn};
+
+// Internal
+use crate::{
+    api::{event_routes, handle_rejection, maker_routes, market_routes, query_routes, taker_routes},
+    audit::AuditJournal,
+    clock::{system_clock, SharedClock},
+    auth::{Credentials, Role},
+    config::Config,
+    driver,
+    metrics::{metrics_routes, run_metrics},
+    peer::{PeerMessage, PeerSession},
+    scheduler::{run_maker_settlement_scheduler, run_settlement_scheduler, answer_settlement_req},
+    settlement::Backoff,
+    state::DaemonState,
+    tls,
+    transport::{HttpTransport, PeerTransport, Transport},
+    webhook::run_webhooks,
+};
+
+// Builds the daemon from a config, or from injected state when embedding it
+pub struct Server {
+    config: Config,
+    addr: SocketAddr,
+    state: Option<DaemonState>,
+    backoff: Backoff,
+    clock: SharedClock,
+}
+
+impl Server {
+    pub fn new(config: Config) -> Self {
+        Server {
+            config,
+            addr: ([127, 0, 0, 1], 3030).into(),
+            state: None,
+            backoff: Backoff::default(),
+            clock: system_clock(),
+        }
+    }
+
+    pub fn addr<A: Into<SocketAddr>>(mut self, addr: A) -> Self {
+        self.addr = addr.into();
+        self
+    }
+
+    pub fn state(mut self, state: DaemonState) -> Self {
+        self.state = Some(state);
+        self
+    }
+
+    pub fn backoff(mut self, backoff: Backoff) -> Self {
+        backoff.validate();
+        self.backoff = backoff;
+        self
+    }
+
+    pub fn clock(mut self, clock: SharedClock) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    // Shared state from the config: node key, credentials and a transport to the counterparty
+    pub fn build_state(&self) -> DaemonState {
+        if let Some(ref state) = self.state {
+            return state.clone()
+        }
+        let node_key = match self.config.node_secret_key {
+            Some(ref key) => SecretKey::from_str(key).expect("node_secret_key is a valid hex secp256k1 key"),
+            None => SecretKey::new(&mut rand::thread_rng())
+        };
+        let transport: Arc<dyn Transport> = match (&self.config.peer_addr, &self.config.peer_public_key) {
+            (Some(addr), Some(public_key)) => Arc::new(PeerTransport::new(
+                addr.parse().expect("peer_addr is a socket address"),
+                node_key.clone(),
+                PublicKey::from_str(public_key).expect("peer_public_key is a valid hex secp256k1 key")
+            )),
+            (None, None) => {
+                let client: Client = tls::client(self.config.tls.as_ref(), &self.config.data_dir());
+                Arc::new(HttpTransport::new(client, self.config.peer_url(), node_key.clone()))
+            },
+            _ => panic!("peer_addr and peer_public_key are set together")
+        };
+        let node_public_key = PublicKey::from_secret_key(&Secp256k1::signing_only(), &node_key);
+        // Fails closed, an open daemon must be asked for
+        let credentials = match (&self.config.auth, self.config.disable_auth) {
+            (Some(auth), _) => Some(Credentials::from_config(auth, node_public_key, self.clock.clone())),
+            (None, true) => None,
+            (None, false) => panic!("No auth configured, set auth or disable_auth in the config")
+        };
+        let mut state = DaemonState::new(transport, node_key, credentials);
+        state.clock = self.clock.clone();
+        if let Some(ref path) = self.config.audit_journal {
+            state.journal = AuditJournal::open(PathBuf::from(path), self.clock.clone())
+                .unwrap_or_else(|err| panic!("Failed to open audit journal {}: {}", path, err));
+        }
+        state
+    }
+
+    pub async fn run(self) {
+        let state = self.build_state();
+        if state.credentials.is_none() {
+            warn!("Auth is disabled, every route is open");
+        }
+        if let Some(ref addr) = self.config.peer_listen_addr {
+            // Peers are only trusted from the traders in the auth config
+            if state.credentials.is_none() {
+                panic!("peer_listen_addr needs auth, with the counterparty among the traders");
+            }
+            let addr: SocketAddr = addr.parse().expect("peer_listen_addr is a socket address");
+            tokio::spawn(run_peer_server(state.clone(), addr));
+        }
+        if let Some(ref webhooks) = self.config.webhooks {
+            let dead_letter_path = webhooks.dead_letter_path(&self.config.data_dir());
+            tokio::spawn(run_webhooks(webhooks.clone(), dead_letter_path, state.events.subscribe_unbounded(), Client::new(), state.node_key.clone(), state.clock.clone()));
+        }
+        tokio::spawn(run_metrics(state.events.subscribe_unbounded()));
+        if let Some(ref addr) = self.config.metrics_listen_addr {
+            // Scrapers cannot sign requests, so the gauges of the book are only served on this address
+            let addr: SocketAddr = addr.parse().expect("metrics_listen_addr is a socket address");
+            info!("Metrics listening on {}", addr);
+            tokio::spawn(warp::serve(metrics_routes(state.clone())).run(addr));
+        }
+        tokio::spawn(run_settlement_scheduler(state.clone(), state.subscribe_epochs(), self.backoff.clone()));
+        tokio::spawn(run_maker_settlement_scheduler(state.clone(), state.subscribe_epochs(), self.backoff.clone()));
+
+        let routes = warp::post2()
+            .and(maker_routes(state.clone()).or(taker_routes(state.clone())).or(market_routes(state.clone())))
+            .or(warp::get2().and(query_routes(state.clone()).or(event_routes(state))))
+            .recover(handle_rejection);
+
+        let addr = self.addr;
+        match self.config.tls {
+            Some(ref tls_config) => {
+                let (cert_path, key_path) = tls_config.server_cert_and_key(&self.config.data_dir());
+                let server = warp::serve(routes)
+                    .tls()
+                    .cert_path(cert_path)
+                    .key_path(key_path);
+                match tls_config.client_ca_path {
+                    Some(ref client_ca_path) => server.client_auth_required_path(client_ca_path).run(addr).await,
+                    None => server.run(addr).await
+                }
+            },
+            None => warp::serve(routes).run(addr).await
+        }
+    }
+}
+
+// Protocol messages from the counterparty over the encrypted peer transport
+async fn handle_peer_message(state: &DaemonState, msg: PeerMessage) -> PeerMessage {
+    match msg {
+        PeerMessage::SettlementRequest(req) => answer_settlement_req(state, req).await,
+        msg => driver::handle_maker_slot_message(&state.maker, msg)
+    }
+}
+
+async fn run_peer_session(state: DaemonState, stream: TcpStream) {
+    let mut session = match PeerSession::accept(stream, &state.node_key).await {
+        Ok(session) => session,
+        Err(err) => return warn!("Peer handshake failed: {}", err)
+    };
+    let trusted = state.credentials.as_ref().as_ref().map_or(false, |credentials| {
+        credentials.allows(&session.remote_public_key, Role::Trader)
+    });
+    if !trusted {
+        return warn!("Peer {} is not a known trader", session.remote_public_key)
+    }
+    info!("Peer session opened with {}", session.remote_public_key);
+
+    loop {
+        let msg = match session.recv().await {
+            Ok(msg) => msg,
+            Err(err) => return info!("Peer session with {} closed: {}", session.remote_public_key, err)
+        };
+        let res = handle_peer_message(&state, msg).await;
+        if let Err(err) = session.send(&res).await {
+            return info!("Peer session with {} closed: {}", session.remote_public_key, err)
+        }
+    }
+}
+
+pub async fn run_peer_server(state: DaemonState, addr: SocketAddr) {
+    let listener = TcpListener::bind(&addr).await.expect("Peer transport failed to bind");
+    info!("Peer transport listening on {}", addr);
+    serve_peers(state, listener).await
+}
+
+// Answers peer sessions on a bound listener, each on its own task
+pub async fn serve_peers(state: DaemonState, mut listener: TcpListener) {
+    loop {
+        match listener.accept().await {
+            Ok((stream, _)) => { tokio::spawn(run_peer_session(state.clone(), stream)); },
+            Err(err) => warn!("Peer connection failed: {}", err)
+        }
+    }
+}
@@ -0,0 +1,129 @@
+use serde::{Serialize, Deserialize};
+use std::fmt;
+use std::time::Duration;
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(tag = "status")]
+pub enum SettlementStatus {
+    Idle,
+    Pending { epoch: u64, attempt: u32 },
+    Settled { epoch: u64 },
+    Failed { epoch: u64, attempts: u32, error: String },
+}
+
+impl Default for SettlementStatus {
+    fn default() -> Self {
+        SettlementStatus::Idle
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub enum Side {
+    Taker,
+    Maker,
+}
+
+// The side that owes money starts the settlement, payments are positive when the taker pays the maker
+pub fn initiator(payment: i64) -> Side {
+    if payment >= 0 {
+        Side::Taker
+    } else {
+        Side::Maker
+    }
+}
+
+// Why a payment round, or the close that settles them, was refused. The channel is left as it was before the request.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(tag = "reason")]
+pub enum PaymentRejection {
+    NoChannel,
+    UnexpectedEpochs { from_epoch: u64, to_epoch: u64, last_settled_epoch: u64, epoch: u64 },
+    MissingMarketData { from_epoch: u64, to_epoch: u64 },
+    WrongAmount { expected: i64, received: i64 },
+    InvalidProof,
+    RevokeTokenReused,
+    InvalidRevokeToken,
+    PaymentInFlight,
+    InvalidClose { error: String },
+    RevokedClose,
+    WrongCloseBalances { taker_balance: i64, maker_balance: i64, expected_taker_balance: i64, expected_maker_balance: i64 },
+}
+
+impl fmt::Display for PaymentRejection {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PaymentRejection::NoChannel =>
+                write!(f, "No channel is open"),
+            PaymentRejection::UnexpectedEpochs { from_epoch, to_epoch, last_settled_epoch, epoch } =>
+                write!(f, "Payment for epochs {} to {} but last settled epoch is {} and current epoch is {}", from_epoch + 1, to_epoch, last_settled_epoch, epoch),
+            PaymentRejection::MissingMarketData { from_epoch, to_epoch } =>
+                write!(f, "No market data for every epoch from {} to {}", from_epoch + 1, to_epoch),
+            PaymentRejection::WrongAmount { expected, received } =>
+                write!(f, "Payment expected {} received {}", expected, received),
+            PaymentRejection::InvalidProof =>
+                write!(f, "Payment proof does not verify against the channel"),
+            PaymentRejection::RevokeTokenReused =>
+                write!(f, "Revoke token was already used for an earlier payment"),
+            PaymentRejection::InvalidRevokeToken =>
+                write!(f, "Revoke token does not verify against the pending payment"),
+            PaymentRejection::PaymentInFlight =>
+                write!(f, "Cannot close a channel with a payment in flight"),
+            PaymentRejection::InvalidClose { error } =>
+                write!(f, "Close message does not verify: {}", error),
+            PaymentRejection::RevokedClose =>
+                write!(f, "Close message is for a state that was already revoked"),
+            PaymentRejection::WrongCloseBalances { taker_balance, maker_balance, expected_taker_balance, expected_maker_balance } =>
+                write!(f, "Close pays taker {} and maker {} but the latest state is {} and {}", taker_balance, maker_balance, expected_taker_balance, expected_maker_balance),
+        }
+    }
+}
+
+impl PaymentRejection {
+    // Stable name of the rejection, as in its serialized "reason"
+    pub fn name(&self) -> &'static str {
+        match self {
+            PaymentRejection::NoChannel => "NoChannel",
+            PaymentRejection::UnexpectedEpochs { .. } => "UnexpectedEpochs",
+            PaymentRejection::MissingMarketData { .. } => "MissingMarketData",
+            PaymentRejection::WrongAmount { .. } => "WrongAmount",
+            PaymentRejection::InvalidProof => "InvalidProof",
+            PaymentRejection::RevokeTokenReused => "RevokeTokenReused",
+            PaymentRejection::InvalidRevokeToken => "InvalidRevokeToken",
+            PaymentRejection::PaymentInFlight => "PaymentInFlight",
+            PaymentRejection::InvalidClose { .. } => "InvalidClose",
+            PaymentRejection::RevokedClose => "RevokedClose",
+            PaymentRejection::WrongCloseBalances { .. } => "WrongCloseBalances",
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Backoff {
+    pub initial_delay_ms: u64,
+    pub max_delay_ms: u64,
+    pub max_attempts: u32,
+}
+
+impl Default for Backoff {
+    fn default() -> Self {
+        Backoff {
+            initial_delay_ms: 500,
+            max_delay_ms: 30000,
+            max_attempts: 5,
+        }
+    }
+}
+
+impl Backoff {
+    // Every retry loop makes at least one attempt
+    pub fn validate(&self) {
+        assert!(self.max_attempts >= 1, "Backoff needs max_attempts of at least 1, got {}", self.max_attempts);
+    }
+
+    // Exponential delay before the given retry, attempts are counted from 1
+    pub fn delay(&self, attempt: u32) -> Duration {
+        let factor = 1u64.checked_shl(attempt.saturating_sub(1)).unwrap_or(u64::max_value());
+        let delay = self.initial_delay_ms.saturating_mul(factor);
+        Duration::from_millis(std::cmp::min(delay, self.max_delay_ms))
+    }
+}
@@ -0,0 +1,109 @@
+use secp256k1::{Secp256k1, PublicKey, SecretKey};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicU64, Ordering};
+use tokio::sync::mpsc;
+use warp::{self, Filter};
+use tracing::{debug, warn};
+
+// Internal
+use crate::audit::AuditJournal;
+use crate::auth::Credentials;
+use crate::clock::{system_clock, SharedClock};
+use crate::driver::{MakerSlot, TakerSlot};
+use crate::events::{Event, EventBus};
+use crate::metrics;
+use crate::settlement::SettlementStatus;
+use crate::transport::Transport;
+use crate::MarketData;
+
+// Shared state injected into the routes, schedulers and peer server
+#[derive(Clone)]
+pub struct DaemonState {
+    pub taker: TakerSlot,
+    pub maker: MakerSlot,
+    pub epoch: Arc<AtomicU64>,
+    pub credentials: Arc<Option<Credentials>>,
+    pub transport: Arc<dyn Transport>,
+    pub node_key: SecretKey,
+    pub settlement_lock: Arc<tokio::sync::Mutex<()>>,
+    pub events: EventBus,
+    pub journal: AuditJournal,
+    pub clock: SharedClock,
+    epoch_listeners: Arc<Mutex<Vec<mpsc::Sender<u64>>>>,
+}
+
+impl DaemonState {
+    pub fn new(transport: Arc<dyn Transport>, node_key: SecretKey, credentials: Option<Credentials>) -> Self {
+        DaemonState {
+            taker: Arc::new(Mutex::new(None)),
+            maker: Arc::new(Mutex::new(None)),
+            epoch: Arc::new(AtomicU64::new(0)),
+            credentials: Arc::new(credentials),
+            transport,
+            node_key,
+            settlement_lock: Arc::new(tokio::sync::Mutex::new(())),
+            events: EventBus::default(),
+            journal: AuditJournal::default(),
+            clock: system_clock(),
+            epoch_listeners: Arc::new(Mutex::new(Vec::new())),
+        }
+    }
+
+    pub fn node_public_key(&self) -> PublicKey {
+        PublicKey::from_secret_key(&Secp256k1::signing_only(), &self.node_key)
+    }
+
+    // Receives every closed epoch from now on
+    pub fn subscribe_epochs(&self) -> mpsc::Receiver<u64> {
+        let (sender, receiver) = mpsc::channel(16);
+        self.epoch_listeners.lock().expect("Epoch listeners are not poisoned").push(sender);
+        receiver
+    }
+
+    // Both sides record the tick under the same epoch so their price histories agree
+    pub fn record_market_data(&self, market_data: MarketData) -> u64 {
+        let epoch = self.epoch.fetch_add(1, Ordering::SeqCst) + 1;
+        if let Some(taker) = self.taker.lock().expect("Taker is not poisoned during market data feed").as_mut() {
+            taker.record_market_data(epoch, market_data.clone());
+            debug!("Updated Taker MarketData!");
+        }
+        if let Some(maker) = self.maker.lock().expect("Maker is not poisoned during market data feed").as_mut() {
+            maker.record_market_data(epoch, market_data.clone());
+            debug!("Updated Maker MarketData!");
+        }
+        self.journal.record_price(epoch, &market_data);
+        self.events.publish(Event::PriceEpochClosed { epoch, market_data });
+        self.close_epoch(epoch);
+        epoch
+    }
+
+    // A busy scheduler misses the epoch, but catches up on every unsettled one with the next
+    fn close_epoch(&self, epoch: u64) {
+        let mut listeners = self.epoch_listeners.lock().expect("Epoch listeners are not poisoned");
+        // Listeners whose receiver is gone are dropped
+        let mut open = Vec::with_capacity(listeners.len());
+        for mut listener in listeners.drain(..) {
+            match listener.try_send(epoch) {
+                Ok(()) => open.push(listener),
+                Err(ref err) if err.is_closed() => debug!("Epoch listener hung up"),
+                Err(err) => {
+                    warn!("Epoch listener is busy, epoch {} not queued: {}", epoch, err);
+                    metrics::epoch_dropped();
+                    open.push(listener);
+                }
+            }
+        }
+        *listeners = open;
+    }
+
+    pub fn set_settlement_status(&self, status: SettlementStatus) {
+        let mut maybe_taker = self.taker.lock().expect("Taker is not poisoned");
+        if let Some(taker) = maybe_taker.as_mut() {
+            taker.settlement = status;
+        }
+    }
+}
+
+pub fn with_state(state: DaemonState) -> impl Filter<Extract = (DaemonState,), Error = std::convert::Infallible> + Clone {
+    warp::any().map(move || state.clone())
+}
@@ -8,6 +8,7 @@ use bolt::{
         establish_customer_final,
         generate_payment_proof,
         generate_revoke_token,
+        customer_close,
     },
     channels::{
         ChannelState,
@@ -15,47 +16,56 @@ use bolt::{
         ChannelToken
     }
 };
-use ff;
-use rand;
+use rand::{CryptoRng, RngCore};
 use pairing::bls12_381::Bls12;
 use serde::{Serialize, Deserialize};
-use std::time::Instant;
-use reqwest::r#async::Client;
-use http::header::{HeaderValue, CONTENT_TYPE};
+use std::panic::{self, AssertUnwindSafe};
+use tracing::{debug, info, info_span, warn};
 // use futures::future::Future;
 
 // Internal
 use crate::message::{
     OpenChannelRequest,
     OpenChannelResponse,
+    ChannelEstablishedRequest,
+    SettlementRequest,
     PaymentRequest,
     PaymentResponse,
     GeneratePaymentTokenRequest,
     GeneratePaymentTokenResponse,
-    OpenMarketState
+    CloseChannelRequest,
+    CloseChannelResponse
 };
-use crate::math;
+use crate::admission::OrderRejection;
+use crate::audit::AuditJournal;
+use crate::clock::{system_clock, SharedClock};
+use crate::events::{taker_channel, taker_margin_call, Event, EventBus};
+use crate::settlement::{PaymentRejection, SettlementStatus, Side};
+use crate::history::{PriceHistory, CatchUpMode};
+use crate::logging::new_correlation_id;
+use crate::metrics;
+use crate::transport::TransportError;
 use crate::MarketData;
 
 
-// macro_rules! measure_one_arg {
-//     ($x: expr) => {
-//         {
-//             let s = Instant::now();
-//             let res = $x;
-//             let e = s.elapsed();
-//             (res, e.as_millis())
-//         };
-//     }
-// }
+macro_rules! measure_one_arg {
+    ($clock: expr, $x: expr) => {
+        {
+            let s = $clock.now_millis();
+            let res = $x;
+            let e = $clock.now_millis().saturating_sub(s);
+            (res, e as u128)
+        };
+    }
+}
 
 macro_rules! measure_two_arg {
-    ($x: expr) => {
+    ($clock: expr, $x: expr) => {
         {
-            let s = Instant::now();
+            let s = $clock.now_millis();
             let (res1, res2) = $x;
-            let e = s.elapsed();
-            (res1, res2, e.as_millis())
+            let e = $clock.now_millis().saturating_sub(s);
+            (res1, res2, e as u128)
         };
     }
 }
@@ -69,55 +79,105 @@ pub struct TakerState {
     pub root_commitment: Commitment<Bls12>,
     pub root_commitment_proof: CommitmentProof<Bls12>,
     pub initial_margin: i64,
+    pub maker_margin: i64,
     pub order_size: i64,
     pub available_margin: i64,
     pub new_customer_state: Option<CustomerState<Bls12>>,
     pub revoke_token: Option<RevokeToken>,
     pub market_data: Option<MarketData>,
-    pub prev_market_data: Option<MarketData>
-}
-
-impl warp::Reply for TakerState {
-    fn into_response(self) -> warp::reply::Response {
-        let body = serde_json::to_vec(&self).expect("TakerState failed to serialize");
-        let mut res = warp::reply::Response::new(body.into());
-        res
-            .headers_mut()
-            .insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
-        res
-    }
+    pub prev_market_data: Option<MarketData>,
+    pub established: bool,
+    pub closed: bool,
+    pub epoch: u64,
+    pub settlement: SettlementStatus,
+    pub price_history: PriceHistory,
+    pub catch_up: CatchUpMode,
+    pub last_settled_epoch: u64,
+    pub pending_epoch: Option<u64>,
+    pub correlation_id: String, // Round in progress, shared with the maker in every request
+    pub settlement_correlation_id: Option<String>, // Maker's settlement request, continued by the next payment round
+    #[serde(skip)]
+    pub events: EventBus,
+    #[serde(skip)]
+    pub journal: AuditJournal,
+    #[serde(skip, default = "system_clock")]
+    pub clock: SharedClock
 }
 
 pub trait Taker {
-    fn init(initial_margin: i64, order_size: i64, channel_state: ChannelState<Bls12>, channel_token: ChannelToken<Bls12>) -> Self;
+    fn init<R: RngCore + CryptoRng>(rng: &mut R, clock: SharedClock, initial_margin: i64, maker_margin: i64, order_size: i64, channel_state: ChannelState<Bls12>, channel_token: ChannelToken<Bls12>) -> Self;
     fn take_order(&mut self);
     fn send_open_channel_req(&self) -> OpenChannelRequest;
-    fn recv_open_channel_res(&mut self, res: OpenChannelResponse);
-    fn send_payment_req(&mut self) -> PaymentRequest;
-    fn recv_payment_res(&mut self, res: PaymentResponse);
+    fn recv_open_channel_res(&mut self, res: OpenChannelResponse) -> Result<(), TransportError>;
+    fn send_channel_established_req(&self) -> ChannelEstablishedRequest;
+    fn recv_settlement_req(&mut self, req: SettlementRequest) -> Result<u64, PaymentRejection>;
+    fn send_payment_req<R: RngCore + CryptoRng>(&mut self, rng: &mut R) -> Option<PaymentRequest>;
+    fn recv_payment_res(&mut self, res: PaymentResponse) -> Result<(), TransportError>;
     fn send_generate_payment_token_req(&mut self) -> GeneratePaymentTokenRequest;
-    fn recv_generate_payment_token_res(&mut self, res: GeneratePaymentTokenResponse);
+    fn recv_generate_payment_token_res(&mut self, res: GeneratePaymentTokenResponse) -> Result<(), TransportError>;
+    fn send_close_channel_req(&self) -> Result<CloseChannelRequest, TransportError>;
+    fn recv_close_channel_res(&mut self, res: CloseChannelResponse) -> Result<(), TransportError>;
 }   
 
+impl TakerState {
+    pub fn record_market_data(&mut self, epoch: u64, market_data: MarketData) {
+        self.prev_market_data = self.market_data.replace(market_data.clone());
+        self.price_history.record(epoch, market_data);
+        self.epoch = epoch;
+        // Without a price at the settled epoch the first recorded price becomes the reference
+        if self.price_history.get(self.last_settled_epoch).is_none() {
+            self.last_settled_epoch = epoch;
+        }
+        if let Some(margin_call) = taker_margin_call(self) {
+            self.events.publish(margin_call);
+        }
+    }
+
+    pub fn has_unsettled_epochs(&self) -> bool {
+        self.established && !self.closed && self.last_settled_epoch < self.epoch
+    }
+
+    // Epoch span and amount of the next payment round, if any epochs are unsettled
+    pub fn next_settlement(&self) -> Option<(u64, u64, i64)> {
+        if !self.has_unsettled_epochs() {
+            return None
+        }
+        let from_epoch = self.last_settled_epoch;
+        let to_epoch = match self.catch_up {
+            CatchUpMode::EveryEpoch => from_epoch + 1,
+            CatchUpMode::Netted => self.epoch
+        };
+        self.price_history
+            .compute_payment(from_epoch, to_epoch, self.order_size)
+            .map(|payment| (from_epoch, to_epoch, payment))
+    }
+
+    // A payment round can start, or resume from its revoke step
+    pub fn can_settle(&self) -> bool {
+        self.revoke_token.is_some() || self.next_settlement().is_some()
+    }
+}
+
 impl Taker for TakerState {
-    fn init(initial_margin: i64, order_size: i64, channel_state: ChannelState<Bls12>, mut channel_token: ChannelToken<Bls12>) -> Self {
-        let rng = &mut rand::thread_rng();
+    fn init<R: RngCore + CryptoRng>(rng: &mut R, clock: SharedClock, initial_margin: i64, maker_margin: i64, order_size: i64, channel_state: ChannelState<Bls12>, mut channel_token: ChannelToken<Bls12>) -> Self {
         let mut customer_state = init_customer(
             rng, 
             &mut channel_token, // Pub key of merchant, updated with Pub key of customer, Bls keys
             initial_margin, // initial balance of customer 
-            order_size, // initial balance of the merchant 
+            maker_margin, // initial balance of the merchant, chosen by the maker independently of order size
             "YouKnowNothing"
         );
 
         let (root_commitment, root_commitment_proof, est_time) = measure_two_arg!(
+            clock,
             establish_customer_generate_proof(
                 rng, 
                 &mut channel_token, 
                 &mut customer_state
             )
         );
-        println!(">> Time to generate proof for establish: {} ms", est_time);
+        debug!(step = "establish_proof", ms = est_time as u64, "Generated proof for establish");
+        metrics::observe_step("establish_proof", est_time);
         
         TakerState {
             channel_id: channel_token.compute_channel_id(),
@@ -128,11 +188,25 @@ impl Taker for TakerState {
             root_commitment,
             root_commitment_proof,
             initial_margin,
+            maker_margin,
             order_size,
             available_margin: initial_margin,
             revoke_token: None,
             market_data: None,
             prev_market_data: None,
+            established: false,
+            closed: false,
+            epoch: 0,
+            settlement: SettlementStatus::Idle,
+            price_history: PriceHistory::default(),
+            catch_up: CatchUpMode::default(),
+            last_settled_epoch: 0,
+            pending_epoch: None,
+            correlation_id: new_correlation_id(),
+            settlement_correlation_id: None,
+            events: EventBus::default(),
+            journal: AuditJournal::default(),
+            clock,
         }
     }
 
@@ -141,58 +215,125 @@ impl Taker for TakerState {
     }
 
     fn send_open_channel_req(&self) -> OpenChannelRequest {
+        let span = info_span!("open_channel", side = "taker", channel = %taker_channel(self), correlation_id = %self.correlation_id);
+        let _enter = span.enter();
         // send message to Merchant
         let req = OpenChannelRequest {
             customer_public_key: self.customer_state.pk_c,
             root_commitment: self.root_commitment.clone(),
             root_commitment_proof: self.root_commitment_proof.clone(),
             margin: self.initial_margin,
+            maker_margin: self.maker_margin,
             order_size: self.order_size,
+            correlation_id: self.correlation_id.clone(),
         };
 
         // TODO non blocking send
-        println!("Open Channel Request sent!");
+        info!(margin = self.initial_margin, maker_margin = self.maker_margin, order_size = self.order_size, "Open Channel Request sent!");
         req
     }
 
-    fn recv_open_channel_res(&mut self, res: OpenChannelResponse) {
-        println!("Open Channel Response received!");
+    fn recv_open_channel_res(&mut self, res: OpenChannelResponse) -> Result<(), TransportError> {
+        let span = info_span!("open_channel", side = "taker", channel = %taker_channel(self), correlation_id = %self.correlation_id);
+        let _enter = span.enter();
+        info!("Open Channel Response received!");
         let OpenChannelResponse {
             close_token,
-            pay_token
+            pay_token,
+            maker_margin
         } = res;
 
-        // validate token & update taker state
-        assert!(self.customer_state.verify_close_token(&self.channel_state, &close_token));
-        println!("verified close token!");
+        // maker must fund the channel with the collateral we committed to
+        if maker_margin != self.maker_margin {
+            warn!(maker_margin, committed = self.maker_margin, "Maker collateral does not match the committed channel balance");
+            return Err(TransportError::Rejected(OrderRejection::CollateralMismatch { maker_margin, quoted: self.maker_margin }))
+        }
 
-        // validate token & update taker state
-        assert!(establish_customer_final(&mut self.channel_state, &mut self.customer_state, &pay_token));
-        println!("verified payment token!");
-        println!("Channel established!");
+        // Tokens come from the maker, so either failing to verify leaves the channel unestablished
+        let mut customer_state = self.customer_state.clone();
+        if !customer_state.verify_close_token(&self.channel_state, &close_token) {
+            warn!("Close token does not verify");
+            return Err(TransportError::Unexpected("a valid close token"))
+        }
+        debug!("verified close token!");
+
+        let mut channel_state = self.channel_state.clone();
+        if !establish_customer_final(&mut channel_state, &mut customer_state, &pay_token) {
+            warn!("Payment token does not verify");
+            return Err(TransportError::Unexpected("a valid payment token"))
+        }
+        debug!("verified payment token!");
+        self.channel_state = channel_state;
+        self.customer_state = customer_state;
+        self.established = true;
+        // PnL accrues from the price at which the channel was established
+        self.last_settled_epoch = self.epoch;
+        info!(epoch = self.epoch, "Channel established!");
+        self.events.publish(Event::ChannelEstablished { side: Side::Taker, channel: taker_channel(self) });
+        Ok(())
     }
 
-    fn send_payment_req(&mut self) -> PaymentRequest {
-        let rng = &mut rand::thread_rng();
-        
-        // compute payment
-        let market_data = self.market_data.clone().expect("must have market data");
-        let prev_market_data = self.prev_market_data.clone().expect("must have market data");
-        let position_size = self.order_size.clone();
-        let change_in_price = market_data.bitcoin.usd - prev_market_data.bitcoin.usd; // change in USD
-        println!("Change in price: {}", change_in_price);
-        let percent_change_in_price = change_in_price / prev_market_data.bitcoin.usd;
-        println!("Percent change in price: {}", change_in_price);
-        let payment = math::compute_payment(market_data, prev_market_data, position_size);
+    fn send_channel_established_req(&self) -> ChannelEstablishedRequest {
+        // Lets the maker lock the collateral it reserved for this channel
+        let req = ChannelEstablishedRequest {
+            customer_public_key: self.customer_state.pk_c,
+            correlation_id: self.correlation_id.clone()
+        };
+        info!(channel = %taker_channel(self), correlation_id = %self.correlation_id, "Channel Established Request sent!");
+        req
+    }
+
+    fn recv_settlement_req(&mut self, req: SettlementRequest) -> Result<u64, PaymentRejection> {
+        let SettlementRequest {
+            from_epoch,
+            to_epoch,
+            amount,
+            correlation_id
+        } = req;
+        let span = info_span!("settlement", side = "taker", channel = %taker_channel(self), from_epoch, to_epoch, correlation_id = %correlation_id);
+        let _enter = span.enter();
+        info!(amount, "Settlement Request received!");
+
+        // Maker's view of the unsettled span must match our own price history
+        let rejection = if !self.established || self.closed {
+            PaymentRejection::NoChannel
+        } else if from_epoch != self.last_settled_epoch || to_epoch <= from_epoch || to_epoch > self.epoch {
+            PaymentRejection::UnexpectedEpochs { from_epoch, to_epoch, last_settled_epoch: self.last_settled_epoch, epoch: self.epoch }
+        } else {
+            match self.price_history.compute_payment(from_epoch, to_epoch, self.order_size) {
+                None => PaymentRejection::MissingMarketData { from_epoch, to_epoch },
+                Some(payment) if payment != amount => {
+                    self.events.publish(Event::PaymentDisputed { side: Side::Taker, channel: taker_channel(self), to_epoch, expected: payment, received: amount });
+                    PaymentRejection::WrongAmount { expected: payment, received: amount }
+                },
+                Some(_) => {
+                    // Payment rounds up to this epoch are started by the taker
+                    self.settlement_correlation_id = Some(correlation_id);
+                    return Ok(to_epoch)
+                }
+            }
+        };
+        warn!(reason = rejection.name(), "Settlement Request refused: {}", rejection);
+        Err(rejection)
+    }
+
+    fn send_payment_req<R: RngCore + CryptoRng>(&mut self, rng: &mut R) -> Option<PaymentRequest> {
+        // compute payment over the unsettled epochs, for which we need every price
+        let (from_epoch, to_epoch, payment) = self.next_settlement()?;
+        self.correlation_id = self.settlement_correlation_id.take().unwrap_or_else(new_correlation_id);
+        let span = info_span!("settlement", side = "taker", channel = %taker_channel(self), from_epoch, to_epoch, correlation_id = %self.correlation_id);
+        let _enter = span.enter();
+        info!("Settling epochs {} to {}", from_epoch + 1, to_epoch);
 
         if payment > 0 {
-            println!("^^UP^^ {} Taker pays Maker! {} in Cosmos (ATOM, Terra, etc) collateral", percent_change_in_price, payment)
+            info!(payment, "Taker pays Maker in Cosmos (ATOM, Terra, etc) collateral")
         } else {
-            println!("__DOWN__ {} Maker pays Taker! ({}) in Cosmos (ATOM, Terra, etc) collateral", percent_change_in_price, -payment)
+            info!(payment = -payment, "Maker pays Taker in Cosmos (ATOM, Terra, etc) collateral")
         }
 
         // generate payment proof
         let (payment_proof, new_customer_state, pay_time) = measure_two_arg!(
+            self.clock,
             generate_payment_proof(
                 rng, 
                 &self.channel_state, 
@@ -201,52 +342,136 @@ impl Taker for TakerState {
             )
         );
         self.new_customer_state = Some(new_customer_state);
-        println!(">> Time to generate payment proof: {} ms", pay_time);
+        self.pending_epoch = Some(to_epoch);
+        debug!(step = "payment_proof", ms = pay_time as u64, "Generated payment proof");
+        metrics::observe_step("payment_proof", pay_time);
 
         // TODO ----- Send proof to merchant -----
         let req = PaymentRequest {
-            payment_proof
+            payment_proof,
+            from_epoch,
+            to_epoch,
+            correlation_id: self.correlation_id.clone()
         };
-        println!("Payment Request sent!");
-        req
+        info!("Payment Request sent!");
+        Some(req)
     }
 
-    fn recv_payment_res(&mut self, res: PaymentResponse) {
-        println!("Payment Response received!");
+    fn recv_payment_res(&mut self, res: PaymentResponse) -> Result<(), TransportError> {
+        let span = info_span!("settlement", side = "taker", channel = %taker_channel(self), to_epoch = ?self.pending_epoch, correlation_id = %self.correlation_id);
+        let _enter = span.enter();
+        info!("Payment Response received!");
         // Recv new close token
         let PaymentResponse {
             close_token
         } = res;
 
-        // Create new revoke token and update customer state
-        self.revoke_token = Some(generate_revoke_token(
-            &self.channel_state, 
-            &mut self.customer_state, 
-            self.new_customer_state.clone().expect("new_customer_state is defined in response to a payment"), 
-            &close_token
-        ));
-        println!("generated revoke token!");
+        let new_customer_state = match self.new_customer_state.clone() {
+            Some(new_customer_state) => new_customer_state,
+            None => return Err(TransportError::Unexpected("a PaymentResponse only with a payment in flight"))
+        };
+
+        // Create new revoke token and update customer state.
+        // libbolt unwraps a close token that does not verify, so the update runs on a copy.
+        let mut customer_state = self.customer_state.clone();
+        let (revoked, revoke_time) = measure_one_arg!(
+            self.clock,
+            panic::catch_unwind(AssertUnwindSafe(|| generate_revoke_token(
+                &self.channel_state, 
+                &mut customer_state, 
+                new_customer_state, 
+                &close_token
+            )))
+        );
+        let revoke_token = match revoked {
+            Ok(revoke_token) => revoke_token,
+            Err(_) => {
+                warn!("Close token for the new wallet does not verify");
+                return Err(TransportError::Unexpected("a valid close token for the new wallet"))
+            }
+        };
+        self.customer_state = customer_state;
+        self.revoke_token = Some(revoke_token);
+        debug!(step = "generate_revoke_token", ms = revoke_time as u64, "generated revoke token!");
+        metrics::observe_step("generate_revoke_token", revoke_time);
 
         // -------- Send revoke token to merchant ----- 
         // self.send_generate_payment_token_req();
+        Ok(())
     }
 
     fn send_generate_payment_token_req(&mut self) -> GeneratePaymentTokenRequest {
         let req = GeneratePaymentTokenRequest {
-            revoke_token: self.revoke_token.clone().expect("Revoke token must be Some() to generate a payment token")
+            revoke_token: self.revoke_token.clone().expect("Revoke token must be Some() to generate a payment token"),
+            correlation_id: self.correlation_id.clone()
         };
         // TODO -------- Send revoke token to merchant ----- 
-        println!("Generate Payment Token Request sent!");
+        info!(channel = %taker_channel(self), correlation_id = %self.correlation_id, "Generate Payment Token Request sent!");
         req
     }
 
-    fn recv_generate_payment_token_res(&mut self, res: GeneratePaymentTokenResponse) {
-        println!("Generate Payment Token Response received!");
+    fn recv_generate_payment_token_res(&mut self, res: GeneratePaymentTokenResponse) -> Result<(), TransportError> {
+        let span = info_span!("settlement", side = "taker", channel = %taker_channel(self), to_epoch = ?self.pending_epoch, correlation_id = %self.correlation_id);
+        let _enter = span.enter();
+        info!("Generate Payment Token Response received!");
         // Recv and verify the pay token and update internal state
         let GeneratePaymentTokenResponse {
             payment_token
         } = res;
-        assert!(self.customer_state.verify_pay_token(&self.channel_state, &payment_token));
-        println!("Generated payment_token is valid!");
+        // The revoke token stays outstanding, so the round resumes from the revoke step
+        if !self.customer_state.verify_pay_token(&self.channel_state, &payment_token) {
+            warn!("Payment token does not verify");
+            return Err(TransportError::Unexpected("a valid payment token"))
+        }
+        info!(taker_balance = self.customer_state.cust_balance, "Generated payment_token is valid!");
+
+        // Payment round is complete
+        self.new_customer_state = None;
+        self.revoke_token = None;
+        if let Some(epoch) = self.pending_epoch.take() {
+            self.last_settled_epoch = epoch;
+            self.price_history.prune_before(epoch);
+        }
+        self.events.publish(Event::PayTokenIssued { side: Side::Taker, channel: taker_channel(self), epoch: self.last_settled_epoch });
+        Ok(())
+    }
+
+    fn send_close_channel_req(&self) -> Result<CloseChannelRequest, TransportError> {
+        // The maker would refuse it anyway, while it waits for the revoke token
+        if self.revoke_token.is_some() {
+            return Err(TransportError::Refused(PaymentRejection::PaymentInFlight))
+        }
+        // Close on the latest state, signed by the latest close token
+        let req = CloseChannelRequest {
+            customer_close: customer_close(&self.channel_state, &self.customer_state),
+            correlation_id: new_correlation_id()
+        };
+        info!(channel = %taker_channel(self), correlation_id = %req.correlation_id, "Close Channel Request sent!");
+        Ok(req)
+    }
+
+    fn recv_close_channel_res(&mut self, res: CloseChannelResponse) -> Result<(), TransportError> {
+        info!(channel = %taker_channel(self), "Close Channel Response received!");
+        let CloseChannelResponse {
+            maker_balance,
+            taker_balance
+        } = res;
+
+        // Maker must agree with the balances in our latest state
+        if taker_balance != self.customer_state.cust_balance || maker_balance != self.customer_state.merch_balance {
+            let rejection = PaymentRejection::WrongCloseBalances {
+                taker_balance,
+                maker_balance,
+                expected_taker_balance: self.customer_state.cust_balance,
+                expected_maker_balance: self.customer_state.merch_balance
+            };
+            warn!(reason = rejection.name(), "Close Channel Response refused: {}", rejection);
+            return Err(TransportError::Refused(rejection))
+        }
+        self.available_margin = taker_balance;
+        self.closed = true;
+        info!(channel = %taker_channel(self), taker_balance, maker_balance, "Channel closed!");
+        self.events.publish(Event::ChannelClosed { side: Side::Taker, channel: taker_channel(self), taker_balance, maker_balance });
+        Ok(())
     }
 }
@@ -0,0 +1,107 @@
+use lazy_static::lazy_static;
+use reqwest::{Certificate, Client, Identity};
+use serde::{Serialize, Deserialize};
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::Path;
+use std::sync::Mutex;
+use tracing::info;
+
+// Internal
+use crate::config::create_private_dir;
+
+lazy_static! {
+    // Pair generated by this process, shared by the server and the pinning client
+    static ref SELF_SIGNED: Mutex<Option<(String, String)>> = Mutex::new(None);
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct TlsConfig {
+    pub cert_path: Option<String>,
+    pub key_path: Option<String>,
+    pub self_signed: bool, // Generate a localhost certificate at startup, for local testing only
+    pub client_ca_path: Option<String>, // Require client certificates signed by this CA (mutual TLS)
+    pub client_cert_path: Option<String>, // Certificate and key presented on outgoing maker-taker links
+    pub client_key_path: Option<String>,
+    pub peer_cert_path: Option<String>, // Pinned certificate of the counterparty, replaces the system roots
+}
+
+impl TlsConfig {
+    // Paths of the server certificate and key, generating a self signed pair if configured
+    pub fn server_cert_and_key(&self, data_dir: &Path) -> (String, String) {
+        match (&self.cert_path, &self.key_path) {
+            (Some(cert_path), Some(key_path)) => (cert_path.clone(), key_path.clone()),
+            _ if self.self_signed => self_signed(data_dir),
+            _ => panic!("TLS needs cert_path and key_path, or self_signed")
+        }
+    }
+}
+
+fn self_signed(data_dir: &Path) -> (String, String) {
+    let mut pair = SELF_SIGNED.lock().expect("Self signed pair is not poisoned");
+    pair.get_or_insert_with(|| generate_self_signed(data_dir)).clone()
+}
+
+// Writes a file that did not exist before, readable only by the daemon's user
+fn write_private(path: &Path, contents: &[u8]) {
+    OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(path)
+        .and_then(|mut file| file.write_all(contents))
+        .unwrap_or_else(|err| panic!("Failed to write {}: {}", path.display(), err));
+}
+
+// A new pair for every run, a key left on disk by an earlier run or anyone else is never trusted
+fn generate_self_signed(data_dir: &Path) -> (String, String) {
+    let dir = data_dir.join("tls");
+    let cert_path = dir.join("self_signed.crt");
+    let key_path = dir.join("self_signed.key");
+    create_private_dir(&dir).expect("Failed to create the certificate directory");
+    for path in &[&cert_path, &key_path] {
+        if path.exists() {
+            fs::remove_file(path).unwrap_or_else(|err| panic!("Failed to remove the earlier {}: {}", path.display(), err));
+        }
+    }
+
+    let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_string()])
+        .expect("Self signed certificate generation failed");
+    write_private(&cert_path, cert.serialize_pem().expect("Certificate serializes").as_bytes());
+    write_private(&key_path, cert.serialize_private_key_pem().as_bytes());
+    info!("Generated a self signed certificate at {}", cert_path.display());
+    (cert_path.display().to_string(), key_path.display().to_string())
+}
+
+fn read(path: &str) -> Vec<u8> {
+    fs::read(path).unwrap_or_else(|err| panic!("Failed to read {}: {}", path, err))
+}
+
+// HTTP client for the maker-taker links, pinning the peer certificate and presenting ours if configured
+pub fn client(tls: Option<&TlsConfig>, data_dir: &Path) -> Client {
+    let tls = match tls {
+        Some(tls) => tls,
+        None => return Client::new()
+    };
+    let mut builder = Client::builder().use_rustls_tls();
+
+    let pinned_cert_path = tls.peer_cert_path.clone().or_else(|| {
+        // A self signed peer is ourselves when testing locally
+        if tls.self_signed { Some(tls.server_cert_and_key(data_dir).0) } else { None }
+    });
+    if let Some(path) = pinned_cert_path {
+        let cert = Certificate::from_pem(&read(&path)).expect("Pinned peer certificate is valid PEM");
+        builder = builder
+            .tls_built_in_root_certs(false)
+            .add_root_certificate(cert);
+    }
+
+    if let (Some(cert_path), Some(key_path)) = (&tls.client_cert_path, &tls.client_key_path) {
+        let mut pem = read(key_path);
+        pem.extend(read(cert_path));
+        builder = builder.identity(Identity::from_pem(&pem).expect("Client certificate and key are valid PEM"));
+    }
+
+    builder.build().expect("TLS client builds")
+}
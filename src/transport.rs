@@ -0,0 +1,222 @@
+use async_trait::async_trait;
+use reqwest::Client;
+use secp256k1::{PublicKey, SecretKey};
+use std::fmt;
+use std::io;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{UnixListener, UnixStream};
+use tokio::sync::{mpsc, oneshot, Mutex};
+use tracing::warn;
+
+// Internal
+use crate::admission::OrderRejection;
+use crate::client::RainboltClient;
+use crate::peer::{PeerMessage, PeerSession, MAX_FRAME_LEN};
+use crate::settlement::{Backoff, PaymentRejection};
+
+#[derive(Debug)]
+pub enum TransportError {
+    Io(String),
+    Rejected(OrderRejection),
+    Refused(PaymentRejection),
+    Remote(String),
+    Unexpected(&'static str),
+}
+
+impl fmt::Display for TransportError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TransportError::Io(err) => write!(f, "transport failed: {}", err),
+            TransportError::Rejected(rejection) => write!(f, "order rejected: {}", rejection),
+            TransportError::Refused(rejection) => write!(f, "payment refused: {}", rejection),
+            TransportError::Remote(err) => write!(f, "counterparty failed: {}", err),
+            TransportError::Unexpected(expected) => write!(f, "unexpected response, expected {}", expected),
+        }
+    }
+}
+
+impl From<io::Error> for TransportError {
+    fn from(err: io::Error) -> Self {
+        TransportError::Io(err.to_string())
+    }
+}
+
+impl From<reqwest::Error> for TransportError {
+    fn from(err: reqwest::Error) -> Self {
+        TransportError::Io(err.to_string())
+    }
+}
+
+// Request/response delivery of protocol messages between a Taker and a Maker
+#[async_trait]
+pub trait Transport: Send + Sync {
+    async fn request(&self, msg: PeerMessage) -> Result<PeerMessage, TransportError>;
+}
+
+// Signed JSON posts to the maker and taker routes
+pub struct HttpTransport {
+    pub client: RainboltClient,
+}
+
+impl HttpTransport {
+    pub fn new(client: Client, base_url: String, signing_key: SecretKey) -> Self {
+        // Settlement is retried by the schedulers, so connect once per request
+        let client = RainboltClient::new(base_url)
+            .client(client)
+            .signing_key(signing_key)
+            .backoff(Backoff { max_attempts: 1, ..Backoff::default() });
+        HttpTransport { client }
+    }
+}
+
+#[async_trait]
+impl Transport for HttpTransport {
+    async fn request(&self, msg: PeerMessage) -> Result<PeerMessage, TransportError> {
+        match msg {
+            PeerMessage::OpenChannelRequest(req) => self.client.open_channel(&req).await.map(PeerMessage::OpenChannelResponse),
+            PeerMessage::ChannelEstablishedRequest(req) => self.client.channel_established(&req).await.map(|_| PeerMessage::Ack),
+            PeerMessage::PaymentRequest(req) => self.client.recv_pay(&req).await.map(PeerMessage::PaymentResponse),
+            PeerMessage::GeneratePaymentTokenRequest(req) => self.client.payment_token(&req).await.map(PeerMessage::GeneratePaymentTokenResponse),
+            PeerMessage::CloseChannelRequest(req) => self.client.close_channel(&req).await.map(PeerMessage::CloseChannelResponse),
+            PeerMessage::SettlementRequest(req) => self.client.settle(&req).await.map(|_| PeerMessage::Ack),
+            _ => Err(TransportError::Unexpected("a request message"))
+        }
+    }
+}
+
+// In process delivery to a handler task, for tests and single process deployments
+pub struct InMemoryTransport {
+    sender: mpsc::Sender<(PeerMessage, oneshot::Sender<PeerMessage>)>,
+}
+
+pub struct InMemoryListener {
+    receiver: mpsc::Receiver<(PeerMessage, oneshot::Sender<PeerMessage>)>,
+}
+
+impl InMemoryTransport {
+    pub fn pair() -> (InMemoryTransport, InMemoryListener) {
+        let (sender, receiver) = mpsc::channel(16);
+        (InMemoryTransport { sender }, InMemoryListener { receiver })
+    }
+}
+
+impl InMemoryListener {
+    // Answers every request with the handler until all transports are dropped
+    pub async fn serve<F: FnMut(PeerMessage) -> PeerMessage>(mut self, mut handler: F) {
+        while let Some((msg, responder)) = self.receiver.recv().await {
+            let _ = responder.send(handler(msg));
+        }
+    }
+}
+
+#[async_trait]
+impl Transport for InMemoryTransport {
+    async fn request(&self, msg: PeerMessage) -> Result<PeerMessage, TransportError> {
+        let (responder, response) = oneshot::channel();
+        self.sender.clone().send((msg, responder)).await
+            .map_err(|_| TransportError::Io("in memory listener is closed".to_string()))?;
+        response.await.map_err(|_| TransportError::Io("in memory listener dropped the request".to_string()))
+    }
+}
+
+async fn write_frame(stream: &mut UnixStream, msg: &PeerMessage) -> io::Result<()> {
+    let body = serde_json::to_vec(msg).map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+    stream.write_all(&(body.len() as u32).to_be_bytes()).await?;
+    stream.write_all(&body).await
+}
+
+async fn read_frame(stream: &mut UnixStream) -> io::Result<PeerMessage> {
+    let mut len = [0u8; 4];
+    stream.read_exact(&mut len).await?;
+    let len = u32::from_be_bytes(len) as usize;
+    if len > MAX_FRAME_LEN {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, format!("frame of {} bytes is too large", len)))
+    }
+    let mut body = vec![0u8; len];
+    stream.read_exact(&mut body).await?;
+    serde_json::from_slice(&body).map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))
+}
+
+// Length prefixed JSON frames over a local Unix socket, one connection per request
+pub struct UnixTransport {
+    pub path: PathBuf,
+}
+
+impl UnixTransport {
+    pub fn new<P: AsRef<Path>>(path: P) -> Self {
+        UnixTransport { path: path.as_ref().to_path_buf() }
+    }
+}
+
+#[async_trait]
+impl Transport for UnixTransport {
+    async fn request(&self, msg: PeerMessage) -> Result<PeerMessage, TransportError> {
+        let mut stream = UnixStream::connect(&self.path).await?;
+        write_frame(&mut stream, &msg).await?;
+        Ok(read_frame(&mut stream).await?)
+    }
+}
+
+async fn answer_unix<F: Fn(PeerMessage) -> PeerMessage>(mut stream: UnixStream, handler: &F) {
+    let msg = match read_frame(&mut stream).await {
+        Ok(msg) => msg,
+        Err(err) => return warn!("Unix transport request failed: {}", err)
+    };
+    if let Err(err) = write_frame(&mut stream, &handler(msg)).await {
+        warn!("Unix transport response failed: {}", err);
+    }
+}
+
+// Answers each connection on its own task, a client that stalls or hangs up only loses its own request
+pub async fn serve_unix<P: AsRef<Path>, F: Fn(PeerMessage) -> PeerMessage + Send + Sync + 'static>(path: P, handler: F) -> io::Result<()> {
+    let mut listener = UnixListener::bind(path)?;
+    let handler = Arc::new(handler);
+    loop {
+        match listener.accept().await {
+            Ok((stream, _)) => {
+                let handler = handler.clone();
+                tokio::spawn(async move { answer_unix(stream, &*handler).await });
+            },
+            Err(err) => warn!("Unix transport connection failed: {}", err)
+        }
+    }
+}
+
+// Requests over an encrypted peer session, one at a time. Connects on the first request
+// and again after a failure, since the counterparty may restart between rounds.
+pub struct PeerTransport {
+    pub addr: SocketAddr,
+    pub remote_public_key: PublicKey,
+    local_key: SecretKey,
+    session: Mutex<Option<PeerSession>>,
+}
+
+impl PeerTransport {
+    pub fn new(addr: SocketAddr, local_key: SecretKey, remote_public_key: PublicKey) -> Self {
+        PeerTransport {
+            addr,
+            remote_public_key,
+            local_key,
+            session: Mutex::new(None),
+        }
+    }
+}
+
+#[async_trait]
+impl Transport for PeerTransport {
+    async fn request(&self, msg: PeerMessage) -> Result<PeerMessage, TransportError> {
+        let mut session = self.session.lock().await;
+        if session.is_none() {
+            *session = Some(PeerSession::connect(self.addr, &self.local_key, &self.remote_public_key).await?);
+        }
+        let res = session.as_mut().expect("session was connected above").request(&msg).await;
+        if res.is_err() {
+            // The stream is out of step with the cipher state, start over on the next request
+            *session = None;
+        }
+        Ok(res?)
+    }
+}
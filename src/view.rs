@@ -0,0 +1,187 @@
+// Public views of the channel state. TakerState and MakerState carry the customer and merchant
+// secret keys, so only these views are ever serialized over HTTP.
+use serde::{Serialize, Deserialize};
+
+// Internal
+use crate::admission::AdmissionPolicy;
+use crate::driver::{MakerSlot, TakerSlot};
+use crate::history::CatchUpMode;
+use crate::maker::MakerState;
+use crate::settlement::{Side, SettlementStatus};
+use crate::taker::TakerState;
+use crate::MarketData;
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub enum ChannelPhase {
+    Opening, // Maker collateral is reserved but not yet locked
+    Open,
+    Settling, // A payment round is in flight
+    Closed,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct MarginView {
+    pub initial_margin: i64,
+    pub available_margin: i64,
+    pub taker_margin: Option<i64>,
+    pub maker_margin: Option<i64>,
+    pub order_size: Option<i64>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ChannelView {
+    pub side: Side,
+    pub phase: ChannelPhase,
+    pub customer_public_key: Option<String>,
+    pub taker_balance: i64,
+    pub maker_balance: i64,
+    pub epoch: u64,
+    pub last_settled_epoch: u64,
+    pub last_settled_price: Option<MarketData>,
+    pub margin: MarginView,
+}
+
+impl ChannelView {
+    pub fn from_taker(taker: &TakerState) -> Self {
+        let phase = if taker.closed {
+            ChannelPhase::Closed
+        } else if !taker.established {
+            ChannelPhase::Opening
+        } else if taker.pending_epoch.is_some() || taker.revoke_token.is_some() {
+            ChannelPhase::Settling
+        } else {
+            ChannelPhase::Open
+        };
+        ChannelView {
+            side: Side::Taker,
+            phase,
+            customer_public_key: taker.channel_token.pk_c.map(|pk| pk.to_string()),
+            taker_balance: taker.customer_state.cust_balance,
+            maker_balance: taker.customer_state.merch_balance,
+            epoch: taker.epoch,
+            last_settled_epoch: taker.last_settled_epoch,
+            last_settled_price: taker.price_history.get(taker.last_settled_epoch).cloned(),
+            margin: MarginView {
+                initial_margin: taker.initial_margin,
+                available_margin: taker.available_margin,
+                taker_margin: Some(taker.initial_margin),
+                maker_margin: Some(taker.maker_margin),
+                order_size: Some(taker.order_size),
+            }
+        }
+    }
+
+    // None when the maker has no channel open or pending
+    pub fn from_maker(maker: &MakerState) -> Option<Self> {
+        let (maker_margin, taker_margin) = match (maker.maker_margin, maker.taker_margin) {
+            (Some(maker_margin), Some(taker_margin)) => (maker_margin, taker_margin),
+            _ => return None
+        };
+        let phase = if maker.reserved_margin > 0 {
+            ChannelPhase::Opening
+        } else if maker.pending_epoch.is_some() {
+            ChannelPhase::Settling
+        } else {
+            ChannelPhase::Open
+        };
+        Some(ChannelView {
+            side: Side::Maker,
+            phase,
+            customer_public_key: maker.channel_token.pk_c.map(|pk| pk.to_string()),
+            // The merchant state does not track per channel balances, so they follow from the verified payments
+            taker_balance: taker_margin - maker.net_payments,
+            maker_balance: maker_margin + maker.net_payments,
+            epoch: maker.epoch,
+            last_settled_epoch: maker.last_settled_epoch,
+            last_settled_price: maker.price_history.get(maker.last_settled_epoch).cloned(),
+            margin: MarginView {
+                initial_margin: maker.initial_margin,
+                available_margin: maker.available_margin,
+                taker_margin: Some(taker_margin),
+                maker_margin: Some(maker_margin),
+                order_size: maker.order_size,
+            }
+        })
+    }
+}
+
+// Every channel of this daemon, as taker and as maker
+pub fn channel_views(taker_slot: &TakerSlot, maker_slot: &MakerSlot) -> Vec<ChannelView> {
+    let taker_view = taker_slot.lock().expect("Taker is not poisoned").as_ref().map(ChannelView::from_taker);
+    let maker_view = maker_slot.lock().expect("Maker is not poisoned").as_ref().and_then(ChannelView::from_maker);
+    taker_view.into_iter().chain(maker_view).collect()
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct TakerView {
+    pub established: bool,
+    pub closed: bool,
+    pub customer_public_key: Option<String>,
+    pub initial_margin: i64,
+    pub maker_margin: i64,
+    pub order_size: i64,
+    pub available_margin: i64,
+    pub taker_balance: i64,
+    pub maker_balance: i64,
+    pub market_data: Option<MarketData>,
+    pub epoch: u64,
+    pub last_settled_epoch: u64,
+    pub settlement: SettlementStatus,
+    pub catch_up: CatchUpMode,
+}
+
+impl From<&TakerState> for TakerView {
+    fn from(taker: &TakerState) -> Self {
+        TakerView {
+            established: taker.established,
+            closed: taker.closed,
+            customer_public_key: taker.channel_token.pk_c.map(|pk| pk.to_string()),
+            initial_margin: taker.initial_margin,
+            maker_margin: taker.maker_margin,
+            order_size: taker.order_size,
+            available_margin: taker.available_margin,
+            taker_balance: taker.customer_state.cust_balance,
+            maker_balance: taker.customer_state.merch_balance,
+            market_data: taker.market_data.clone(),
+            epoch: taker.epoch,
+            last_settled_epoch: taker.last_settled_epoch,
+            settlement: taker.settlement.clone(),
+            catch_up: taker.catch_up.clone(),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct MakerView {
+    pub initial_margin: i64,
+    pub available_margin: i64,
+    pub reserved_margin: i64,
+    pub collateral: Option<i64>,
+    pub order_size: Option<i64>,
+    pub maker_margin: Option<i64>,
+    pub taker_margin: Option<i64>,
+    pub net_payments: i64,
+    pub policy: AdmissionPolicy,
+    pub market_data: Option<MarketData>,
+    pub epoch: u64,
+    pub last_settled_epoch: u64,
+}
+
+impl From<&MakerState> for MakerView {
+    fn from(maker: &MakerState) -> Self {
+        MakerView {
+            initial_margin: maker.initial_margin,
+            available_margin: maker.available_margin,
+            reserved_margin: maker.reserved_margin,
+            collateral: maker.collateral,
+            order_size: maker.order_size,
+            maker_margin: maker.maker_margin,
+            taker_margin: maker.taker_margin,
+            net_payments: maker.net_payments,
+            policy: maker.policy.clone(),
+            market_data: maker.market_data.clone(),
+            epoch: maker.epoch,
+            last_settled_epoch: maker.last_settled_epoch,
+        }
+    }
+}
@@ -0,0 +1,171 @@
+// Delivers selected domain events to external endpoints as signed JSON posts
+use reqwest::{Client, Url, header::CONTENT_TYPE};
+use secp256k1::SecretKey;
+use serde::{Serialize, Deserialize};
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+use tokio::sync::mpsc;
+use tracing::{error, warn};
+
+// Internal
+use crate::auth::sign_request;
+use crate::clock::SharedClock;
+use crate::config::create_private_dir;
+use crate::events::Event;
+use crate::settlement::Backoff;
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct WebhookSubscription {
+    pub url: String,
+    pub events: Vec<String>, // Event names such as "margin_call", every event if empty
+}
+
+impl WebhookSubscription {
+    pub fn wants(&self, event: &Event) -> bool {
+        self.events.is_empty() || self.events.iter().any(|name| name == event.name())
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct WebhookConfig {
+    pub subscriptions: Vec<WebhookSubscription>,
+    pub backoff: Option<Backoff>,
+    pub dead_letter_path: Option<String>, // Defaults to webhooks.dead.jsonl in the data dir
+    pub queue_len: Option<usize>, // Deliveries waiting per endpoint before the rest are dead lettered, 256 by default
+}
+
+impl WebhookConfig {
+    pub fn dead_letter_path(&self, data_dir: &Path) -> PathBuf {
+        match self.dead_letter_path {
+            Some(ref path) => PathBuf::from(path),
+            None => data_dir.join("webhooks.dead.jsonl")
+        }
+    }
+
+    pub fn queue_len(&self) -> usize {
+        self.queue_len.unwrap_or(256)
+    }
+}
+
+// Body of every webhook post, signed with the node key like the daemon's own requests
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct WebhookPayload {
+    pub timestamp: u64,
+    pub event: Event,
+}
+
+// One line of the dead letter log, for deliveries that ran out of attempts or never got a place in the queue
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct DeadLetter {
+    pub url: String,
+    pub attempts: u32,
+    pub error: String,
+    pub payload: WebhookPayload,
+}
+
+async fn post(client: &Client, url: &str, body: &[u8], node_key: &SecretKey) -> Result<(), String> {
+    let path = Url::parse(url).map_err(|err| err.to_string())?.path().to_string();
+    let res = sign_request(node_key, "POST", &path, body)
+        .into_iter()
+        .fold(
+            client.post(url).header(CONTENT_TYPE, "application/json"),
+            |req, (name, value)| req.header(name, value)
+        )
+        .body(body.to_vec())
+        .send()
+        .await
+        .map_err(|err| err.to_string())?;
+    if res.status().is_success() {
+        Ok(())
+    } else {
+        Err(format!("{} returned {}", url, res.status()))
+    }
+}
+
+fn dead_letter(path: &PathBuf, letter: &DeadLetter) {
+    if let Some(dir) = path.parent() {
+        let _ = create_private_dir(dir);
+    }
+    let line = serde_json::to_string(letter).expect("Dead letter serializes to JSON");
+    // Payloads carry channel balances, so the log is only readable by the daemon's user
+    let written = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .mode(0o600)
+        .open(path)
+        .and_then(|mut file| writeln!(file, "{}", line));
+    if let Err(err) = written {
+        error!("Failed to write webhook dead letter to {}: {}", path.display(), err);
+    }
+}
+
+// Everything one endpoint's worker needs to post and retry
+struct Endpoint {
+    client: Client,
+    url: String,
+    node_key: SecretKey,
+    backoff: Backoff,
+    dead_letter_path: PathBuf,
+    clock: SharedClock,
+}
+
+impl Endpoint {
+    async fn deliver(&self, payload: WebhookPayload) {
+        let body = serde_json::to_vec(&payload).expect("Webhook payload serializes to JSON");
+        for attempt in 1..=self.backoff.max_attempts {
+            let error = match post(&self.client, &self.url, &body, &self.node_key).await {
+                Ok(()) => return,
+                Err(error) => error
+            };
+            warn!("Webhook {} for {} failed (attempt {}): {}", payload.event.name(), self.url, attempt, error);
+            if attempt < self.backoff.max_attempts {
+                self.clock.sleep(self.backoff.delay(attempt)).await;
+            } else {
+                dead_letter(&self.dead_letter_path, &DeadLetter { url: self.url.clone(), attempts: attempt, error, payload });
+                return
+            }
+        }
+    }
+
+    // Posts the endpoint's deliveries one at a time, in the order the events happened
+    async fn run(self, mut queue: mpsc::Receiver<WebhookPayload>) {
+        while let Some(payload) = queue.recv().await {
+            self.deliver(payload).await;
+        }
+    }
+}
+
+// Queues every subscribed event for its endpoint's worker, so a slow endpoint does not hold up the rest.
+// This loop never waits on an endpoint, and what does not fit in a full queue goes to the dead letter log.
+pub async fn run_webhooks(config: WebhookConfig, dead_letter_path: PathBuf, mut events: mpsc::UnboundedReceiver<Event>, client: Client, node_key: SecretKey, clock: SharedClock) {
+    let backoff = config.backoff.clone().unwrap_or_default();
+    backoff.validate();
+    let mut queues: Vec<(WebhookSubscription, mpsc::Sender<WebhookPayload>)> = config.subscriptions
+        .iter()
+        .map(|subscription| {
+            let (sender, receiver) = mpsc::channel(config.queue_len());
+            let endpoint = Endpoint {
+                client: client.clone(),
+                url: subscription.url.clone(),
+                node_key: node_key.clone(),
+                backoff: backoff.clone(),
+                dead_letter_path: dead_letter_path.clone(),
+                clock: clock.clone()
+            };
+            tokio::spawn(endpoint.run(receiver));
+            (subscription.clone(), sender)
+        })
+        .collect();
+    while let Some(event) = events.recv().await {
+        let payload = WebhookPayload { timestamp: clock.now_secs(), event };
+        for (subscription, queue) in queues.iter_mut().filter(|(subscription, _)| subscription.wants(&payload.event)) {
+            if let Err(err) = queue.try_send(payload.clone()) {
+                let error = if err.is_closed() { "delivery worker stopped" } else { "delivery queue is full" };
+                warn!("Webhook {} for {} not queued: {}", payload.event.name(), subscription.url, error);
+                dead_letter(&dead_letter_path, &DeadLetter { url: subscription.url.clone(), attempts: 0, error: error.to_string(), payload: payload.clone() });
+            }
+        }
+    }
+}
@@ -0,0 +1,107 @@
+// Signed requests are accepted once, from known keys, with bounded bodies
+use bytes::Bytes;
+use secp256k1::{Secp256k1, PublicKey, SecretKey};
+use std::sync::Arc;
+use std::time::Duration;
+use warp::{Filter, http::Response};
+
+use rainboltd::{
+    api::{handle_rejection, market_routes},
+    auth::{sign_request, AuthConfig, Credentials, MAX_BODY_LEN},
+    clock::{system_clock, Clock, ManualClock, SharedClock, SystemClock},
+    state::DaemonState,
+    transport::InMemoryTransport,
+    MarketData,
+    MarketPrice
+};
+
+fn market_data(bitcoin: i64) -> Vec<u8> {
+    serde_json::to_vec(&MarketData {
+        bitcoin: MarketPrice { usd: bitcoin },
+        cosmos: MarketPrice { usd: 4 }
+    }).unwrap()
+}
+
+// Daemon whose only admin is the returned key
+fn daemon() -> (DaemonState, SecretKey) {
+    daemon_on(system_clock())
+}
+
+fn daemon_on(clock: SharedClock) -> (DaemonState, SecretKey) {
+    let admin_key = SecretKey::new(&mut rand::thread_rng());
+    let node_key = SecretKey::new(&mut rand::thread_rng());
+    let secp = Secp256k1::signing_only();
+    let config = AuthConfig {
+        admins: vec![PublicKey::from_secret_key(&secp, &admin_key).to_string()],
+        traders: Vec::new(),
+        max_clock_skew_secs: 30,
+    };
+    let credentials = Credentials::from_config(&config, PublicKey::from_secret_key(&secp, &node_key), clock);
+    let (transport, _listener) = InMemoryTransport::pair();
+    (DaemonState::new(Arc::new(transport), node_key, Some(credentials)), admin_key)
+}
+
+async fn post(state: &DaemonState, headers: &[(&'static str, String)], body: Vec<u8>) -> Response<Bytes> {
+    let req = headers.iter().fold(
+        warp::test::request().method("POST").path("/marketData"),
+        |req, (name, value)| req.header(*name, value.as_str())
+    );
+    req.body(body).reply(&market_routes(state.clone()).recover(handle_rejection)).await
+}
+
+#[tokio::test]
+async fn accepts_a_signed_request_once() {
+    let (state, admin_key) = daemon();
+    let body = market_data(8000);
+    let headers = sign_request(&admin_key, "POST", "/marketData", &body);
+    assert_eq!(post(&state, &headers, body.clone()).await.status(), 200);
+
+    // The same signature again is a replay, however fresh its timestamp
+    assert_eq!(post(&state, &headers, body.clone()).await.status(), 401);
+
+    // An identical request signs with a new nonce
+    let headers = sign_request(&admin_key, "POST", "/marketData", &body);
+    assert_eq!(post(&state, &headers, body).await.status(), 200);
+}
+
+#[tokio::test]
+async fn refuses_unsigned_and_unknown_keys() {
+    let (state, _) = daemon();
+    let body = market_data(8000);
+    assert_eq!(post(&state, &[], body.clone()).await.status(), 401);
+
+    let stranger = SecretKey::new(&mut rand::thread_rng());
+    let headers = sign_request(&stranger, "POST", "/marketData", &body);
+    assert_eq!(post(&state, &headers, body).await.status(), 403);
+}
+
+#[tokio::test]
+async fn refuses_bodies_over_the_limit() {
+    let (state, admin_key) = daemon();
+    let body = vec![b' '; MAX_BODY_LEN + 1];
+    let headers = sign_request(&admin_key, "POST", "/marketData", &body);
+    assert_eq!(post(&state, &headers, body).await.status(), 413);
+}
+
+#[tokio::test]
+async fn refuses_timestamps_outside_the_skew_on_the_daemon_clock() {
+    let clock = ManualClock::new(SystemClock.now_millis());
+    let (state, admin_key) = daemon_on(Arc::new(clock.clone()));
+    let body = market_data(8000);
+    let headers = sign_request(&admin_key, "POST", "/marketData", &body);
+    assert_eq!(post(&state, &headers, body.clone()).await.status(), 200);
+
+    // The daemon's clock has run ahead of the signer by more than the allowed skew
+    clock.advance(Duration::from_secs(35));
+    let headers = sign_request(&admin_key, "POST", "/marketData", &body);
+    assert_eq!(post(&state, &headers, body.clone()).await.status(), 401);
+
+    // Or fallen behind it
+    clock.set(SystemClock.now_millis() - 35_000);
+    let headers = sign_request(&admin_key, "POST", "/marketData", &body);
+    assert_eq!(post(&state, &headers, body.clone()).await.status(), 401);
+
+    clock.set(SystemClock.now_millis());
+    let headers = sign_request(&admin_key, "POST", "/marketData", &body);
+    assert_eq!(post(&state, &headers, body).await.status(), 200);
+}
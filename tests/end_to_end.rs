@@ -0,0 +1,675 @@
+// Runs the maker and taker routes of one daemon in process, the taker reaching its maker over an in-memory transport
+use bytes::Bytes;
+use secp256k1::SecretKey;
+use std::sync::Arc;
+use warp::{Filter, http::Response};
+
+use rainboltd::{
+    admission::AdmissionPolicy,
+    api::{handle_rejection, maker_routes, market_routes, query_routes, taker_routes},
+    clock::system_clock,
+    driver,
+    history::CatchUpMode,
+    settlement::Side,
+    message::{GeneratePaymentTokenResponse, OrderRequest, PaymentRequest, PaymentResponse, SettlementRequest},
+    peer::PeerMessage,
+    state::DaemonState,
+    taker::{Taker, TakerState},
+    transport::InMemoryTransport,
+    view::{ChannelPhase, ChannelView, MakerView, TakerView},
+    MarketData,
+    MarketPrice
+};
+
+const MAKER_MARGIN: i64 = 5000;
+const TAKER_MARGIN: i64 = 1000;
+const ORDER_SIZE: i64 = 1000;
+
+fn market_data(bitcoin: i64) -> MarketData {
+    MarketData {
+        bitcoin: MarketPrice { usd: bitcoin },
+        cosmos: MarketPrice { usd: 4 }
+    }
+}
+
+struct Harness {
+    state: DaemonState,
+}
+
+impl Harness {
+    fn new() -> Self {
+        Harness::tampered(|res| res)
+    }
+
+    // Taker whose maker passes every answer through `tamper` on the way back
+    fn tampered<F: FnMut(PeerMessage) -> PeerMessage + Send + 'static>(mut tamper: F) -> Self {
+        let (transport, listener) = InMemoryTransport::pair();
+        let state = DaemonState::new(Arc::new(transport), SecretKey::new(&mut rand::thread_rng()), None);
+        let maker_slot = state.maker.clone();
+        tokio::spawn(listener.serve(move |msg| tamper(driver::handle_maker_slot_message(&maker_slot, msg))));
+        Harness { state }
+    }
+
+    async fn post(&self, path: &str, body: Vec<u8>) -> Response<Bytes> {
+        let routes = maker_routes(self.state.clone())
+            .or(taker_routes(self.state.clone()))
+            .or(market_routes(self.state.clone()))
+            .recover(handle_rejection);
+        warp::test::request()
+            .method("POST")
+            .path(path)
+            .body(body)
+            .reply(&routes)
+            .await
+    }
+
+    async fn get(&self, path: &str) -> Response<Bytes> {
+        warp::test::request()
+            .method("GET")
+            .path(path)
+            .reply(&query_routes(self.state.clone()).recover(handle_rejection))
+            .await
+    }
+
+    async fn price(&self, bitcoin: i64) {
+        let res = self.post("/marketData", serde_json::to_vec(&market_data(bitcoin)).unwrap()).await;
+        assert_eq!(res.status(), 200);
+    }
+
+    // Maker with margin to spare and a taker whose channel is open
+    async fn open() -> Self {
+        Harness::new().opened().await
+    }
+
+    async fn opened(self) -> Self {
+        let harness = self;
+        let res = harness.post(&format!("/maker/init/{}", MAKER_MARGIN), Vec::new()).await;
+        assert_eq!(res.status(), 200);
+        let maker: MakerView = serde_json::from_slice(res.body()).unwrap();
+        assert_eq!(maker.available_margin, MAKER_MARGIN);
+
+        let order = OrderRequest {
+            initial_margin: TAKER_MARGIN,
+            order_size: ORDER_SIZE,
+            maker_order_id: String::new()
+        };
+        let res = harness.post("/taker/order", serde_json::to_vec(&order).unwrap()).await;
+        assert_eq!(res.status(), 200);
+        let taker: TakerView = serde_json::from_slice(res.body()).unwrap();
+        assert!(taker.established);
+        harness.assert_balances(TAKER_MARGIN, ORDER_SIZE).await;
+        harness
+    }
+
+    // Customer public key both sides know the channel by
+    fn channel_id(&self) -> String {
+        let taker = self.state.taker.lock().unwrap();
+        taker.as_ref().unwrap().channel_token.pk_c.unwrap().to_string()
+    }
+
+    async fn channel(&self, side: &str) -> ChannelView {
+        let res = self.get(&format!("/channels/{}", self.channel_id())).await;
+        assert_eq!(res.status(), 200, "no channel {}", self.channel_id());
+        let views: Vec<ChannelView> = serde_json::from_slice(res.body()).unwrap();
+        let side = if side == "taker" { Side::Taker } else { Side::Maker };
+        views.into_iter().find(|view| view.side == side).unwrap_or_else(|| panic!("no {:?} side", side))
+    }
+
+    async fn channels(&self) -> Vec<ChannelView> {
+        let res = self.get("/channels").await;
+        assert_eq!(res.status(), 200);
+        serde_json::from_slice(res.body()).unwrap()
+    }
+
+    // Both sides agree on the balances and neither has a payment in flight
+    async fn assert_balances(&self, taker_balance: i64, maker_balance: i64) {
+        for side in &["taker", "maker"] {
+            let channel = self.channel(side).await;
+            assert_eq!(channel.phase, ChannelPhase::Open, "{} channel is not open", side);
+            assert_eq!(channel.taker_balance, taker_balance, "{} sees the wrong taker balance", side);
+            assert_eq!(channel.maker_balance, maker_balance, "{} sees the wrong maker balance", side);
+        }
+    }
+
+    // Payment proof built by the taker without sending it, as a counterparty might
+    fn payment_req(&self) -> PaymentRequest {
+        let mut taker = self.state.taker.lock().unwrap();
+        taker.as_mut().unwrap().send_payment_req(&mut rand::thread_rng()).expect("an epoch is unsettled")
+    }
+
+    // The maker answers 400 with the reason, and nothing is left in flight
+    async fn refused(&self, path: &str, body: Vec<u8>, reason: &str) -> serde_json::Value {
+        let res = self.post(path, body).await;
+        assert_eq!(res.status(), 400, "{} was not refused", path);
+        let rejection: serde_json::Value = serde_json::from_slice(res.body()).unwrap();
+        assert_eq!(rejection["reason"], reason);
+        assert_eq!(self.state.maker.lock().unwrap().as_ref().unwrap().pending_epoch, None);
+        rejection
+    }
+
+    fn maker(&self) -> MakerView {
+        let maker = self.state.maker.lock().unwrap();
+        MakerView::from(maker.as_ref().unwrap())
+    }
+}
+
+#[tokio::test]
+async fn open_pay_and_close() {
+    let harness = Harness::open().await;
+    assert_eq!(harness.maker().available_margin, MAKER_MARGIN - ORDER_SIZE);
+
+    // The first price is the reference, the second moves 10%
+    harness.price(8000).await;
+    harness.price(8800).await;
+    harness.assert_balances(TAKER_MARGIN, ORDER_SIZE).await;
+    assert_eq!(harness.channel("taker").await.last_settled_epoch, 1);
+    // Channels are looked up by their customer public key
+    assert_eq!(harness.get("/channels/taker").await.status(), 404);
+
+    // Taker pays the maker
+    let res = harness.post("/taker/pay", Vec::new()).await;
+    assert_eq!(res.status(), 200);
+    let taker: TakerView = serde_json::from_slice(res.body()).unwrap();
+    assert_eq!(taker.last_settled_epoch, 2);
+    harness.assert_balances(TAKER_MARGIN - 100, ORDER_SIZE + 100).await;
+    assert_eq!(harness.maker().net_payments, 100);
+
+    // Down 5%, the maker pays back half
+    harness.price(8360).await;
+    let res = harness.post("/taker/pay", Vec::new()).await;
+    assert_eq!(res.status(), 200);
+    harness.assert_balances(TAKER_MARGIN - 50, ORDER_SIZE + 50).await;
+    assert_eq!(harness.channel("maker").await.last_settled_epoch, 3);
+
+    let res = harness.post("/taker/close", Vec::new()).await;
+    assert_eq!(res.status(), 200);
+    let taker: TakerView = serde_json::from_slice(res.body()).unwrap();
+    assert!(taker.closed);
+    assert_eq!(taker.available_margin, TAKER_MARGIN - 50);
+    assert_eq!(harness.channel("taker").await.phase, ChannelPhase::Closed);
+    // The maker's collateral and winnings are free again, only the taker's closed side is left
+    let channels = harness.channels().await;
+    assert_eq!(channels.len(), 1);
+    assert_eq!(channels[0].side, Side::Taker);
+    assert_eq!(harness.maker().available_margin, MAKER_MARGIN + 50);
+}
+
+#[tokio::test]
+async fn maker_refuses_a_stale_or_repeated_close() {
+    let harness = Harness::open().await;
+    let stale = {
+        let taker = harness.state.taker.lock().unwrap();
+        serde_json::to_vec(&taker.as_ref().unwrap().send_close_channel_req().unwrap()).unwrap()
+    };
+    harness.price(8000).await;
+    harness.price(8800).await;
+    assert_eq!(harness.post("/taker/pay", Vec::new()).await.status(), 200);
+
+    // The opening state was revoked by the payment, the channel stays open on the latest one
+    harness.refused("/maker/close", stale, "RevokedClose").await;
+    harness.assert_balances(TAKER_MARGIN - 100, ORDER_SIZE + 100).await;
+
+    let latest = {
+        let taker = harness.state.taker.lock().unwrap();
+        serde_json::to_vec(&taker.as_ref().unwrap().send_close_channel_req().unwrap()).unwrap()
+    };
+    assert_eq!(harness.post("/maker/close", latest.clone()).await.status(), 200);
+    assert_eq!(harness.maker().available_margin, MAKER_MARGIN + 100);
+    // Closing again would hand the collateral back twice
+    harness.refused("/maker/close", latest, "NoChannel").await;
+    assert_eq!(harness.maker().available_margin, MAKER_MARGIN + 100);
+}
+
+#[tokio::test]
+async fn close_waits_for_the_payment_in_flight() {
+    let harness = Harness::open().await;
+    harness.price(8000).await;
+    harness.price(8800).await;
+    // The round stops after the maker's close token, before the revoke token is sent
+    let res = harness.post("/maker/recvPay", serde_json::to_vec(&harness.payment_req()).unwrap()).await;
+    assert_eq!(res.status(), 200);
+    let res: PaymentResponse = serde_json::from_slice(res.body()).unwrap();
+    harness.state.taker.lock().unwrap().as_mut().unwrap().recv_payment_res(res).expect("close token verifies");
+
+    let res = harness.post("/taker/close", Vec::new()).await;
+    assert_eq!(res.status(), 409);
+    assert!(String::from_utf8_lossy(res.body()).contains("payment is in flight"));
+    assert!(!harness.state.taker.is_poisoned());
+
+    // Paying resumes the round from the revoke step, then the channel closes
+    assert_eq!(harness.post("/taker/pay", Vec::new()).await.status(), 200);
+    harness.assert_balances(TAKER_MARGIN - 100, ORDER_SIZE + 100).await;
+    assert_eq!(harness.post("/taker/close", Vec::new()).await.status(), 200);
+    assert_eq!(harness.maker().available_margin, MAKER_MARGIN + 100);
+}
+
+#[tokio::test]
+async fn catches_up_on_missed_epochs() {
+    let harness = Harness::open().await;
+    harness.price(8000).await;
+    harness.price(8800).await;
+    harness.price(8360).await;
+
+    // One round per epoch, the taker settles the first and the second is still owed
+    let res = harness.post("/taker/pay", Vec::new()).await;
+    assert_eq!(res.status(), 200);
+    harness.assert_balances(TAKER_MARGIN - 100, ORDER_SIZE + 100).await;
+    let res = harness.post("/taker/pay", Vec::new()).await;
+    assert_eq!(res.status(), 200);
+    harness.assert_balances(TAKER_MARGIN - 50, ORDER_SIZE + 50).await;
+}
+
+#[tokio::test]
+async fn rejects_orders_beyond_the_policy() {
+    let harness = Harness::new();
+    harness.post(&format!("/maker/init/{}", MAKER_MARGIN), Vec::new()).await;
+
+    // 1000x leverage against the default maximum of 100x
+    let order = OrderRequest {
+        initial_margin: 1,
+        order_size: ORDER_SIZE,
+        maker_order_id: String::new()
+    };
+    let res = harness.post("/taker/order", serde_json::to_vec(&order).unwrap()).await;
+    assert_eq!(res.status(), 400);
+    let rejection: serde_json::Value = serde_json::from_slice(res.body()).unwrap();
+    assert_eq!(rejection["reason"], "LeverageTooHigh");
+
+    // Nothing was reserved, and the taker can order again
+    assert!(harness.channels().await.is_empty());
+    assert_eq!(harness.maker().available_margin, MAKER_MARGIN);
+}
+
+#[tokio::test]
+async fn refuses_a_second_order_while_the_channel_is_open() {
+    let harness = Harness::open().await;
+    let order = OrderRequest {
+        initial_margin: TAKER_MARGIN,
+        order_size: ORDER_SIZE,
+        maker_order_id: String::new()
+    };
+    let res = harness.post("/taker/order", serde_json::to_vec(&order).unwrap()).await;
+    assert_eq!(res.status(), 409);
+
+    // The live channel is untouched and still settles
+    harness.assert_balances(TAKER_MARGIN, ORDER_SIZE).await;
+    harness.price(8000).await;
+    harness.price(8800).await;
+    assert_eq!(harness.post("/taker/pay", Vec::new()).await.status(), 200);
+    harness.assert_balances(TAKER_MARGIN - 100, ORDER_SIZE + 100).await;
+}
+
+#[tokio::test]
+async fn maker_refuses_to_reopen_or_re_establish_a_live_channel() {
+    let harness = Harness::open().await;
+    let (open, established) = {
+        let maker = harness.state.maker.lock().unwrap();
+        let maker = maker.as_ref().unwrap();
+        let other = TakerState::init(
+            &mut rand::thread_rng(),
+            system_clock(),
+            TAKER_MARGIN,
+            maker.quote_collateral(ORDER_SIZE),
+            ORDER_SIZE,
+            maker.channel_state.clone(),
+            maker.channel_token.clone()
+        );
+        (serde_json::to_vec(&other.send_open_channel_req()).unwrap(), serde_json::to_vec(&other.send_channel_established_req()).unwrap())
+    };
+    harness.refused("/maker/openChannel", open, "ChannelOpen").await;
+    harness.refused("/maker/established", established, "UnknownChannel").await;
+
+    // Established once already, so its collateral is not unlocked twice
+    let again = serde_json::to_vec(&harness.state.taker.lock().unwrap().as_ref().unwrap().send_channel_established_req()).unwrap();
+    harness.refused("/maker/established", again, "UnknownChannel").await;
+    assert!(!harness.state.maker.is_poisoned());
+    let maker = harness.maker();
+    assert_eq!((maker.available_margin, maker.reserved_margin), (MAKER_MARGIN - ORDER_SIZE, 0));
+
+    // The live channel is untouched and still settles
+    harness.assert_balances(TAKER_MARGIN, ORDER_SIZE).await;
+    harness.price(8000).await;
+    harness.price(8800).await;
+    assert_eq!(harness.post("/taker/pay", Vec::new()).await.status(), 200);
+    harness.assert_balances(TAKER_MARGIN - 100, ORDER_SIZE + 100).await;
+}
+
+#[tokio::test]
+async fn taker_refuses_a_settlement_request_for_the_wrong_amount() {
+    let harness = Harness::open().await;
+    harness.price(8000).await;
+    harness.price(8800).await;
+    let req = SettlementRequest { from_epoch: 1, to_epoch: 2, amount: -100, correlation_id: String::new() };
+    let res = harness.post("/taker/settle", serde_json::to_vec(&req).unwrap()).await;
+    assert_eq!(res.status(), 400);
+    let rejection: serde_json::Value = serde_json::from_slice(res.body()).unwrap();
+    assert_eq!(rejection["reason"], "WrongAmount");
+
+    let req = SettlementRequest { from_epoch: 0, to_epoch: 2, amount: 100, correlation_id: String::new() };
+    let res = harness.post("/taker/settle", serde_json::to_vec(&req).unwrap()).await;
+    assert_eq!(res.status(), 400);
+    let rejection: serde_json::Value = serde_json::from_slice(res.body()).unwrap();
+    assert_eq!(rejection["reason"], "UnexpectedEpochs");
+
+    // The taker still settles what it really owes
+    assert_eq!(harness.post("/taker/pay", Vec::new()).await.status(), 200);
+    harness.assert_balances(TAKER_MARGIN - 100, ORDER_SIZE + 100).await;
+}
+
+#[tokio::test]
+async fn routes_without_a_channel_are_not_found() {
+    let harness = Harness::new();
+    assert_eq!(harness.post("/maker/collateral/100", Vec::new()).await.status(), 404);
+    assert_eq!(harness.post("/maker/settle", Vec::new()).await.status(), 404);
+    assert_eq!(harness.post("/taker/pay", Vec::new()).await.status(), 404);
+    assert_eq!(harness.post("/taker/close", Vec::new()).await.status(), 404);
+    assert_eq!(harness.get("/channels/taker").await.status(), 404);
+    let order = OrderRequest {
+        initial_margin: TAKER_MARGIN,
+        order_size: ORDER_SIZE,
+        maker_order_id: String::new()
+    };
+    assert_eq!(harness.post("/taker/order", serde_json::to_vec(&order).unwrap()).await.status(), 404);
+}
+
+#[tokio::test]
+async fn counterparty_failures_are_bad_gateway() {
+    let harness = Harness::open().await;
+    harness.price(8000).await;
+    harness.price(8800).await;
+    // The counterparty loses its maker, so every request fails on its side
+    let maker = harness.state.maker.lock().unwrap().take();
+    assert_eq!(harness.post("/taker/pay", Vec::new()).await.status(), 502);
+
+    // and the taker is still there to settle once it is back
+    *harness.state.maker.lock().unwrap() = maker;
+    assert_eq!(harness.post("/taker/pay", Vec::new()).await.status(), 200);
+    harness.assert_balances(TAKER_MARGIN - 100, ORDER_SIZE + 100).await;
+}
+
+#[tokio::test]
+async fn taker_refuses_answers_the_maker_tampered_with() {
+    // Collateral other than the balance the taker committed to
+    let harness = Harness::tampered(|res| match res {
+        PeerMessage::OpenChannelResponse(mut res) => {
+            res.maker_margin += 1;
+            PeerMessage::OpenChannelResponse(res)
+        },
+        res => res
+    });
+    assert_eq!(harness.post(&format!("/maker/init/{}", MAKER_MARGIN), Vec::new()).await.status(), 200);
+    let order = OrderRequest { initial_margin: TAKER_MARGIN, order_size: ORDER_SIZE, maker_order_id: String::new() };
+    let res = harness.post("/taker/order", serde_json::to_vec(&order).unwrap()).await;
+    assert_eq!(res.status(), 400);
+    let rejection: serde_json::Value = serde_json::from_slice(res.body()).unwrap();
+    assert_eq!(rejection["reason"], "CollateralMismatch");
+    assert!(!harness.state.taker.is_poisoned());
+    assert!(harness.state.taker.lock().unwrap().is_none());
+
+    // A payment answered with the close token of the opening wallet
+    let mut opening_close_token = None;
+    let harness = Harness::tampered(move |res| match res {
+        PeerMessage::OpenChannelResponse(res) => {
+            opening_close_token = Some(res.close_token.clone());
+            PeerMessage::OpenChannelResponse(res)
+        },
+        PeerMessage::PaymentResponse(mut res) => {
+            res.close_token = opening_close_token.clone().unwrap();
+            PeerMessage::PaymentResponse(res)
+        },
+        res => res
+    }).opened().await;
+    harness.price(8000).await;
+    harness.price(8800).await;
+    assert_eq!(harness.post("/taker/pay", Vec::new()).await.status(), 502);
+    assert!(!harness.state.taker.is_poisoned());
+    assert!(harness.state.taker.lock().unwrap().as_ref().unwrap().revoke_token.is_none());
+    let taker = harness.channel("taker").await;
+    assert_eq!((taker.taker_balance, taker.maker_balance), (TAKER_MARGIN, ORDER_SIZE));
+}
+
+#[tokio::test]
+async fn concurrent_payments_settle_an_epoch_once() {
+    let harness = Harness::open().await;
+    harness.price(8000).await;
+    harness.price(8800).await;
+    // The second round waits for the first, then finds nothing left to settle
+    let (first, second) = futures03::future::join(
+        harness.post("/taker/pay", Vec::new()),
+        harness.post("/taker/pay", Vec::new())
+    ).await;
+    let mut statuses = vec![first.status().as_u16(), second.status().as_u16()];
+    statuses.sort();
+    assert_eq!(statuses, vec![200, 409]);
+    assert!(!harness.state.taker.is_poisoned());
+    harness.assert_balances(TAKER_MARGIN - 100, ORDER_SIZE + 100).await;
+}
+
+#[tokio::test]
+async fn maker_has_nothing_to_settle_without_market_data() {
+    let harness = Harness::open().await;
+    let res = harness.post("/maker/settle", Vec::new()).await;
+    assert_eq!(res.status(), 200);
+    assert_eq!(res.body().as_ref(), b"false");
+    harness.assert_balances(TAKER_MARGIN, ORDER_SIZE).await;
+}
+
+#[tokio::test]
+async fn taker_cannot_pay_without_market_data() {
+    let harness = Harness::open().await;
+    harness.price(8000).await;
+    harness.price(8800).await;
+    // The taker lost the reference price of the unsettled epoch
+    harness.state.taker.lock().unwrap().as_mut().unwrap().price_history.prices.remove(&1);
+    let res = harness.post("/taker/pay", Vec::new()).await;
+    assert_eq!(res.status(), 409);
+    assert!(String::from_utf8_lossy(res.body()).contains("Nothing to settle"));
+    harness.assert_balances(TAKER_MARGIN, ORDER_SIZE).await;
+
+    // With the price back it pays as usual
+    harness.state.taker.lock().unwrap().as_mut().unwrap().price_history.record(1, market_data(8000));
+    assert_eq!(harness.post("/taker/pay", Vec::new()).await.status(), 200);
+    harness.assert_balances(TAKER_MARGIN - 100, ORDER_SIZE + 100).await;
+}
+
+#[tokio::test]
+async fn maker_refuses_payment_for_an_epoch_it_has_no_price_for() {
+    let harness = Harness::open().await;
+    harness.price(8000).await;
+    harness.price(8800).await;
+    // Only the taker saw the third epoch
+    {
+        let mut taker = harness.state.taker.lock().unwrap();
+        let taker = taker.as_mut().unwrap();
+        taker.record_market_data(3, market_data(8360));
+        taker.catch_up = CatchUpMode::Netted;
+    }
+    let req = harness.payment_req();
+    harness.refused("/maker/recvPay", serde_json::to_vec(&req).unwrap(), "UnexpectedEpochs").await;
+
+    // Once the maker sees the epoch too, the netted payment goes through
+    harness.price(8360).await;
+    assert_eq!(harness.post("/taker/pay", Vec::new()).await.status(), 200);
+    harness.assert_balances(TAKER_MARGIN - 50, ORDER_SIZE + 50).await;
+}
+
+#[tokio::test]
+async fn maker_refuses_the_wrong_amount() {
+    let harness = Harness::open().await;
+    harness.price(8000).await;
+    harness.price(8800).await;
+    // The taker believes the price moved twice as far
+    harness.state.taker.lock().unwrap().as_mut().unwrap().price_history.record(2, market_data(9600));
+    let req = harness.payment_req();
+    assert_eq!(req.payment_proof.amount, 200);
+    let rejection = harness.refused("/maker/recvPay", serde_json::to_vec(&req).unwrap(), "WrongAmount").await;
+    assert_eq!(rejection["expected"], 100);
+    assert_eq!(rejection["received"], 200);
+
+    // Nothing was settled, and the corrected payment goes through
+    harness.state.taker.lock().unwrap().as_mut().unwrap().price_history.record(2, market_data(8800));
+    assert_eq!(harness.post("/taker/pay", Vec::new()).await.status(), 200);
+    harness.assert_balances(TAKER_MARGIN - 100, ORDER_SIZE + 100).await;
+}
+
+#[tokio::test]
+async fn maker_refuses_a_proof_for_another_channel() {
+    let harness = Harness::open().await;
+    let other = Harness::open().await;
+    for bitcoin in &[8000, 8800] {
+        harness.price(*bitcoin).await;
+        other.price(*bitcoin).await;
+    }
+    // Same epochs and amount, but proven against the other channel's wallet
+    let req = other.payment_req();
+    assert_eq!(req.payment_proof.amount, 100);
+    harness.refused("/maker/recvPay", serde_json::to_vec(&req).unwrap(), "InvalidProof").await;
+
+    // The maker is unharmed and takes the channel's own proof
+    assert_eq!(harness.post("/taker/pay", Vec::new()).await.status(), 200);
+    harness.assert_balances(TAKER_MARGIN - 100, ORDER_SIZE + 100).await;
+}
+
+#[tokio::test]
+async fn maker_refuses_a_replayed_revoke_token() {
+    let harness = Harness::open().await;
+    harness.price(8000).await;
+    harness.price(8800).await;
+
+    // First round by hand, keeping the revoke token
+    let res = harness.post("/maker/recvPay", serde_json::to_vec(&harness.payment_req()).unwrap()).await;
+    assert_eq!(res.status(), 200);
+    let res: PaymentResponse = serde_json::from_slice(res.body()).unwrap();
+    let revoke = {
+        let mut taker = harness.state.taker.lock().unwrap();
+        let taker = taker.as_mut().unwrap();
+        taker.recv_payment_res(res).expect("close token verifies");
+        serde_json::to_vec(&taker.send_generate_payment_token_req()).unwrap()
+    };
+    let res = harness.post("/maker/paymentToken", revoke.clone()).await;
+    assert_eq!(res.status(), 200);
+    let res: GeneratePaymentTokenResponse = serde_json::from_slice(res.body()).unwrap();
+    harness.state.taker.lock().unwrap().as_mut().unwrap().recv_generate_payment_token_res(res).expect("pay token verifies");
+    harness.assert_balances(TAKER_MARGIN - 100, ORDER_SIZE + 100).await;
+
+    // Against the next payment it would settle without revoking the spent wallet
+    harness.price(8360).await;
+    let res = harness.post("/maker/recvPay", serde_json::to_vec(&harness.payment_req()).unwrap()).await;
+    assert_eq!(res.status(), 200);
+    let res: PaymentResponse = serde_json::from_slice(res.body()).unwrap();
+    let replayed = harness.post("/maker/paymentToken", revoke).await;
+    assert_eq!(replayed.status(), 400);
+    let rejection: serde_json::Value = serde_json::from_slice(replayed.body()).unwrap();
+    assert_eq!(rejection["reason"], "RevokeTokenReused");
+    assert_eq!(harness.maker().net_payments, 100);
+
+    // The payment is still pending, and the fresh revoke token settles it
+    let revoke = {
+        let mut taker = harness.state.taker.lock().unwrap();
+        let taker = taker.as_mut().unwrap();
+        taker.recv_payment_res(res).expect("close token verifies");
+        serde_json::to_vec(&taker.send_generate_payment_token_req()).unwrap()
+    };
+    let res = harness.post("/maker/paymentToken", revoke).await;
+    assert_eq!(res.status(), 200);
+    let res: GeneratePaymentTokenResponse = serde_json::from_slice(res.body()).unwrap();
+    harness.state.taker.lock().unwrap().as_mut().unwrap().recv_generate_payment_token_res(res).expect("pay token verifies");
+    harness.assert_balances(TAKER_MARGIN - 50, ORDER_SIZE + 50).await;
+}
+
+#[tokio::test]
+async fn maker_refuses_a_revoke_token_replayed_after_its_round() {
+    let harness = Harness::open().await;
+    harness.price(8000).await;
+    harness.price(8800).await;
+    let res = harness.post("/maker/recvPay", serde_json::to_vec(&harness.payment_req()).unwrap()).await;
+    assert_eq!(res.status(), 200);
+    let res: PaymentResponse = serde_json::from_slice(res.body()).unwrap();
+    let revoke = {
+        let mut taker = harness.state.taker.lock().unwrap();
+        let taker = taker.as_mut().unwrap();
+        taker.recv_payment_res(res).expect("close token verifies");
+        serde_json::to_vec(&taker.send_generate_payment_token_req()).unwrap()
+    };
+    let res = harness.post("/maker/paymentToken", revoke.clone()).await;
+    assert_eq!(res.status(), 200);
+    let res: GeneratePaymentTokenResponse = serde_json::from_slice(res.body()).unwrap();
+    harness.state.taker.lock().unwrap().as_mut().unwrap().recv_generate_payment_token_res(res).expect("pay token verifies");
+
+    // Nothing is pending, and the replay still issues nothing
+    let mut events = harness.state.events.subscribe();
+    harness.refused("/maker/paymentToken", revoke, "RevokeTokenReused").await;
+    assert_eq!(harness.maker().net_payments, 100);
+    harness.price(8360).await;
+    assert_eq!(events.recv().await.unwrap().name(), "price_epoch_closed");
+    harness.assert_balances(TAKER_MARGIN - 100, ORDER_SIZE + 100).await;
+}
+
+#[tokio::test]
+async fn settles_with_collateral_margin_and_size_all_different() {
+    let harness = Harness::new();
+    assert_eq!(harness.post(&format!("/maker/init/{}", MAKER_MARGIN), Vec::new()).await.status(), 200);
+    assert_eq!(harness.post("/maker/collateral/800", Vec::new()).await.status(), 200);
+    let order = OrderRequest { initial_margin: 1200, order_size: 1000, maker_order_id: String::new() };
+    assert_eq!(harness.post("/taker/order", serde_json::to_vec(&order).unwrap()).await.status(), 200);
+    harness.assert_balances(1200, 800).await;
+    assert_eq!(harness.maker().available_margin, MAKER_MARGIN - 800);
+
+    // Payments follow the order size, not either side's margin
+    harness.price(8000).await;
+    harness.price(8800).await;
+    assert_eq!(harness.post("/taker/pay", Vec::new()).await.status(), 200);
+    harness.assert_balances(1100, 900).await;
+    let margin = harness.channel("maker").await.margin;
+    assert_eq!((margin.taker_margin, margin.maker_margin, margin.order_size), (Some(1200), Some(800), Some(1000)));
+
+    assert_eq!(harness.post("/taker/close", Vec::new()).await.status(), 200);
+    assert_eq!(harness.maker().available_margin, MAKER_MARGIN + 100);
+}
+
+#[tokio::test]
+async fn rejects_orders_the_policy_or_liquidity_does_not_admit() {
+    let harness = Harness::new();
+    assert_eq!(harness.post(&format!("/maker/init/{}", MAKER_MARGIN), Vec::new()).await.status(), 200);
+    let policy = AdmissionPolicy {
+        min_order_size: 500,
+        max_order_size: 5000,
+        max_leverage: 5,
+        margin_requirement_bps: 1000,
+        max_exposure_per_identity: 800,
+    };
+    assert_eq!(harness.post("/maker/policy", serde_json::to_vec(&policy).unwrap()).await.status(), 200);
+
+    let order = |initial_margin, order_size| serde_json::to_vec(&OrderRequest { initial_margin, order_size, maker_order_id: String::new() }).unwrap();
+    let rejection = harness.refused("/taker/order", order(TAKER_MARGIN, 100), "OrderTooSmall").await;
+    assert_eq!(rejection["min_order_size"], 500);
+    let rejection = harness.refused("/taker/order", order(TAKER_MARGIN, ORDER_SIZE), "ExposureLimit").await;
+    assert_eq!(rejection["max_exposure"], 800);
+    assert!(harness.channels().await.is_empty());
+
+    // Collateral the maker does not have, or did not quote, straight from a counterparty
+    let forged = |maker_margin| {
+        let maker = harness.state.maker.lock().unwrap();
+        let maker = maker.as_ref().unwrap();
+        let taker = TakerState::init(
+            &mut rand::thread_rng(),
+            system_clock(),
+            TAKER_MARGIN,
+            maker_margin,
+            700,
+            maker.channel_state.clone(),
+            maker.channel_token.clone()
+        );
+        serde_json::to_vec(&taker.send_open_channel_req()).unwrap()
+    };
+    let rejection = harness.refused("/maker/openChannel", forged(MAKER_MARGIN + 1), "InsufficientLiquidity").await;
+    assert_eq!(rejection["available_margin"], MAKER_MARGIN);
+    let rejection = harness.refused("/maker/openChannel", forged(300), "CollateralMismatch").await;
+    assert_eq!(rejection["quoted"], 700);
+    let maker = harness.maker();
+    assert_eq!((maker.available_margin, maker.reserved_margin), (MAKER_MARGIN, 0));
+
+    // An order within every limit still opens
+    assert_eq!(harness.post("/taker/order", order(TAKER_MARGIN, 700)).await.status(), 200);
+    harness.assert_balances(TAKER_MARGIN, 700).await;
+}
@@ -0,0 +1,55 @@
+// Live subscribers miss what they fall behind on, webhook delivery and metrics get every event
+use rainboltd::{
+    events::{Event, EventBus},
+    metrics::run_metrics,
+    settlement::Side,
+};
+
+#[tokio::test]
+async fn unbounded_subscribers_get_every_event() {
+    let bus = EventBus::default();
+    let mut live = bus.subscribe();
+    let mut webhooks = bus.subscribe_unbounded();
+    for epoch in 0..100 {
+        bus.publish(Event::RevokeTokenAccepted { side: Side::Maker, channel: String::new(), epoch });
+    }
+    // Hanging up the bus ends both streams once they are drained
+    drop(bus);
+
+    let mut received = 0;
+    while live.recv().await.is_some() {
+        received += 1;
+    }
+    assert_eq!(received, 64);
+
+    let mut epochs = Vec::new();
+    while let Some(event) = webhooks.recv().await {
+        match event {
+            Event::RevokeTokenAccepted { epoch, .. } => epochs.push(epoch),
+            _ => panic!("unexpected event")
+        }
+    }
+    assert_eq!(epochs, (0..100).collect::<Vec<_>>());
+}
+
+#[tokio::test]
+async fn metrics_count_every_event() {
+    let bus = EventBus::default();
+    let events = bus.subscribe_unbounded();
+    for epoch in 0..100 {
+        bus.publish(Event::PayTokenIssued { side: Side::Taker, channel: String::new(), epoch });
+    }
+    drop(bus);
+    run_metrics(events).await;
+
+    let settlements = prometheus::gather()
+        .into_iter()
+        .find(|family| family.get_name() == "rainbolt_settlements_total")
+        .expect("settlement counter is registered");
+    let taker = settlements
+        .get_metric()
+        .iter()
+        .find(|metric| metric.get_label().iter().any(|label| label.get_name() == "side" && label.get_value() == "taker"))
+        .expect("taker settlements are counted");
+    assert_eq!(taker.get_counter().get_value() as u64, 100);
+}
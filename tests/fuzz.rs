@@ -0,0 +1,396 @@
+// Request bodies come straight from untrusted peers, so no body may panic the daemon while it is decoded
+use lazy_static::lazy_static;
+use proptest::prelude::*;
+use rand::{SeedableRng, rngs::StdRng};
+use secp256k1::SecretKey;
+use serde::{Serialize, de::DeserializeOwned};
+use serde_json::{json, Value};
+use std::sync::Arc;
+use warp::Filter;
+
+use rainboltd::{
+    admission::{AdmissionPolicy, OrderRejection},
+    api::{handle_rejection, maker_routes, market_routes},
+    clock::system_clock,
+    driver::handle_maker_slot_message,
+    history::CatchUpMode,
+    maker::{Maker, MakerState},
+    message::*,
+    peer::PeerMessage,
+    settlement::PaymentRejection,
+    state::DaemonState,
+    taker::{Taker, TakerState},
+    transport::InMemoryTransport,
+    MarketData,
+    MarketPrice
+};
+
+fn market_data(bitcoin: i64, cosmos: i64) -> MarketData {
+    MarketData {
+        bitcoin: MarketPrice { usd: bitcoin },
+        cosmos: MarketPrice { usd: cosmos }
+    }
+}
+
+// MakerState is not Clone, but survives a round trip through JSON
+fn copy<T: Serialize + DeserializeOwned>(state: &T) -> T {
+    serde_json::from_value(serde_json::to_value(state).unwrap()).unwrap()
+}
+
+struct Corpus {
+    maker: MakerState, // Channel open with one settled payment
+    taker: TakerState,
+    messages: Vec<(&'static str, Value)>, // Every message of the channel, named after its PeerMessage variant
+    makers: Vec<(&'static str, MakerState)>, // The maker as it was when each of its requests arrived
+}
+
+// One channel run through every protocol message, with the proofs and tokens libbolt really produces
+fn corpus() -> Corpus {
+    let mut rng = StdRng::seed_from_u64(7);
+    let mut messages = Vec::new();
+    let mut record = |name, msg: Value| messages.push((name, msg));
+    let mut makers = Vec::new();
+
+    let mut maker = MakerState::init(&mut rng, system_clock(), 5000);
+    let mut taker = TakerState::init(
+        &mut rng,
+        system_clock(),
+        1000,
+        maker.quote_collateral(1000),
+        1000,
+        maker.channel_state.clone(),
+        maker.channel_token.clone()
+    );
+    let req = taker.send_open_channel_req();
+    record("OpenChannelRequest", json!(req));
+    makers.push(("OpenChannelRequest", copy(&maker)));
+    let res = maker.recv_open_channel_req(&mut rng, req).expect("order is within the policy");
+    record("OpenChannelResponse", json!(res));
+    taker.recv_open_channel_res(res).expect("maker tokens verify");
+    let req = taker.send_channel_established_req();
+    record("ChannelEstablishedRequest", json!(req));
+    makers.push(("ChannelEstablishedRequest", copy(&maker)));
+    maker.recv_channel_established(req).expect("channel is pending");
+
+    for (epoch, bitcoin) in [8000, 8800].iter().enumerate() {
+        maker.record_market_data(epoch as u64 + 1, market_data(*bitcoin, 4));
+        taker.record_market_data(epoch as u64 + 1, market_data(*bitcoin, 4));
+    }
+    let req = maker.send_settlement_req().expect("an epoch is unsettled");
+    record("SettlementRequest", json!(req));
+    let req = taker.send_payment_req(&mut rng).expect("an epoch is unsettled");
+    record("PaymentRequest", json!(req));
+    makers.push(("PaymentRequest", copy(&maker)));
+    let res = maker.recv_payment_req(&mut rng, req).expect("payment is for the unsettled epochs");
+    record("PaymentResponse", json!(res));
+    taker.recv_payment_res(res).expect("close token verifies");
+    let req = taker.send_generate_payment_token_req();
+    record("GeneratePaymentTokenRequest", json!(req));
+    makers.push(("GeneratePaymentTokenRequest", copy(&maker)));
+    let res = maker.recv_generate_payment_token_req(req).expect("revoke token is for the pending payment");
+    record("GeneratePaymentTokenResponse", json!(res));
+    taker.recv_generate_payment_token_res(res).expect("pay token verifies");
+
+    // Close a copy, so the corpus keeps an open channel
+    let req = taker.send_close_channel_req().expect("no payment is in flight");
+    record("CloseChannelRequest", json!(req));
+    makers.push(("CloseChannelRequest", copy(&maker)));
+    let res = copy(&maker).recv_close_channel_req(req).expect("close is on the latest state");
+    record("CloseChannelResponse", json!(res));
+    record("OrderRejection", json!(OrderRejection::ChannelPending));
+    record("PaymentRejection", json!(PaymentRejection::RevokeTokenReused));
+
+    Corpus { maker, taker, messages, makers }
+}
+
+lazy_static! {
+    static ref CORPUS: Corpus = corpus();
+}
+
+// Valid bodies as the routes receive them, and as the peer transport frames them
+fn seeds() -> Vec<Vec<u8>> {
+    CORPUS.messages.iter().flat_map(|(name, body)| {
+        let framed = json!({ "type": name, "body": body });
+        vec![serde_json::to_vec(body).unwrap(), serde_json::to_vec(&framed).unwrap()]
+    }).collect()
+}
+
+// Every type a route or the peer transport decodes from the wire. Only panics matter, errors are expected.
+fn decode_all(body: &[u8]) {
+    let _ = serde_json::from_slice::<OrderRequest>(body);
+    let _ = serde_json::from_slice::<OpenChannelRequest>(body);
+    let _ = serde_json::from_slice::<OpenChannelResponse>(body);
+    let _ = serde_json::from_slice::<ChannelEstablishedRequest>(body);
+    let _ = serde_json::from_slice::<SettlementRequest>(body);
+    let _ = serde_json::from_slice::<PaymentRequest>(body);
+    let _ = serde_json::from_slice::<PaymentResponse>(body);
+    let _ = serde_json::from_slice::<GeneratePaymentTokenRequest>(body);
+    let _ = serde_json::from_slice::<GeneratePaymentTokenResponse>(body);
+    let _ = serde_json::from_slice::<CloseChannelRequest>(body);
+    let _ = serde_json::from_slice::<CloseChannelResponse>(body);
+    let _ = serde_json::from_slice::<OrderRejection>(body);
+    let _ = serde_json::from_slice::<PeerMessage>(body);
+    let _ = serde_json::from_slice::<MarketData>(body);
+    let _ = serde_json::from_slice::<AdmissionPolicy>(body);
+    let _ = serde_json::from_slice::<CatchUpMode>(body);
+}
+
+fn leaves_mut<'a>(value: &'a mut Value, out: &mut Vec<&'a mut Value>) {
+    match value {
+        Value::Array(values) => values.iter_mut().for_each(|value| leaves_mut(value, out)),
+        Value::Object(fields) => fields.values_mut().for_each(|value| leaves_mut(value, out)),
+        leaf => out.push(leaf)
+    }
+}
+
+// Keeps the shape of a valid message, so the values reach the curve point and signature decoders
+fn replace_leaf(body: &[u8], index: usize, replacement: Value) -> Vec<u8> {
+    let mut value: Value = serde_json::from_slice(body).unwrap();
+    {
+        let mut leaves = Vec::new();
+        leaves_mut(&mut value, &mut leaves);
+        if !leaves.is_empty() {
+            let count = leaves.len();
+            *leaves.swap_remove(index % count) = replacement;
+        }
+    }
+    serde_json::to_vec(&value).unwrap()
+}
+
+fn leaf() -> impl Strategy<Value = Value> {
+    prop_oneof![
+        any::<u8>().prop_map(Value::from),
+        any::<i64>().prop_map(Value::from),
+        any::<u64>().prop_map(Value::from),
+        any::<f64>().prop_map(Value::from),
+        ".{0,64}".prop_map(Value::from),
+        any::<bool>().prop_map(Value::from),
+        Just(Value::Null),
+        Just(json!([])),
+        Just(json!({}))
+    ]
+}
+
+fn post_market_data(state: &DaemonState, body: Vec<u8>) -> u16 {
+    let routes = market_routes(state.clone()).recover(handle_rejection);
+    let res = futures03::executor::block_on(
+        warp::test::request()
+            .method("POST")
+            .path("/marketData")
+            .body(body)
+            .reply(&routes)
+    );
+    res.status().as_u16()
+}
+
+// Daemon with both ends of the corpus channel, so every price reaches the margin checks
+fn open_state() -> DaemonState {
+    let (transport, _listener) = InMemoryTransport::pair();
+    let state = DaemonState::new(Arc::new(transport), SecretKey::new(&mut rand::thread_rng()), None);
+    *state.maker.lock().unwrap() = Some(copy(&CORPUS.maker));
+    *state.taker.lock().unwrap() = Some(CORPUS.taker.clone());
+    state.epoch.store(CORPUS.taker.epoch, std::sync::atomic::Ordering::SeqCst);
+    state
+}
+
+// Maker routes that take a protocol message, with the message each one takes
+const MAKER_ROUTES: [(&str, &str); 5] = [
+    ("/maker/openChannel", "OpenChannelRequest"),
+    ("/maker/established", "ChannelEstablishedRequest"),
+    ("/maker/recvPay", "PaymentRequest"),
+    ("/maker/paymentToken", "GeneratePaymentTokenRequest"),
+    ("/maker/close", "CloseChannelRequest"),
+];
+
+// Every PeerMessage variant, as the peer transport may deliver any of them to the maker
+const PEER_MESSAGES: [&str; 14] = [
+    "OpenChannelRequest",
+    "OpenChannelResponse",
+    "OrderRejection",
+    "ChannelEstablishedRequest",
+    "SettlementRequest",
+    "PaymentRequest",
+    "PaymentResponse",
+    "PaymentRejection",
+    "GeneratePaymentTokenRequest",
+    "GeneratePaymentTokenResponse",
+    "CloseChannelRequest",
+    "CloseChannelResponse",
+    "Ack",
+    "Error",
+];
+
+fn corpus_message(name: &str) -> Vec<u8> {
+    let (_, body) = CORPUS.messages.iter().find(|(message, _)| *message == name).expect("message is in the corpus");
+    serde_json::to_vec(body).unwrap()
+}
+
+// The named message as the peer transport frames it
+fn framed_message(name: &str) -> Vec<u8> {
+    match name {
+        "Ack" => serde_json::to_vec(&PeerMessage::Ack).unwrap(),
+        "Error" => serde_json::to_vec(&PeerMessage::Error("Taker failed".to_string())).unwrap(),
+        _ => {
+            let (_, body) = CORPUS.messages.iter().find(|(message, _)| *message == name).expect("message is in the corpus");
+            serde_json::to_vec(&json!({ "type": name, "body": body })).unwrap()
+        }
+    }
+}
+
+// Daemon whose maker is about to receive the named message, or with the open channel for messages it never takes
+fn maker_state(name: &str) -> DaemonState {
+    let maker = CORPUS.makers.iter().find(|(message, _)| *message == name).map_or(&CORPUS.maker, |(_, maker)| maker);
+    let (transport, _listener) = InMemoryTransport::pair();
+    let state = DaemonState::new(Arc::new(transport), SecretKey::new(&mut rand::thread_rng()), None);
+    *state.maker.lock().unwrap() = Some(copy(maker));
+    state
+}
+
+// Answers a framed message the way the peer transport does, if it decodes at all
+fn send_maker(state: &DaemonState, frame: &[u8]) -> Option<PeerMessage> {
+    serde_json::from_slice::<PeerMessage>(frame).ok().map(|msg| handle_maker_slot_message(&state.maker, msg))
+}
+
+fn post_maker(state: &DaemonState, path: &str, body: Vec<u8>) -> u16 {
+    let routes = maker_routes(state.clone()).recover(handle_rejection);
+    let res = futures03::executor::block_on(
+        warp::test::request()
+            .method("POST")
+            .path(path)
+            .body(body)
+            .reply(&routes)
+    );
+    res.status().as_u16()
+}
+
+#[test]
+fn maker_accepts_the_corpus_messages() {
+    for (path, name) in MAKER_ROUTES.iter() {
+        assert_eq!(post_maker(&maker_state(name), path, corpus_message(name)), 200, "{} refused {}", path, name);
+    }
+}
+
+#[test]
+fn maker_answers_every_peer_message() {
+    for name in PEER_MESSAGES.iter() {
+        let state = maker_state(name);
+        let res = send_maker(&state, &framed_message(name)).unwrap_or_else(|| panic!("{} does not decode", name));
+        let answered = match res {
+            PeerMessage::OrderRejection(_) | PeerMessage::PaymentRejection(_) => false,
+            PeerMessage::Error(_) => false,
+            _ => true
+        };
+        // Requests are answered, anything else is refused as unexpected
+        let request = MAKER_ROUTES.iter().any(|(_, message)| message == name);
+        assert_eq!(answered, request, "maker answered {} wrongly", name);
+        assert!(!state.maker.is_poisoned(), "{} poisoned the maker", name);
+    }
+}
+
+#[test]
+fn corpus_decodes() {
+    for (name, body) in &CORPUS.messages {
+        let framed = serde_json::to_vec(&json!({ "type": name, "body": body })).unwrap();
+        assert!(serde_json::from_slice::<PeerMessage>(&framed).is_ok(), "{} does not decode", name);
+    }
+}
+
+proptest! {
+    #[test]
+    fn arbitrary_bytes_do_not_panic(body in proptest::collection::vec(any::<u8>(), 0..512)) {
+        decode_all(&body);
+    }
+
+    #[test]
+    fn corrupted_messages_do_not_panic(seed in any::<prop::sample::Index>(), position in any::<prop::sample::Index>(), byte in any::<u8>()) {
+        let seeds = seeds();
+        let mut body = seed.get(&seeds).clone();
+        let position = position.index(body.len());
+        body[position] = byte;
+        decode_all(&body);
+    }
+
+    #[test]
+    fn truncated_messages_do_not_panic(seed in any::<prop::sample::Index>(), len in any::<prop::sample::Index>()) {
+        let seeds = seeds();
+        let body = seed.get(&seeds);
+        decode_all(&body[..len.index(body.len())]);
+    }
+
+    #[test]
+    fn mutated_fields_do_not_panic(seed in any::<prop::sample::Index>(), index in any::<usize>(), replacement in leaf()) {
+        let seeds = seeds();
+        decode_all(&replace_leaf(seed.get(&seeds), index, replacement));
+    }
+
+    #[test]
+    fn market_data_bodies_do_not_panic(body in proptest::collection::vec(any::<u8>(), 0..128)) {
+        let status = post_market_data(&open_state(), body);
+        prop_assert!(status == 200 || status == 400, "status {}", status);
+    }
+
+    #[test]
+    fn only_positive_prices_are_recorded(prices in proptest::collection::vec((any::<i64>(), any::<i64>()), 1..8)) {
+        let state = open_state();
+        for (bitcoin, cosmos) in prices {
+            let epoch = state.taker.lock().unwrap().as_ref().unwrap().epoch;
+            let status = post_market_data(&state, serde_json::to_vec(&market_data(bitcoin, cosmos)).unwrap());
+            let recorded = state.taker.lock().unwrap().as_ref().unwrap().epoch > epoch;
+            if bitcoin > 0 && cosmos > 0 {
+                prop_assert_eq!(status, 200);
+                prop_assert!(recorded);
+            } else {
+                prop_assert_eq!(status, 400);
+                prop_assert!(!recorded);
+            }
+        }
+    }
+}
+
+// Each case verifies real proofs, so fewer of them
+proptest! {
+    #![proptest_config(ProptestConfig::with_cases(64))]
+
+    #[test]
+    fn mutated_peer_messages_are_answered(route in any::<prop::sample::Index>(), index in any::<usize>(), replacement in leaf()) {
+        let (path, name) = route.get(&MAKER_ROUTES);
+        let state = maker_state(name);
+        let status = post_maker(&state, path, replace_leaf(&corpus_message(name), index, replacement));
+        prop_assert!(status < 500, "{} answered {}", path, status);
+        // A request the maker could not verify leaves it usable for the next one
+        prop_assert!(!state.maker.is_poisoned(), "{} poisoned the maker", path);
+        prop_assert!(state.maker.lock().unwrap().is_some());
+    }
+
+    #[test]
+    fn corrupted_peer_messages_are_answered(route in any::<prop::sample::Index>(), position in any::<prop::sample::Index>(), byte in any::<u8>()) {
+        let (path, name) = route.get(&MAKER_ROUTES);
+        let state = maker_state(name);
+        let mut body = corpus_message(name);
+        let position = position.index(body.len());
+        body[position] = byte;
+        let status = post_maker(&state, path, body);
+        prop_assert!(status < 500, "{} answered {}", path, status);
+        prop_assert!(!state.maker.is_poisoned(), "{} poisoned the maker", path);
+    }
+
+    #[test]
+    fn mutated_transport_messages_are_answered(message in any::<prop::sample::Index>(), index in any::<usize>(), replacement in leaf()) {
+        let name = message.get(&PEER_MESSAGES);
+        let state = maker_state(name);
+        send_maker(&state, &replace_leaf(&framed_message(name), index, replacement));
+        prop_assert!(!state.maker.is_poisoned(), "{} poisoned the maker", name);
+        prop_assert!(state.maker.lock().unwrap().is_some());
+    }
+
+    #[test]
+    fn corrupted_transport_messages_are_answered(message in any::<prop::sample::Index>(), position in any::<prop::sample::Index>(), byte in any::<u8>()) {
+        let name = message.get(&PEER_MESSAGES);
+        let state = maker_state(name);
+        let mut frame = framed_message(name);
+        let position = position.index(frame.len());
+        frame[position] = byte;
+        send_maker(&state, &frame);
+        prop_assert!(!state.maker.is_poisoned(), "{} poisoned the maker", name);
+    }
+}
@@ -0,0 +1,97 @@
+// The payoff both sides compute independently must agree, whatever prices the feed reports
+use proptest::prelude::*;
+
+use rainboltd::{
+    history::PriceHistory,
+    math::compute_payment,
+    MarketData,
+    MarketPrice
+};
+
+fn market_data(bitcoin: i64) -> MarketData {
+    MarketData {
+        bitcoin: MarketPrice { usd: bitcoin },
+        cosmos: MarketPrice { usd: 4 }
+    }
+}
+
+fn history(prices: &[i64]) -> PriceHistory {
+    let mut history = PriceHistory::default();
+    for (epoch, bitcoin) in prices.iter().enumerate() {
+        history.record(epoch as u64, market_data(*bitcoin));
+    }
+    history
+}
+
+// Prices a feed could plausibly report, in cents from a dollar to a billion dollars
+fn price() -> impl Strategy<Value = i64> {
+    100..100_000_000_000i64
+}
+
+fn position_size() -> impl Strategy<Value = i64> {
+    -1_000_000i64..=1_000_000i64
+}
+
+proptest! {
+    #[test]
+    fn never_panics(bitcoin in any::<i64>(), prev in any::<i64>(), position_size in any::<i64>()) {
+        let payment = compute_payment(market_data(bitcoin), market_data(prev), position_size);
+        if prev <= 0 {
+            prop_assert_eq!(payment, None);
+        }
+    }
+
+    #[test]
+    fn plausible_prices_always_have_a_payment(bitcoin in price(), prev in price(), position_size in position_size()) {
+        prop_assert!(compute_payment(market_data(bitcoin), market_data(prev), position_size).is_some());
+    }
+
+    // The maker holds the opposite position, so whatever one side gains the other pays
+    #[test]
+    fn antisymmetric_between_sides(bitcoin in any::<i64>(), prev in any::<i64>(), position_size in -i64::max_value()..=i64::max_value()) {
+        let taker = compute_payment(market_data(bitcoin), market_data(prev), position_size);
+        let maker = compute_payment(market_data(bitcoin), market_data(prev), -position_size);
+        prop_assert_eq!(taker.map(|payment| -payment), maker);
+    }
+
+    #[test]
+    fn follows_the_price(bitcoin in price(), prev in price(), position_size in 0..=1_000_000i64) {
+        let payment = compute_payment(market_data(bitcoin), market_data(prev), position_size).unwrap();
+        prop_assert!(payment.signum() * (bitcoin - prev).signum() >= 0);
+        if bitcoin == prev {
+            prop_assert_eq!(payment, 0);
+        }
+    }
+
+    // Netted catch up settles the same amount as one payment per epoch
+    #[test]
+    fn additive_across_epochs(
+        prices in proptest::collection::vec(price(), 3..16),
+        position_size in position_size(),
+        split in any::<prop::sample::Index>()
+    ) {
+        let history = history(&prices);
+        let to_epoch = prices.len() as u64 - 1;
+        let split = 1 + split.index(prices.len() - 2) as u64;
+        let whole = history.compute_payment(0, to_epoch, position_size).unwrap();
+        let first = history.compute_payment(0, split, position_size).unwrap();
+        let rest = history.compute_payment(split, to_epoch, position_size).unwrap();
+        prop_assert_eq!(whole, first + rest);
+
+        let every_epoch: i64 = (1..=to_epoch)
+            .map(|epoch| history.compute_payment(epoch - 1, epoch, position_size).unwrap())
+            .sum();
+        prop_assert_eq!(whole, every_epoch);
+    }
+
+    #[test]
+    fn missing_or_invalid_prices_have_no_payment(prices in proptest::collection::vec(any::<i64>(), 2..8), missing in any::<prop::sample::Index>()) {
+        let mut history = history(&prices);
+        let to_epoch = prices.len() as u64 - 1;
+        if prices[..prices.len() - 1].iter().any(|price| *price <= 0) {
+            prop_assert_eq!(history.compute_payment(0, to_epoch, 1), None);
+        }
+        history.prices.remove(&(missing.index(prices.len()) as u64));
+        prop_assert_eq!(history.compute_payment(0, to_epoch, 1), None);
+    }
+}
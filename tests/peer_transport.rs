@@ -0,0 +1,80 @@
+// A taker daemon opens its channel over the encrypted peer transport, which only admits known traders
+use secp256k1::{Secp256k1, PublicKey, SecretKey};
+use std::sync::{Arc, Mutex};
+use tokio::net::TcpListener;
+
+use rainboltd::{
+    auth::{AuthConfig, Credentials},
+    clock::system_clock,
+    driver,
+    maker::{Maker, MakerState},
+    server::serve_peers,
+    state::DaemonState,
+    taker::{Taker, TakerState},
+    transport::{InMemoryTransport, PeerTransport, TransportError},
+};
+
+fn public_key(secret_key: &SecretKey) -> PublicKey {
+    PublicKey::from_secret_key(&Secp256k1::signing_only(), secret_key)
+}
+
+// Maker daemon trusting the given traders, listening on a free local port
+async fn maker_daemon(traders: Vec<PublicKey>) -> (DaemonState, std::net::SocketAddr) {
+    let node_key = SecretKey::new(&mut rand::thread_rng());
+    let config = AuthConfig {
+        admins: Vec::new(),
+        traders: traders.iter().map(PublicKey::to_string).collect(),
+        max_clock_skew_secs: 30,
+    };
+    let credentials = Credentials::from_config(&config, public_key(&node_key), system_clock());
+    let (transport, _listener) = InMemoryTransport::pair();
+    let state = DaemonState::new(Arc::new(transport), node_key, Some(credentials));
+    *state.maker.lock().unwrap() = Some(MakerState::init(&mut rand::thread_rng(), system_clock(), 5000));
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(serve_peers(state.clone(), listener));
+    (state, addr)
+}
+
+fn taker_slot(maker: &DaemonState) -> Arc<Mutex<Option<TakerState>>> {
+    let maker = maker.maker.lock().unwrap();
+    let maker = maker.as_ref().unwrap();
+    let taker = TakerState::init(
+        &mut rand::thread_rng(),
+        system_clock(),
+        1000,
+        maker.quote_collateral(1000),
+        1000,
+        maker.channel_state.clone(),
+        maker.channel_token.clone()
+    );
+    Arc::new(Mutex::new(Some(taker)))
+}
+
+#[tokio::test]
+async fn opens_a_channel_over_the_peer_transport() {
+    let taker_key = SecretKey::new(&mut rand::thread_rng());
+    let (maker, addr) = maker_daemon(vec![public_key(&taker_key)]).await;
+    let transport = PeerTransport::new(addr, taker_key, maker.node_public_key());
+
+    let slot = taker_slot(&maker);
+    let taker = driver::open_channel(&slot, &transport).await.unwrap();
+    assert!(taker.established);
+    assert_eq!(maker.maker.lock().unwrap().as_ref().unwrap().reserved_margin, 0);
+}
+
+#[tokio::test]
+async fn refuses_a_peer_that_is_not_a_trader() {
+    let (maker, addr) = maker_daemon(Vec::new()).await;
+    let stranger = SecretKey::new(&mut rand::thread_rng());
+    let transport = PeerTransport::new(addr, stranger, maker.node_public_key());
+
+    let slot = taker_slot(&maker);
+    match driver::open_channel(&slot, &transport).await {
+        Err(TransportError::Io(_)) => (),
+        Err(err) => panic!("expected the session to be dropped, got {}", err),
+        Ok(_) => panic!("an unknown peer opened a channel")
+    }
+    assert_eq!(maker.maker.lock().unwrap().as_ref().unwrap().reserved_margin, 0);
+}
@@ -0,0 +1,116 @@
+// Responses must never carry the customer or merchant secret state
+use secp256k1::SecretKey;
+use serde_json::Value;
+use std::collections::HashSet;
+use std::sync::Arc;
+
+use rainboltd::{
+    api::{maker_routes, query_routes, taker_routes},
+    driver,
+    message::OrderRequest,
+    state::DaemonState,
+    transport::InMemoryTransport,
+    MarketData,
+    MarketPrice
+};
+
+fn leaves(value: &Value, out: &mut HashSet<String>) {
+    match value {
+        Value::String(s) if s.len() >= 16 => { out.insert(s.clone()); },
+        Value::Array(values) if values.len() >= 16 && values.iter().all(Value::is_number) => {
+            out.insert(value.to_string());
+        },
+        Value::Array(values) => values.iter().for_each(|value| leaves(value, out)),
+        Value::Object(fields) => fields.values().for_each(|value| leaves(value, out)),
+        _ => ()
+    }
+}
+
+// Every long string or byte array of the secret state that is not also public in the channel token
+fn secrets(state: &DaemonState) -> HashSet<String> {
+    let mut secret = HashSet::new();
+    let mut public = HashSet::new();
+    let taker = state.taker.lock().unwrap();
+    let taker = taker.as_ref().expect("taker exists");
+    let maker = state.maker.lock().unwrap();
+    let maker = maker.as_ref().expect("maker exists");
+    leaves(&serde_json::to_value(&taker.customer_state).unwrap(), &mut secret);
+    leaves(&serde_json::to_value(&maker.merchant_state).unwrap(), &mut secret);
+    leaves(&serde_json::to_value(&taker.channel_token).unwrap(), &mut public);
+    secret.difference(&public).cloned().collect()
+}
+
+fn assert_redacted(body: &[u8], state: &DaemonState) {
+    let body = std::str::from_utf8(body).expect("response is utf8");
+    assert!(!body.contains("customer_state"), "response carries customer_state: {}", body);
+    assert!(!body.contains("merchant_state"), "response carries merchant_state: {}", body);
+    for secret in secrets(state) {
+        assert!(!body.contains(&secret), "response leaks {}", secret);
+    }
+}
+
+fn market_data(bitcoin: i64, cosmos: i64) -> MarketData {
+    MarketData {
+        bitcoin: MarketPrice { usd: bitcoin },
+        cosmos: MarketPrice { usd: cosmos }
+    }
+}
+
+#[tokio::test]
+async fn responses_do_not_leak_secret_state() {
+    let (transport, listener) = InMemoryTransport::pair();
+    let state = DaemonState::new(Arc::new(transport), SecretKey::new(&mut rand::thread_rng()), None);
+    let maker_slot = state.maker.clone();
+    tokio::spawn(listener.serve(move |msg| driver::handle_maker_slot_message(&maker_slot, msg)));
+    let maker = maker_routes(state.clone());
+    let taker = taker_routes(state.clone());
+
+    let init = warp::test::request()
+        .method("POST")
+        .path("/maker/init/1000")
+        .reply(&maker)
+        .await;
+    assert_eq!(init.status(), 200);
+
+    let order = OrderRequest {
+        initial_margin: 100,
+        order_size: 10,
+        maker_order_id: String::new()
+    };
+    let order = warp::test::request()
+        .method("POST")
+        .path("/taker/order")
+        .body(serde_json::to_vec(&order).unwrap())
+        .reply(&taker)
+        .await;
+    assert_eq!(order.status(), 200);
+
+    state.record_market_data(market_data(8000, 4));
+    state.record_market_data(market_data(8100, 4));
+    let pay = warp::test::request()
+        .method("POST")
+        .path("/taker/pay")
+        .reply(&taker)
+        .await;
+    assert_eq!(pay.status(), 200);
+
+    let channels = warp::test::request()
+        .method("GET")
+        .path("/channels")
+        .reply(&query_routes(state.clone()))
+        .await;
+    assert_eq!(channels.status(), 200);
+    let channel_id = state.taker.lock().unwrap().as_ref().unwrap().channel_token.pk_c.unwrap().to_string();
+    let channel = warp::test::request()
+        .method("GET")
+        .path(&format!("/channels/{}", channel_id))
+        .reply(&query_routes(state.clone()))
+        .await;
+    assert_eq!(channel.status(), 200);
+
+    assert_redacted(init.body(), &state);
+    assert_redacted(order.body(), &state);
+    assert_redacted(pay.body(), &state);
+    assert_redacted(channels.body(), &state);
+    assert_redacted(channel.body(), &state);
+}
@@ -0,0 +1,127 @@
+// A channel journaled by a real run replays without divergences, and a tampered journal diverges where it was changed
+use bytes::Bytes;
+use secp256k1::SecretKey;
+use std::path::PathBuf;
+use std::sync::Arc;
+use warp::{Filter, http::Response};
+
+use rainboltd::{
+    api::{handle_rejection, maker_routes, market_routes, taker_routes},
+    audit::{transcript, AuditEntry, AuditJournal, Direction, Recorded},
+    clock::system_clock,
+    driver,
+    message::OrderRequest,
+    replay::replay,
+    settlement::Side,
+    state::DaemonState,
+    transport::InMemoryTransport,
+    MarketData,
+    MarketPrice
+};
+
+// Distinct so a replay that mixed them up would diverge
+const MAKER_MARGIN: i64 = 5000;
+const COLLATERAL: i64 = 800;
+const TAKER_MARGIN: i64 = 1200;
+const ORDER_SIZE: i64 = 1000;
+
+async fn post(state: &DaemonState, path: &str, body: Vec<u8>) -> Response<Bytes> {
+    let routes = maker_routes(state.clone())
+        .or(taker_routes(state.clone()))
+        .or(market_routes(state.clone()))
+        .recover(handle_rejection);
+    warp::test::request().method("POST").path(path).body(body).reply(&routes).await
+}
+
+async fn price(state: &DaemonState, bitcoin: i64) {
+    let market_data = MarketData { bitcoin: MarketPrice { usd: bitcoin }, cosmos: MarketPrice { usd: 4 } };
+    assert_eq!(post(state, "/marketData", serde_json::to_vec(&market_data).unwrap()).await.status(), 200);
+}
+
+// Opens, pays twice and closes a channel with every message journaled, returning its transcript
+async fn journaled_channel(test: &str) -> Vec<AuditEntry> {
+    let path = std::env::temp_dir().join(format!("rainboltd-{}-{}", test, std::process::id())).join("audit.jsonl");
+    let _ = std::fs::remove_file(&path);
+    let (transport, listener) = InMemoryTransport::pair();
+    let mut state = DaemonState::new(Arc::new(transport), SecretKey::new(&mut rand::thread_rng()), None);
+    state.journal = AuditJournal::open(PathBuf::from(&path), system_clock()).unwrap();
+    let maker_slot = state.maker.clone();
+    tokio::spawn(listener.serve(move |msg| driver::handle_maker_slot_message(&maker_slot, msg)));
+
+    assert_eq!(post(&state, &format!("/maker/init/{}", MAKER_MARGIN), Vec::new()).await.status(), 200);
+    assert_eq!(post(&state, &format!("/maker/collateral/{}", COLLATERAL), Vec::new()).await.status(), 200);
+    let order = OrderRequest { initial_margin: TAKER_MARGIN, order_size: ORDER_SIZE, maker_order_id: String::new() };
+    assert_eq!(post(&state, "/taker/order", serde_json::to_vec(&order).unwrap()).await.status(), 200);
+    price(&state, 8000).await;
+    price(&state, 8800).await;
+    assert_eq!(post(&state, "/taker/pay", Vec::new()).await.status(), 200);
+    price(&state, 8360).await;
+    assert_eq!(post(&state, "/taker/pay", Vec::new()).await.status(), 200);
+    assert_eq!(post(&state, "/taker/close", Vec::new()).await.status(), 200);
+
+    let channel = state.taker.lock().unwrap().as_ref().unwrap().channel_token.pk_c.unwrap().to_string();
+    transcript(&path, &channel).unwrap()
+}
+
+// Seq of the first message of the given type one side journaled in the given direction
+fn message_seq(entries: &[AuditEntry], side: Side, direction: Direction, kind: &str) -> u64 {
+    entries.iter().find(|entry| match entry.record.recorded {
+        Recorded::Message { side: ref recorded_side, direction: recorded_direction, ref message } =>
+            *recorded_side == side && recorded_direction == direction && message["type"] == kind,
+        _ => false
+    }).map(|entry| entry.seq).expect("message was journaled")
+}
+
+fn entry(entries: &mut [AuditEntry], seq: u64) -> &mut AuditEntry {
+    entries.iter_mut().find(|entry| entry.seq == seq).expect("entry is in the transcript")
+}
+
+#[tokio::test]
+async fn replays_a_real_run_without_divergences() {
+    let entries = journaled_channel("replay-clean").await;
+    for side in vec![Side::Maker, Side::Taker] {
+        let report = replay(&entries, side.clone(), 7);
+        assert!(report.divergence.is_none(), "{:?} diverged: {}", side, report.divergence.unwrap());
+        // Open, established, two rounds of payment and token, close, each with its answer
+        assert!(report.steps >= 12, "{:?} replayed only {} steps", side, report.steps);
+    }
+}
+
+#[tokio::test]
+async fn diverges_at_a_tampered_amount() {
+    let mut entries = journaled_channel("replay-amount").await;
+    let seq = message_seq(&entries, Side::Maker, Direction::Inbound, "PaymentRequest");
+    if let Recorded::Message { ref mut message, .. } = entry(&mut entries, seq).record.recorded {
+        message["body"]["payment_proof"]["amount"] = serde_json::json!(90);
+    }
+    let divergence = replay(&entries, Side::Maker, 7).divergence.expect("tampered amount diverges");
+    assert_eq!(divergence.seq, seq);
+    assert_eq!(divergence.field, "amount");
+    assert_eq!(divergence.recorded, "90");
+    assert_eq!(divergence.replayed, "100");
+}
+
+#[tokio::test]
+async fn diverges_when_the_quote_does_not_follow_the_journaled_terms() {
+    let mut entries = journaled_channel("replay-terms").await;
+    for entry in entries.iter_mut() {
+        if let Recorded::MakerTerms(ref mut terms) = entry.record.recorded {
+            terms.collateral = Some(COLLATERAL / 2);
+        }
+    }
+    let seq = message_seq(&entries, Side::Maker, Direction::Inbound, "OpenChannelRequest");
+    let divergence = replay(&entries, Side::Maker, 7).divergence.expect("tampered collateral diverges");
+    assert_eq!(divergence.seq, seq);
+    assert_eq!(divergence.field, "maker_margin");
+    assert_eq!(divergence.recorded, COLLATERAL.to_string());
+}
+
+#[test]
+fn journal_directory_is_private() {
+    let dir = std::env::temp_dir().join(format!("rainboltd-journal-dir-{}", std::process::id())).join("audit");
+    let _ = std::fs::remove_dir_all(&dir);
+    AuditJournal::open(dir.join("audit.jsonl"), system_clock()).unwrap();
+
+    use std::os::unix::fs::PermissionsExt;
+    assert_eq!(std::fs::metadata(&dir).unwrap().permissions().mode() & 0o777, 0o700);
+}
@@ -0,0 +1,78 @@
+// The settlement scheduler settles each closed epoch, pacing its retries on the daemon's clock
+use bytes::Bytes;
+use secp256k1::SecretKey;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::timer::delay_for;
+use warp::{Filter, http::Response};
+
+use rainboltd::{
+    api::{handle_rejection, maker_routes, taker_routes},
+    clock::ManualClock,
+    driver,
+    message::OrderRequest,
+    scheduler::run_settlement_scheduler,
+    settlement::{Backoff, SettlementStatus},
+    state::DaemonState,
+    transport::InMemoryTransport,
+    MarketData,
+    MarketPrice
+};
+
+async fn post(state: &DaemonState, path: &str, body: Vec<u8>) -> Response<Bytes> {
+    let routes = maker_routes(state.clone()).or(taker_routes(state.clone())).recover(handle_rejection);
+    warp::test::request().method("POST").path(path).body(body).reply(&routes).await
+}
+
+fn status(state: &DaemonState) -> SettlementStatus {
+    state.taker.lock().unwrap().as_ref().unwrap().settlement.clone()
+}
+
+async fn wait_for<F: Fn() -> bool>(done: F) {
+    for _ in 0..200 {
+        if done() {
+            return
+        }
+        delay_for(Duration::from_millis(10)).await;
+    }
+    panic!("timed out");
+}
+
+#[tokio::test]
+async fn retries_an_epoch_on_the_clock_until_the_maker_is_back() {
+    let clock = ManualClock::new(1_000_000);
+    let (transport, listener) = InMemoryTransport::pair();
+    let mut state = DaemonState::new(Arc::new(transport), SecretKey::new(&mut rand::thread_rng()), None);
+    state.clock = Arc::new(clock.clone());
+    let maker_slot = state.maker.clone();
+    tokio::spawn(listener.serve(move |msg| driver::handle_maker_slot_message(&maker_slot, msg)));
+
+    assert_eq!(post(&state, "/maker/init/5000", Vec::new()).await.status(), 200);
+    let order = OrderRequest { initial_margin: 1000, order_size: 1000, maker_order_id: String::new() };
+    assert_eq!(post(&state, "/taker/order", serde_json::to_vec(&order).unwrap()).await.status(), 200);
+
+    // The maker goes away, still seeing every price
+    let mut maker = state.maker.lock().unwrap().take().unwrap();
+    let backoff = Backoff { initial_delay_ms: 1000, max_delay_ms: 60_000, max_attempts: 3 };
+    tokio::spawn(run_settlement_scheduler(state.clone(), state.subscribe_epochs(), backoff));
+    for bitcoin in &[8000, 8800] {
+        let market_data = MarketData { bitcoin: MarketPrice { usd: *bitcoin }, cosmos: MarketPrice { usd: 4 } };
+        let epoch = state.record_market_data(market_data.clone());
+        maker.record_market_data(epoch, market_data);
+    }
+
+    // The taker owes for epoch 2 and tries once, then waits a full second on the clock
+    wait_for(|| status(&state) == SettlementStatus::Pending { epoch: 2, attempt: 1 } && clock.sleeping() > 0).await;
+    clock.advance(Duration::from_millis(999));
+    delay_for(Duration::from_millis(50)).await;
+    assert_eq!(status(&state), SettlementStatus::Pending { epoch: 2, attempt: 1 });
+    clock.advance(Duration::from_millis(1));
+    wait_for(|| status(&state) == SettlementStatus::Pending { epoch: 2, attempt: 2 } && clock.sleeping() > 0).await;
+
+    // Back in time for the last attempt, two seconds later
+    *state.maker.lock().unwrap() = Some(maker);
+    clock.advance(Duration::from_millis(2000));
+    wait_for(|| status(&state) == SettlementStatus::Settled { epoch: 2 }).await;
+    let taker = state.taker.lock().unwrap();
+    assert_eq!(taker.as_ref().unwrap().customer_state.cust_balance, 900);
+}
@@ -0,0 +1,36 @@
+// The Unix socket server keeps answering whatever a single client sends or how it hangs up
+use std::time::Duration;
+use tokio::io::AsyncWriteExt;
+use tokio::net::UnixStream;
+use tokio::timer::delay_for;
+
+use rainboltd::{
+    peer::{PeerMessage, MAX_FRAME_LEN},
+    transport::{serve_unix, Transport, UnixTransport},
+};
+
+#[tokio::test]
+async fn survives_bad_clients() {
+    let path = std::env::temp_dir().join(format!("rainboltd-test-{}.sock", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+    tokio::spawn(serve_unix(path.clone(), |msg| msg));
+    delay_for(Duration::from_millis(50)).await;
+
+    // A frame claiming more than the limit is dropped without allocating it
+    let mut stream = UnixStream::connect(&path).await.unwrap();
+    stream.write_all(&((MAX_FRAME_LEN + 1) as u32).to_be_bytes()).await.unwrap();
+    // A client that hangs up before its answer, and one that never sends anything
+    let mut stream = UnixStream::connect(&path).await.unwrap();
+    let body = serde_json::to_vec(&PeerMessage::Ack).unwrap();
+    stream.write_all(&(body.len() as u32).to_be_bytes()).await.unwrap();
+    stream.write_all(&body).await.unwrap();
+    drop(stream);
+    let _idle = UnixStream::connect(&path).await.unwrap();
+
+    match UnixTransport::new(&path).request(PeerMessage::Ack).await {
+        Ok(PeerMessage::Ack) => (),
+        Ok(_) => panic!("unexpected answer"),
+        Err(err) => panic!("server stopped answering: {}", err)
+    }
+    let _ = std::fs::remove_file(&path);
+}
@@ -0,0 +1,209 @@
+// Webhook posts are signed with the node key, retried with backoff and dead lettered once attempts run out
+use bytes::Bytes;
+use reqwest::Client;
+use secp256k1::{Secp256k1, PublicKey, SecretKey};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::mpsc;
+use tokio::timer::delay_for;
+use warp::{Filter, http::{HeaderMap, Method, StatusCode}, path::FullPath};
+
+use rainboltd::{
+    auth::{verify_request, AuthConfig, Credentials, Role},
+    clock::{system_clock, Clock, ManualClock, SharedClock},
+    events::Event,
+    settlement::{Backoff, Side},
+    webhook::{run_webhooks, DeadLetter, WebhookConfig, WebhookPayload, WebhookSubscription},
+};
+
+// What the endpoint saw of each post: whether the signature verified, and the payload
+type Deliveries = Arc<Mutex<Vec<(bool, WebhookPayload)>>>;
+
+// Endpoint that fails the first `failures` posts, verifying each against the node key
+fn endpoint(node_public_key: PublicKey, failures: usize) -> (String, Deliveries) {
+    let config = AuthConfig {
+        admins: vec![node_public_key.to_string()],
+        traders: Vec::new(),
+        max_clock_skew_secs: 30,
+    };
+    let credentials = Credentials::from_config(&config, node_public_key, system_clock());
+    let deliveries = Deliveries::default();
+    let seen = deliveries.clone();
+    let route = warp::method()
+        .and(warp::path::full())
+        .and(warp::header::headers_cloned())
+        .and(warp::body::bytes())
+        .map(move |method: Method, path: FullPath, headers: HeaderMap, body: Bytes| {
+            let signed = verify_request(&credentials, Role::Admin, &method, path.as_str(), &headers, &body).is_ok();
+            let payload: WebhookPayload = serde_json::from_slice(&body).unwrap();
+            let mut seen = seen.lock().unwrap();
+            seen.push((signed, payload));
+            let status = if seen.len() > failures { StatusCode::OK } else { StatusCode::INTERNAL_SERVER_ERROR };
+            warp::reply::with_status("", status)
+        });
+    let (addr, server) = warp::serve(route).bind_ephemeral(([127, 0, 0, 1], 0));
+    tokio::spawn(server);
+    (format!("http://{}/hooks/rainbolt", addr), deliveries)
+}
+
+fn dead_letter_path(test: &str) -> PathBuf {
+    let path = std::env::temp_dir().join(format!("rainboltd-{}-{}", test, std::process::id())).join("webhooks.dead.jsonl");
+    let _ = std::fs::remove_file(&path);
+    path
+}
+
+fn margin_call() -> Event {
+    Event::MarginCall { side: Side::Taker, channel: "channel".to_string(), epoch: 2, balance: 50, payment: 100 }
+}
+
+// Starts delivery for one subscription, returning the sender for its events
+fn webhooks(url: String, events: Vec<String>, backoff: Backoff, dead_letter_path: PathBuf, node_key: SecretKey, clock: SharedClock) -> mpsc::UnboundedSender<Event> {
+    queued_webhooks(url, events, backoff, dead_letter_path, node_key, clock, None)
+}
+
+fn queued_webhooks(url: String, events: Vec<String>, backoff: Backoff, dead_letter_path: PathBuf, node_key: SecretKey, clock: SharedClock, queue_len: Option<usize>) -> mpsc::UnboundedSender<Event> {
+    let config = WebhookConfig {
+        subscriptions: vec![WebhookSubscription { url, events }],
+        backoff: Some(backoff),
+        dead_letter_path: None,
+        queue_len,
+    };
+    let (sender, receiver) = mpsc::unbounded_channel();
+    tokio::spawn(run_webhooks(config, dead_letter_path, receiver, Client::new(), node_key, clock));
+    sender
+}
+
+fn quick_backoff(max_attempts: u32) -> Backoff {
+    Backoff { initial_delay_ms: 10, max_delay_ms: 40, max_attempts }
+}
+
+async fn wait_for<F: Fn() -> bool>(done: F) {
+    for _ in 0..200 {
+        if done() {
+            return
+        }
+        delay_for(Duration::from_millis(10)).await;
+    }
+    panic!("timed out");
+}
+
+#[tokio::test]
+async fn retries_signed_posts_until_delivered() {
+    let node_key = SecretKey::new(&mut rand::thread_rng());
+    let (url, deliveries) = endpoint(PublicKey::from_secret_key(&Secp256k1::signing_only(), &node_key), 2);
+    let path = dead_letter_path("delivered");
+    let mut events = webhooks(url, vec!["margin_call".to_string()], quick_backoff(3), path.clone(), node_key, system_clock());
+
+    // Only subscribed events are posted
+    events.try_send(Event::RevokeTokenAccepted { side: Side::Maker, channel: String::new(), epoch: 1 }).unwrap();
+    events.try_send(margin_call()).unwrap();
+    wait_for(|| deliveries.lock().unwrap().len() == 3).await;
+
+    // Every attempt is signed afresh, so none is refused as a replay
+    for (signed, payload) in deliveries.lock().unwrap().iter() {
+        assert!(signed);
+        assert_eq!(payload.event.name(), "margin_call");
+    }
+    delay_for(Duration::from_millis(100)).await;
+    assert_eq!(deliveries.lock().unwrap().len(), 3);
+    assert!(!path.exists());
+}
+
+#[tokio::test]
+async fn dead_letters_deliveries_that_run_out_of_attempts() {
+    let node_key = SecretKey::new(&mut rand::thread_rng());
+    let (url, deliveries) = endpoint(PublicKey::from_secret_key(&Secp256k1::signing_only(), &node_key), usize::max_value());
+    let path = dead_letter_path("dead");
+    let mut events = webhooks(url.clone(), Vec::new(), quick_backoff(2), path.clone(), node_key, system_clock());
+
+    events.try_send(margin_call()).unwrap();
+    wait_for(|| path.exists()).await;
+    assert_eq!(deliveries.lock().unwrap().len(), 2);
+
+    let contents = std::fs::read_to_string(&path).unwrap();
+    let letter: DeadLetter = serde_json::from_str(contents.lines().next().unwrap()).unwrap();
+    assert_eq!(letter.url, url);
+    assert_eq!(letter.attempts, 2);
+    assert!(letter.error.contains("500"), "{}", letter.error);
+    assert_eq!(letter.payload.event.name(), "margin_call");
+
+    use std::os::unix::fs::PermissionsExt;
+    assert_eq!(std::fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o600);
+}
+
+#[tokio::test]
+async fn waits_out_the_backoff_on_the_clock() {
+    let node_key = SecretKey::new(&mut rand::thread_rng());
+    let (url, deliveries) = endpoint(PublicKey::from_secret_key(&Secp256k1::signing_only(), &node_key), usize::max_value());
+    let path = dead_letter_path("backoff");
+    let clock = ManualClock::new(1_000_000);
+    let backoff = Backoff { initial_delay_ms: 1000, max_delay_ms: 60_000, max_attempts: 3 };
+    let mut events = webhooks(url, Vec::new(), backoff, path.clone(), node_key, Arc::new(clock.clone()));
+
+    events.try_send(margin_call()).unwrap();
+    // Each retry waits for its delay to pass on the clock, however long it really takes
+    for (attempt, delay_ms) in vec![(1, 1000), (2, 2000)] {
+        wait_for(|| deliveries.lock().unwrap().len() == attempt).await;
+        wait_for(|| clock.sleeping() > 0).await;
+        clock.advance(Duration::from_millis(delay_ms - 1));
+        delay_for(Duration::from_millis(50)).await;
+        assert_eq!(deliveries.lock().unwrap().len(), attempt);
+        clock.advance(Duration::from_millis(1));
+    }
+    wait_for(|| path.exists()).await;
+    assert_eq!(deliveries.lock().unwrap().len(), 3);
+    // Payloads are stamped with the clock too
+    assert_eq!(deliveries.lock().unwrap()[0].1.timestamp, 1000);
+    assert_eq!(clock.now_secs(), 1003);
+}
+
+#[tokio::test]
+async fn dead_letters_what_does_not_fit_in_the_endpoint_queue() {
+    let node_key = SecretKey::new(&mut rand::thread_rng());
+    let (url, deliveries) = endpoint(PublicKey::from_secret_key(&Secp256k1::signing_only(), &node_key), usize::max_value());
+    let path = dead_letter_path("queue");
+    let clock = ManualClock::new(1_000_000);
+    let backoff = Backoff { initial_delay_ms: 1000, max_delay_ms: 60_000, max_attempts: 2 };
+    let mut events = queued_webhooks(url.clone(), Vec::new(), backoff, path.clone(), node_key, Arc::new(clock.clone()), Some(1));
+    let margin_call = |epoch| Event::MarginCall { side: Side::Taker, channel: "channel".to_string(), epoch, balance: 50, payment: 100 };
+
+    // The worker waits out the backoff of the first delivery, one more fits in the queue
+    events.try_send(margin_call(1)).unwrap();
+    wait_for(|| deliveries.lock().unwrap().len() == 1 && clock.sleeping() > 0).await;
+    events.try_send(margin_call(2)).unwrap();
+    events.try_send(margin_call(3)).unwrap();
+    wait_for(|| path.exists()).await;
+    let contents = std::fs::read_to_string(&path).unwrap();
+    let letters: Vec<DeadLetter> = contents.lines().map(|line| serde_json::from_str(line).unwrap()).collect();
+    assert_eq!(letters.len(), 1);
+    assert_eq!(letters[0].attempts, 0);
+    assert!(letters[0].error.contains("full"), "{}", letters[0].error);
+    match letters[0].payload.event {
+        Event::MarginCall { epoch, .. } => assert_eq!(epoch, 3),
+        ref event => panic!("dead lettered {}", event.name())
+    }
+    assert_eq!(deliveries.lock().unwrap().len(), 1);
+
+    // The queued one is still delivered in turn, once the first runs out of attempts
+    clock.advance(Duration::from_millis(1000));
+    wait_for(|| deliveries.lock().unwrap().len() == 3).await;
+    match deliveries.lock().unwrap()[2].1.event {
+        Event::MarginCall { epoch, .. } => assert_eq!(epoch, 2),
+        ref event => panic!("delivered {}", event.name())
+    }
+}
+
+#[test]
+fn backoff_doubles_up_to_the_cap() {
+    let backoff = Backoff { initial_delay_ms: 10, max_delay_ms: 40, max_attempts: 5 };
+    let delays: Vec<u64> = (1..=5).map(|attempt| backoff.delay(attempt).as_millis() as u64).collect();
+    assert_eq!(delays, vec![10, 20, 40, 40, 40]);
+    assert_eq!(backoff.delay(200), Duration::from_millis(40));
+}
+
+#[test]
+#[should_panic(expected = "max_attempts of at least 1")]
+fn backoff_needs_an_attempt() {
+    Backoff { initial_delay_ms: 10, max_delay_ms: 40, max_attempts: 0 }.validate();
+}